@@ -0,0 +1,103 @@
+// Loads a seeded thread of 500 replies with the recursive CTE behind
+// `getMessageThread` and with the old approach of one replies query plus one
+// author query per message, for comparison.
+//
+//     TEST_DATABASE_URL=postgres://... cargo bench --bench thread
+use backend::db::repository::Repositories;
+use backend::entity::{message, user};
+use criterion::{criterion_group, criterion_main, Criterion};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, Database, DatabaseConnection, DbErr, EntityTrait, ModelTrait,
+    QueryFilter, Set,
+};
+
+const THREAD_SIZE: usize = 500;
+// Every message gets up to this many replies, so the thread is a few levels
+// deep and wide at the bottom.
+const FAN_OUT: usize = 3;
+
+async fn seed_thread(db: &DatabaseConnection) -> (i32, i32) {
+    let author = user::ActiveModel {
+        name: Set("Bench Author".to_owned()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .expect("Failed to create user");
+
+    let mut ids = Vec::with_capacity(THREAD_SIZE + 1);
+    for i in 0..=THREAD_SIZE {
+        let parent_id = (i > 0).then(|| ids[(i - 1) / FAN_OUT]);
+        let message = message::ActiveModel {
+            user_id: Set(Some(author.id)),
+            content: Set(format!("Reply {}", i)),
+            parent_id: Set(parent_id),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .expect("Failed to create message");
+        ids.push(message.id);
+    }
+    (author.id, ids[0])
+}
+
+// How threads were loaded before the recursive CTE.
+async fn fetch_thread_per_message(
+    db: &DatabaseConnection,
+    root_id: i32,
+) -> Result<Vec<(message::Model, Option<user::Model>)>, DbErr> {
+    let root = message::Entity::find_by_id(root_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| DbErr::Custom("Root message not found".to_owned()))?;
+    let root_user = root.find_related(user::Entity).one(db).await?;
+    let mut thread = vec![(root, root_user)];
+    let mut pending = vec![root_id];
+    while let Some(parent_id) = pending.pop() {
+        let replies = message::Entity::find()
+            .filter(message::Column::ParentId.eq(parent_id))
+            .all(db)
+            .await?;
+        for reply in replies {
+            let user = reply.find_related(user::Entity).one(db).await?;
+            pending.push(reply.id);
+            thread.push((reply, user));
+        }
+    }
+    Ok(thread)
+}
+
+fn bench_thread(c: &mut Criterion) {
+    dotenvy::dotenv().ok();
+    let db_url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let db = rt
+        .block_on(Database::connect(&db_url))
+        .expect("Failed to connect to test database");
+    let (author_id, root_id) = rt.block_on(seed_thread(&db));
+    let repos = Repositories::sea_orm(db.clone());
+
+    let mut group = c.benchmark_group("thread_of_500_replies");
+    group.sample_size(20);
+    group.bench_function("recursive_cte", |b| {
+        b.to_async(&rt)
+            .iter(|| async { repos.messages.thread(root_id, None).await.unwrap() })
+    });
+    group.bench_function("query_per_message", |b| {
+        b.to_async(&rt)
+            .iter(|| async { fetch_thread_per_message(&db, root_id).await.unwrap() })
+    });
+    group.finish();
+
+    // Deleting the root cascades to its replies; the author would only leave
+    // them behind as tombstones.
+    rt.block_on(async {
+        message::Entity::delete_by_id(root_id).exec(&db).await?;
+        user::Entity::delete_by_id(author_id).exec(&db).await
+    })
+    .expect("Failed to clean up");
+}
+
+criterion_group!(benches, bench_thread);
+criterion_main!(benches);
@@ -3,10 +3,13 @@
 # Construct the DATABASE_URL
 export DATABASE_URL="postgresql://$POSTGRES_USER:$POSTGRES_PASSWORD@$DB_HOST/$POSTGRES_DB"
 
-echo "Starting the backend server..."
-/app/backend
-
-sleep 5
+echo "Applying migrations..."
+/app/backend migrate up || exit 1
 
+# Sample users from an earlier start are left alone; any other failure stops
+# the container.
 echo "Populating the database..."
-/usr/local/bin/populate.sh
+/app/backend seed --skip-existing || exit 1
+
+echo "Starting the backend server..."
+exec /app/backend serve
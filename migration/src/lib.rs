@@ -1,12 +1,34 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20240410_000002_add_user_password_hash;
+mod m20240412_000003_create_personal_access_token;
+mod m20240414_000004_add_roles_and_hidden_messages;
+mod m20240416_000005_add_message_deleted_at;
+mod m20240418_000006_create_message_revision;
+mod m20240420_000007_create_message_reaction;
+mod m20240422_000008_create_message_mention;
+mod m20240424_000009_create_channel;
+mod m20240426_000010_keep_messages_of_deleted_users;
+mod m20240428_000011_add_message_indexes;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20240410_000002_add_user_password_hash::Migration),
+            Box::new(m20240412_000003_create_personal_access_token::Migration),
+            Box::new(m20240414_000004_add_roles_and_hidden_messages::Migration),
+            Box::new(m20240416_000005_add_message_deleted_at::Migration),
+            Box::new(m20240418_000006_create_message_revision::Migration),
+            Box::new(m20240420_000007_create_message_reaction::Migration),
+            Box::new(m20240422_000008_create_message_mention::Migration),
+            Box::new(m20240424_000009_create_channel::Migration),
+            Box::new(m20240426_000010_keep_messages_of_deleted_users::Migration),
+            Box::new(m20240428_000011_add_message_indexes::Migration),
+        ]
     }
 }
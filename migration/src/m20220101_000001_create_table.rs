@@ -90,11 +90,12 @@ impl MigrationTrait for Migration {
     }
 
     async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Messages reference users, so they go first.
         manager
-            .drop_table(Table::drop().table(User::Table).to_owned())
+            .drop_table(Table::drop().table(Message::Table).to_owned())
             .await?;
         manager
-            .drop_table(Table::drop().table(Message::Table).to_owned())
+            .drop_table(Table::drop().table(User::Table).to_owned())
             .await?;
 
         Ok(())
@@ -0,0 +1,78 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum Message {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum MessageRevision {
+    Table,
+    Id,
+    MessageId,
+    Revision,
+    Content,
+    CreatedAt,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(MessageRevision::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(MessageRevision::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(MessageRevision::MessageId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(MessageRevision::Revision)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(MessageRevision::Content).text().not_null())
+                    .col(
+                        ColumnDef::new(MessageRevision::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_message_revision_message_id_revision")
+                            .col(MessageRevision::MessageId)
+                            .col(MessageRevision::Revision)
+                            .unique(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .name("fk_message_revision_message_id")
+                            .from(MessageRevision::Table, MessageRevision::MessageId)
+                            .to(Message::Table, Message::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MessageRevision::Table).to_owned())
+            .await
+    }
+}
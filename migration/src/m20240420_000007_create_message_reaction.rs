@@ -0,0 +1,89 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum Message {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum MessageReaction {
+    Table,
+    Id,
+    MessageId,
+    UserId,
+    Emoji,
+    CreatedAt,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(MessageReaction::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(MessageReaction::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(MessageReaction::MessageId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(MessageReaction::UserId).integer().not_null())
+                    .col(ColumnDef::new(MessageReaction::Emoji).string().not_null())
+                    .col(
+                        ColumnDef::new(MessageReaction::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_message_reaction_message_id_user_id_emoji")
+                            .col(MessageReaction::MessageId)
+                            .col(MessageReaction::UserId)
+                            .col(MessageReaction::Emoji)
+                            .unique(),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .name("fk_message_reaction_message_id")
+                            .from(MessageReaction::Table, MessageReaction::MessageId)
+                            .to(Message::Table, Message::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .name("fk_message_reaction_user_id")
+                            .from(MessageReaction::Table, MessageReaction::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MessageReaction::Table).to_owned())
+            .await
+    }
+}
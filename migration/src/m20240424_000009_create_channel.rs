@@ -0,0 +1,124 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::DatabaseBackend;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum Message {
+    Table,
+    ChannelId,
+}
+
+#[derive(DeriveIden)]
+enum Channel {
+    Table,
+    Id,
+    Name,
+    Topic,
+    CreatedBy,
+    Visibility,
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Channel::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Channel::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(Channel::Name)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(Channel::Topic).text().null())
+                    .col(ColumnDef::new(Channel::CreatedBy).integer().null())
+                    .col(
+                        ColumnDef::new(Channel::Visibility)
+                            .string_len(16)
+                            .not_null()
+                            .default("public"),
+                    )
+                    .col(
+                        ColumnDef::new(Channel::CreatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Channel::UpdatedAt)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    // Channels outlive the user who created them.
+                    .foreign_key(
+                        ForeignKeyCreateStatement::new()
+                            .name("fk_channel_created_by")
+                            .from(Channel::Table, Channel::CreatedBy)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::SetNull)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Existing messages stay outside of any channel.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Message::Table)
+                    .add_column(ColumnDef::new(Message::ChannelId).integer().null())
+                    .to_owned(),
+            )
+            .await?;
+        // SQLite cannot add foreign keys to an existing table. There the
+        // repositories keep channel ids valid on their own.
+        if manager.get_database_backend() != DatabaseBackend::Sqlite {
+            manager
+                .create_foreign_key(
+                    ForeignKey::create()
+                        .name("fk_message_channel_id")
+                        .from(Message::Table, Message::ChannelId)
+                        .to(Channel::Table, Channel::Id)
+                        .on_delete(ForeignKeyAction::Cascade)
+                        .on_update(ForeignKeyAction::Cascade)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Message::Table)
+                    .drop_column(Message::ChannelId)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Channel::Table).to_owned())
+            .await?;
+        Ok(())
+    }
+}
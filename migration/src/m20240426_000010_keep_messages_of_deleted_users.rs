@@ -0,0 +1,254 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, DatabaseBackend, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum Message {
+    Table,
+    Id,
+    UserId,
+    Content,
+    ParentId,
+    CreatedAt,
+    UpdatedAt,
+    HiddenAt,
+    DeletedAt,
+    ChannelId,
+}
+
+// Messages of deleted users stay behind as tombstones without an author,
+// instead of taking every reply below them along.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == DatabaseBackend::Sqlite {
+            return rebuild_sqlite(manager, true).await;
+        }
+        manager
+            .drop_foreign_key(
+                ForeignKey::drop()
+                    .name("fk_messages_user_id")
+                    .table(Message::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Message::Table)
+                    .modify_column(ColumnDef::new(Message::UserId).integer().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_foreign_key(author_key(ForeignKeyAction::SetNull))
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Messages without an author have nowhere to go, and deleting them
+        // would cascade to other users' replies, so those have to be dealt
+        // with by hand first.
+        let connection = manager.get_connection();
+        let backend = manager.get_database_backend();
+        let authorless = connection
+            .query_one(
+                backend.build(
+                    Query::select()
+                        .expr(Expr::col(Message::Id).count())
+                        .from(Message::Table)
+                        .and_where(Expr::col(Message::UserId).is_null()),
+                ),
+            )
+            .await?
+            .map(|row| row.try_get_by_index::<i64>(0))
+            .transpose()?
+            .unwrap_or(0);
+        if authorless > 0 {
+            return Err(DbErr::Migration(format!(
+                "{authorless} messages of deleted users have no author; \
+                 delete them or give them one before rolling back"
+            )));
+        }
+        if manager.get_database_backend() == DatabaseBackend::Sqlite {
+            return rebuild_sqlite(manager, false).await;
+        }
+        manager
+            .drop_foreign_key(
+                ForeignKey::drop()
+                    .name("fk_messages_user_id")
+                    .table(Message::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Message::Table)
+                    .modify_column(ColumnDef::new(Message::UserId).integer().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_foreign_key(author_key(ForeignKeyAction::Cascade))
+            .await
+    }
+}
+
+fn author_key(on_delete: ForeignKeyAction) -> ForeignKeyCreateStatement {
+    ForeignKey::create()
+        .name("fk_messages_user_id")
+        .from(Message::Table, Message::UserId)
+        .to(User::Table, User::Id)
+        .on_delete(on_delete)
+        .on_update(ForeignKeyAction::Cascade)
+        .to_owned()
+}
+
+// The columns of `message` as the earlier migrations left them, apart from
+// the author, whose definition is what changes.
+fn message_columns(user_id: ColumnDef) -> [ColumnDef; 9] {
+    [
+        ColumnDef::new(Message::Id)
+            .integer()
+            .not_null()
+            .auto_increment()
+            .primary_key()
+            .to_owned(),
+        user_id,
+        ColumnDef::new(Message::Content)
+            .text()
+            .not_null()
+            .to_owned(),
+        ColumnDef::new(Message::ParentId)
+            .integer()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Message::CreatedAt)
+            .timestamp_with_time_zone()
+            .not_null()
+            .default(Expr::cust("CURRENT_TIMESTAMP"))
+            .to_owned(),
+        ColumnDef::new(Message::UpdatedAt)
+            .timestamp_with_time_zone()
+            .not_null()
+            .default(Expr::cust("CURRENT_TIMESTAMP"))
+            .to_owned(),
+        ColumnDef::new(Message::HiddenAt)
+            .timestamp_with_time_zone()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Message::DeletedAt)
+            .timestamp_with_time_zone()
+            .null()
+            .to_owned(),
+        ColumnDef::new(Message::ChannelId)
+            .integer()
+            .null()
+            .to_owned(),
+    ]
+}
+
+// SQLite can change neither a column's nullability nor a foreign key, so the
+// table is copied into a new one with the wanted definition, the way the
+// SQLite documentation describes. Foreign keys are switched off meanwhile, or
+// dropping the old table would delete the revisions, reactions and mentions
+// that refer to it. The pragma does nothing inside a transaction and only
+// applies to the connection it runs on, so everything goes in one batch that
+// opens a transaction of its own after it; run inside another transaction,
+// that fails before anything is dropped.
+async fn rebuild_sqlite(manager: &SchemaManager<'_>, author_optional: bool) -> Result<(), DbErr> {
+    let connection = manager.get_connection();
+    let mut user_id = ColumnDef::new(Message::UserId);
+    user_id.integer();
+    let on_delete = if author_optional {
+        user_id.null();
+        ForeignKeyAction::SetNull
+    } else {
+        user_id.not_null();
+        ForeignKeyAction::Cascade
+    };
+    let columns = message_columns(user_id);
+    let names: Vec<String> = columns.iter().map(ColumnDef::get_column_name).collect();
+
+    // A column this doesn't know about would be lost in the copy.
+    let mut existing: Vec<String> = connection
+        .query_all(Statement::from_string(
+            DatabaseBackend::Sqlite,
+            "SELECT name FROM pragma_table_info('message')",
+        ))
+        .await?
+        .into_iter()
+        .map(|row| row.try_get_by_index(0))
+        .collect::<Result<_, _>>()?;
+    existing.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    if existing != expected {
+        return Err(DbErr::Migration(format!(
+            "message has columns {existing:?}, expected {expected:?}"
+        )));
+    }
+
+    let rebuilt = Alias::new("message_rebuilt");
+    let mut create = Table::create();
+    create.table(rebuilt.clone());
+    for mut column in columns {
+        create.col(&mut column);
+    }
+    let create = create
+        .foreign_key(
+            ForeignKeyCreateStatement::new()
+                .name("fk_messages_user_id")
+                .from(rebuilt.clone(), Message::UserId)
+                .to(User::Table, User::Id)
+                .on_delete(on_delete)
+                .on_update(ForeignKeyAction::Cascade),
+        )
+        .foreign_key(
+            ForeignKeyCreateStatement::new()
+                .name("fk_messages_parent_id")
+                .from(rebuilt.clone(), Message::ParentId)
+                .to(Message::Table, Message::Id)
+                .on_delete(ForeignKeyAction::Cascade)
+                .on_update(ForeignKeyAction::Cascade),
+        )
+        .to_string(SqliteQueryBuilder);
+    let names = names.join(", ");
+    connection
+        .execute_unprepared(&format!(
+            "PRAGMA foreign_keys = OFF;
+            BEGIN;
+            {create};
+            INSERT INTO message_rebuilt ({names}) SELECT {names} FROM message;
+            DROP TABLE message;
+            ALTER TABLE message_rebuilt RENAME TO message;
+            COMMIT;
+            PRAGMA foreign_keys = ON;"
+        ))
+        .await?;
+
+    // The copy has to leave every reference intact, since nothing checked
+    // them while it ran.
+    let broken = connection
+        .query_all(Statement::from_string(
+            DatabaseBackend::Sqlite,
+            "PRAGMA foreign_key_check",
+        ))
+        .await?;
+    if !broken.is_empty() {
+        return Err(DbErr::Migration(format!(
+            "rebuilding message left {} broken foreign keys",
+            broken.len()
+        )));
+    }
+    Ok(())
+}
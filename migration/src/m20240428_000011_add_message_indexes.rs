@@ -0,0 +1,58 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden, Clone, Copy)]
+enum Message {
+    Table,
+    Id,
+    UserId,
+    ParentId,
+    CreatedAt,
+    ChannelId,
+}
+
+// Message listings are paged by `(created_at, id)`, either over all messages
+// in a time range or within one author's or channel's messages, and threads
+// are walked down through `parent_id`. Postgres does not index foreign key
+// columns on its own, so without these every page is a full scan and sort.
+const INDEXES: [(&str, &[Message]); 4] = [
+    (
+        "idx_message_created_at_id",
+        &[Message::CreatedAt, Message::Id],
+    ),
+    (
+        "idx_message_user_id_created_at_id",
+        &[Message::UserId, Message::CreatedAt, Message::Id],
+    ),
+    (
+        "idx_message_channel_id_created_at_id",
+        &[Message::ChannelId, Message::CreatedAt, Message::Id],
+    ),
+    ("idx_message_parent_id", &[Message::ParentId]),
+];
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for (name, columns) in INDEXES {
+            let mut index = Index::create();
+            index.name(name).table(Message::Table);
+            for column in columns {
+                index.col(*column);
+            }
+            manager.create_index(index).await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for (name, _) in INDEXES {
+            manager
+                .drop_index(Index::drop().name(name).table(Message::Table).to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use crate::auth::middleware::CurrentUser;
+use crate::auth::scope::Scope;
+use crate::entity::user::Role;
+use crate::error::AppError;
+use async_graphql::{Context, Guard, Result};
+
+// Rejects callers whose token does not carry `scope`. Anonymous callers are
+// only let through for scopes that cover public data.
+pub struct ScopeGuard {
+    scope: Scope,
+}
+
+impl ScopeGuard {
+    pub fn new(scope: Scope) -> Self {
+        Self { scope }
+    }
+}
+
+impl Guard for ScopeGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        match ctx.data_opt::<CurrentUser>() {
+            Some(user) if user.has_scope(self.scope) => Ok(()),
+            Some(_) => {
+                Err(AppError::Forbidden(format!("Missing required scope: {}", self.scope)).into())
+            }
+            None if self.scope.allows_anonymous() => Ok(()),
+            None => Err(AppError::Unauthenticated("Authentication required".to_string()).into()),
+        }
+    }
+}
+
+// Token management is only available to login sessions, so a leaked personal
+// access token cannot be used to mint or revoke other tokens.
+pub struct SessionGuard;
+
+impl Guard for SessionGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        match ctx.data_opt::<CurrentUser>() {
+            Some(user) if user.token_id.is_none() => Ok(()),
+            Some(_) => Err(AppError::Forbidden(
+                "Personal access tokens cannot manage tokens".to_string(),
+            )
+            .into()),
+            None => Err(AppError::Unauthenticated("Authentication required".to_string()).into()),
+        }
+    }
+}
+
+// Requires a caller with at least the given role.
+pub struct RoleGuard {
+    role: Role,
+}
+
+impl RoleGuard {
+    pub fn new(role: Role) -> Self {
+        Self { role }
+    }
+}
+
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let allowed = |role: Role| match self.role {
+            Role::Member => true,
+            Role::Moderator => role.can_moderate(),
+            Role::Admin => role.is_admin(),
+        };
+        match ctx.data_opt::<CurrentUser>() {
+            Some(user) if allowed(user.role) => Ok(()),
+            Some(_) => Err(AppError::Forbidden("Insufficient role".to_string()).into()),
+            None => Err(AppError::Unauthenticated("Authentication required".to_string()).into()),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::auth::personal_access_token::TOKEN_PREFIX;
+use crate::auth::scope::{parse_scopes, Scope};
+use crate::auth::token::TokenKeys;
+use crate::db::repository::{Actor, Repositories};
+use crate::entity::user::Role;
+use crate::error::AppError;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+// The authenticated caller, injected into the GraphQL request data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub role: Role,
+    pub scopes: Vec<Scope>,
+    // Set when the caller authenticated with a personal access token rather
+    // than a login session.
+    pub token_id: Option<i32>,
+}
+
+impl CurrentUser {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn actor(&self) -> Actor {
+        Actor {
+            id: self.id,
+            role: self.role,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthState {
+    pub keys: TokenKeys,
+    pub repos: Repositories,
+}
+
+// Validates `Authorization: Bearer <token>` if present. Requests without the
+// header pass through anonymously; a malformed or invalid token is rejected
+// outright rather than silently downgraded to anonymous.
+pub async fn authenticate(
+    State(state): State<AuthState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+        return next.run(req).await;
+    };
+
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned());
+    let Some(token) = token else {
+        return (StatusCode::UNAUTHORIZED, "Malformed Authorization header").into_response();
+    };
+
+    match resolve_current_user(&state, token).await {
+        Ok(Some(current_user)) => {
+            req.extensions_mut().insert(current_user);
+            next.run(req).await
+        }
+        Ok(None) => (StatusCode::UNAUTHORIZED, "Invalid or expired token").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to authenticate request: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Maps a bearer token to the caller. The user row is loaded on every request
+// so role changes and deleted accounts take effect immediately. Tokens that
+// do not check out give `None`; only server failures are errors.
+pub async fn resolve_current_user(
+    state: &AuthState,
+    token: String,
+) -> Result<Option<CurrentUser>, AppError> {
+    let (user_id, scopes, token_id) = if token.starts_with(TOKEN_PREFIX) {
+        match state.repos.tokens.authenticate(&token).await {
+            Ok(token) => (token.user_id, parse_scopes(&token.scopes), Some(token.id)),
+            Err(AppError::Unauthenticated(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    } else {
+        match state.keys.verify(&token) {
+            // A login session acts with the user's full authority. Scopes
+            // only narrow what a caller may attempt; whose account or message
+            // it may touch is still checked against the user's own id and role,
+            // so `users:admin` here does not reach other users' accounts.
+            Ok(claims) => (claims.sub, Scope::ALL.to_vec(), None),
+            Err(_) => return Ok(None),
+        }
+    };
+
+    let user = state.repos.users.get(user_id).await?;
+    Ok(user.map(|user| CurrentUser {
+        id: user.id,
+        role: user.role,
+        scopes,
+        token_id,
+    }))
+}
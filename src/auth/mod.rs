@@ -0,0 +1,6 @@
+pub mod guard;
+pub mod middleware;
+pub mod password;
+pub mod personal_access_token;
+pub mod scope;
+pub mod token;
@@ -0,0 +1,69 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordVerifier};
+use std::sync::OnceLock;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Hash a password with a fresh random salt. The returned PHC string embeds
+// the algorithm parameters and the salt, so it is all we need to store.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Checks a login against the stored hash, if there is one. Without one the
+// password is checked against a hash nothing matches, so that unknown names
+// and accounts without a password take as long to turn away as a wrong
+// password, and response times do not give away which names exist.
+pub fn verify_login(password: &str, password_hash: Option<&str>) -> bool {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    match password_hash {
+        Some(hash) => verify_password(password, hash),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| {
+                let unguessable = SaltString::generate(&mut OsRng);
+                hash_password(unguessable.as_str()).expect("Failed to hash dummy password")
+            });
+            verify_password(password, dummy);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify_password() {
+        let hash = hash_password("correct horse").expect("Failed to hash password");
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+    }
+
+    #[test]
+    fn test_verify_login_without_hash_fails() {
+        let hash = hash_password("correct horse").expect("Failed to hash password");
+        assert!(verify_login("correct horse", Some(&hash)));
+        assert!(!verify_login("correct horse", None));
+        assert!(!verify_login("", None));
+    }
+
+    #[test]
+    fn test_hash_is_salted() {
+        let first = hash_password("correct horse").expect("Failed to hash password");
+        let second = hash_password("correct horse").expect("Failed to hash password");
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,45 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// Prefix that tells the auth middleware to look the token up in the
+// database instead of verifying it as a signed session token.
+pub const TOKEN_PREFIX: &str = "pat_";
+pub const DEFAULT_EXPIRY_DAYS: i64 = 90;
+pub const MAX_EXPIRY_DAYS: i64 = 365;
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, to_hex(&bytes))
+}
+
+// Tokens carry 256 bits of randomness, so a fast unsalted hash is enough and
+// lets us find the row by hash in a single indexed lookup.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + 64);
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn test_hash_token_is_stable() {
+        let token = generate_token();
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+    }
+}
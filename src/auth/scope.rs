@@ -0,0 +1,80 @@
+use async_graphql::Enum;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Enum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    MessagesRead,
+    MessagesWrite,
+    UsersRead,
+    UsersAdmin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Scope::MessagesRead,
+        Scope::MessagesWrite,
+        Scope::UsersRead,
+        Scope::UsersAdmin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::MessagesRead => "messages:read",
+            Scope::MessagesWrite => "messages:write",
+            Scope::UsersRead => "users:read",
+            Scope::UsersAdmin => "users:admin",
+        }
+    }
+
+    // Read scopes cover data that is public anyway, so anonymous callers get them.
+    pub fn allows_anonymous(&self) -> bool {
+        matches!(self, Scope::MessagesRead | Scope::UsersRead)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("Unknown scope: {}", s))
+    }
+}
+
+// Scopes are stored space separated in `personal_access_token.scopes`.
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split_whitespace()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_round_trip() {
+        let scopes = vec![Scope::MessagesRead, Scope::UsersAdmin];
+        let stored = format_scopes(&scopes);
+        assert_eq!(stored, "messages:read users:admin");
+        assert_eq!(parse_scopes(&stored), scopes);
+    }
+}
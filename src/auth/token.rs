@@ -0,0 +1,64 @@
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+pub const TOKEN_TTL_HOURS: i64 = 24;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i32,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// Signing and verification keys for the HS256 bearer tokens handed out by `login`.
+#[derive(Clone)]
+pub struct TokenKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl TokenKeys {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    pub fn issue(&self, user_id: i32) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: user_id,
+            iat: now.timestamp(),
+            exp: (now + Duration::hours(TOKEN_TTL_HOURS)).timestamp(),
+        };
+        encode(&Header::default(), &claims, &self.encoding)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        let data = decode::<Claims>(token, &self.decoding, &Validation::default())?;
+        Ok(data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_verify_token() {
+        let keys = TokenKeys::new(b"test-secret");
+        let token = keys.issue(42).expect("Failed to issue token");
+        let claims = keys.verify(&token).expect("Failed to verify token");
+        assert_eq!(claims.sub, 42);
+    }
+
+    #[test]
+    fn test_reject_token_signed_with_other_secret() {
+        let token = TokenKeys::new(b"other-secret")
+            .issue(42)
+            .expect("Failed to issue token");
+        assert!(TokenKeys::new(b"test-secret").verify(&token).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::auth::token::TokenKeys;
+use crate::config::{Config, MigrationMode};
+use crate::db::dump;
+use crate::db::repository::{now, Actor, Repositories};
+use crate::db::seed::{self, SeedOptions};
+use crate::duration::Duration;
+use crate::entity::user::Role;
+use crate::error::AppError;
+use crate::server;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{DatabaseConnection, DbErr};
+use std::io::BufRead;
+use std::path::PathBuf;
+
+// The `backend` binary. Without a subcommand it serves, so existing
+// deployments keep working unchanged.
+#[derive(Debug, Parser)]
+#[command(name = "backend", about = "Serves and manages the pocketchange API")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Serve the GraphQL API (the default)
+    Serve,
+    /// Apply, roll back or list schema migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Fill the database with generated users and message threads
+    Seed(SeedArgs),
+    /// Manage users without going through the API
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Write every user, message and token to a JSON file
+    Export { path: PathBuf },
+    /// Load a file written by `export` into an empty database
+    Import { path: PathBuf },
+}
+
+#[derive(Debug, PartialEq, Args)]
+pub struct SeedArgs {
+    #[arg(long, default_value_t = 5)]
+    users: u32,
+    /// Root posts and replies together
+    #[arg(long, default_value_t = 50)]
+    messages: u32,
+    /// How many levels replies are nested below their root at most
+    #[arg(long, default_value_t = 3)]
+    max_depth: u32,
+    /// How many direct replies a message gets at most
+    #[arg(long, default_value_t = 3)]
+    branching: u32,
+    /// The same seed and date range always give the same data
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Post messages from this time on, e.g. 2024-04-01T00:00:00Z
+    #[arg(long, conflicts_with = "within")]
+    start: Option<DateTime<Utc>>,
+    /// Post messages up to this time [default: now]
+    #[arg(long)]
+    end: Option<DateTime<Utc>>,
+    /// Post messages over this long before the end, e.g. 30d
+    #[arg(long, default_value = "30d")]
+    within: Duration,
+    /// Succeed without changes if the sample users already exist
+    #[arg(long)]
+    skip_existing: bool,
+}
+
+impl SeedArgs {
+    fn options(&self) -> SeedOptions {
+        let end = self.end.unwrap_or_else(now);
+        SeedOptions {
+            users: self.users,
+            messages: self.messages,
+            max_depth: self.max_depth,
+            branching: self.branching,
+            seed: self.seed,
+            start: self.start.unwrap_or(end - self.within.0),
+            end,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum MigrateCommand {
+    /// Apply pending migrations, all of them unless limited
+    Up {
+        #[arg(short, long)]
+        num: Option<u32>,
+    },
+    /// Roll back the most recently applied migrations
+    Down {
+        #[arg(short, long, default_value_t = 1)]
+        num: u32,
+    },
+    /// List the migrations and whether they have been applied
+    Status,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum UserCommand {
+    /// Create a user, who can log in if given a password
+    Create {
+        name: String,
+        /// member, moderator or admin
+        #[arg(long, default_value = "member")]
+        role: Role,
+        /// Read the password from the first line of stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Rename a user
+    Rename { id: i32, name: String },
+    /// Delete a user and their tokens, leaving their messages as tombstones
+    Delete { id: i32 },
+}
+
+// Unlike API clients, operators are shown internal details.
+fn describe(error: AppError) -> String {
+    match error {
+        AppError::Internal(detail) => detail,
+        error => error.message().to_owned(),
+    }
+}
+
+pub async fn run(command: Command, config: &Config) -> Result<(), String> {
+    match command {
+        Command::Serve => {
+            // Only the server signs tokens, so the other commands run without
+            // a secret.
+            let secret = config.jwt_secret().map_err(|e| e.to_string())?;
+            let keys = TokenKeys::new(secret.as_bytes());
+            serve(config, keys, connect(config).await?).await
+        }
+        Command::Migrate(command) => migrate(&connect(config).await?, command)
+            .await
+            .map_err(|e| format!("Migrations failed: {}", e)),
+        command => {
+            let db = connect(config).await?;
+            // Changing the schema is left to `migrate`.
+            server::migrate(&db, MigrationMode::Verify)
+                .await
+                .map_err(|e| format!("{}; run `backend migrate up` first", e))?;
+            manage(&db, command).await
+        }
+    }
+}
+
+async fn connect(config: &Config) -> Result<DatabaseConnection, String> {
+    server::connect(&config.database)
+        .await
+        .map_err(|e| format!("Database connection failed: {}", e))
+}
+
+async fn serve(config: &Config, keys: TokenKeys, db: DatabaseConnection) -> Result<(), String> {
+    server::migrate(&db, config.database.migrations)
+        .await
+        .map_err(|e| format!("Migrations failed: {}", e))?;
+    if config.database.migrations == MigrationMode::MigrateAndExit {
+        tracing::info!("Migrations applied");
+        return Ok(());
+    }
+
+    let app = server::app(config, keys, db);
+
+    let listener = tokio::net::TcpListener::bind(config.server.listen_addr)
+        .await
+        .map_err(|e| format!("Could not listen on {}: {}", config.server.listen_addr, e))?;
+    if config.server.playground {
+        tracing::info!(
+            "GraphiQL: http://localhost:{}/graphiql",
+            listener.local_addr().unwrap().port()
+        );
+    }
+
+    axum::serve(listener, app.into_make_service())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn migrate(db: &DatabaseConnection, command: MigrateCommand) -> Result<(), DbErr> {
+    match command {
+        MigrateCommand::Up { num } => Migrator::up(db, num).await,
+        MigrateCommand::Down { num } => Migrator::down(db, Some(num)).await,
+        MigrateCommand::Status => {
+            for migration in Migrator::get_migration_with_status(db).await? {
+                println!("{:<8} {}", migration.status(), migration.name());
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn manage(db: &DatabaseConnection, command: Command) -> Result<(), String> {
+    let repos = Repositories::sea_orm(db.clone());
+    match command {
+        Command::Seed(args) => {
+            let options = args.options();
+            let summary = match seed::seed(db, &options).await {
+                Err(AppError::Conflict(message)) if args.skip_existing => {
+                    println!("{}, nothing to do", message);
+                    return Ok(());
+                }
+                result => result.map_err(describe)?,
+            };
+            println!(
+                "Created {} users (password {}) and {} messages in {} threads between {} and {}",
+                summary.users,
+                seed::SEED_PASSWORD,
+                summary.messages,
+                summary.threads,
+                options.start,
+                options.end
+            );
+        }
+        Command::User(command) => manage_user(&repos, command).await?,
+        Command::Export { path } => {
+            let dump = dump::export(db).await.map_err(describe)?;
+            let json = serde_json::to_string_pretty(&dump).map_err(|e| e.to_string())?;
+            std::fs::write(&path, json)
+                .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+            println!(
+                "Exported {} users, {} messages and {} tokens",
+                dump.users.len(),
+                dump.messages.len(),
+                dump.personal_access_tokens.len()
+            );
+        }
+        Command::Import { path } => {
+            let json = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+            let dump: dump::Dump = serde_json::from_str(&json)
+                .map_err(|e| format!("Invalid export file {}: {}", path.display(), e))?;
+            let counts = (
+                dump.users.len(),
+                dump.messages.len(),
+                dump.personal_access_tokens.len(),
+            );
+            dump::import(db, dump).await.map_err(describe)?;
+            println!(
+                "Imported {} users, {} messages and {} tokens",
+                counts.0, counts.1, counts.2
+            );
+        }
+        Command::Serve | Command::Migrate(_) => unreachable!("Handled by run"),
+    }
+    Ok(())
+}
+
+async fn manage_user(repos: &Repositories, command: UserCommand) -> Result<(), String> {
+    match command {
+        UserCommand::Create {
+            name,
+            role,
+            password_stdin,
+        } => {
+            let password = if password_stdin {
+                Some(read_password()?)
+            } else {
+                None
+            };
+            let user = repos
+                .users
+                .create_with_role(Actor::OPERATOR, &name, password.as_deref(), role)
+                .await
+                .map_err(describe)?;
+            println!("Created user {} ({})", user.id, user.name);
+        }
+        UserCommand::Rename { id, name } => {
+            let user = repos
+                .users
+                .update(Actor::OPERATOR, id, &name)
+                .await
+                .map_err(describe)?;
+            println!("Renamed user {} to {}", user.id, user.name);
+        }
+        UserCommand::Delete { id } => {
+            repos
+                .users
+                .delete(Actor::OPERATOR, id)
+                .await
+                .map_err(describe)?;
+            println!("Deleted user {}", id);
+        }
+    }
+    Ok(())
+}
+
+// Passwords are not taken as arguments, which would leave them in the shell
+// history and the process list.
+fn read_password() -> Result<String, String> {
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("Could not read password: {}", e))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, clap::Error> {
+        Cli::try_parse_from(std::iter::once("backend").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        Cli::command().debug_assert();
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(
+            parse(&["migrate", "down"]).unwrap(),
+            Some(Command::Migrate(MigrateCommand::Down { num: 1 }))
+        );
+        assert_eq!(
+            parse(&["migrate", "up", "-n", "2"]).unwrap(),
+            Some(Command::Migrate(MigrateCommand::Up { num: Some(2) }))
+        );
+        assert_eq!(
+            parse(&[
+                "user",
+                "create",
+                "Root",
+                "--role",
+                "admin",
+                "--password-stdin"
+            ])
+            .unwrap(),
+            Some(Command::User(UserCommand::Create {
+                name: "Root".to_string(),
+                role: Role::Admin,
+                password_stdin: true,
+            }))
+        );
+        assert!(parse(&["user", "create", "Root", "--role", "owner"]).is_err());
+        assert!(parse(&["user", "rename", "one", "Two"]).is_err());
+
+        let Some(Command::Seed(args)) =
+            parse(&["seed", "--end", "2024-04-01T00:00:00Z", "--within", "1w"]).unwrap()
+        else {
+            panic!("Expected the seed command");
+        };
+        let options = args.options();
+        assert_eq!(options.start.to_rfc3339(), "2024-03-25T00:00:00+00:00");
+        assert_eq!(options.messages, 50);
+        assert!(!args.skip_existing);
+        assert!(parse(&["seed", "--start", "2024-03-01T00:00:00Z", "--within", "1w"]).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::duration::Duration as DurationSetting;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use toml_edit::{Document, Item};
+use tracing::level_filters::LevelFilter;
+
+// Names the TOML file to read settings from. Without it, settings come from
+// the environment and the defaults only.
+pub const CONFIG_FILE_VAR: &str = "CONFIG_FILE";
+
+// Every setting, as its key in the TOML file and the environment variable
+// that overrides it.
+const SETTINGS: [(&str, &str); 16] = [
+    ("log_level", "LOG_LEVEL"),
+    ("jwt_secret", "JWT_SECRET"),
+    ("server.listen_addr", "LISTEN_ADDR"),
+    ("server.playground", "PLAYGROUND"),
+    ("server.max_query_depth", "MAX_QUERY_DEPTH"),
+    ("server.max_query_complexity", "MAX_QUERY_COMPLEXITY"),
+    ("server.max_body_size", "MAX_BODY_SIZE"),
+    ("database.url", "DATABASE_URL"),
+    ("database.max_connections", "DATABASE_MAX_CONNECTIONS"),
+    ("database.min_connections", "DATABASE_MIN_CONNECTIONS"),
+    ("database.connect_timeout", "DATABASE_CONNECT_TIMEOUT"),
+    ("database.acquire_timeout", "DATABASE_ACQUIRE_TIMEOUT"),
+    ("database.idle_timeout", "DATABASE_IDLE_TIMEOUT"),
+    ("database.max_lifetime", "DATABASE_MAX_LIFETIME"),
+    ("database.log_level", "DATABASE_LOG_LEVEL"),
+    ("database.migrations", "DATABASE_MIGRATIONS"),
+];
+
+// Everything the server reads at startup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    // The key used to sign bearer tokens. Only `serve` needs it, see
+    // `Config::jwt_secret`.
+    pub jwt_secret: Option<String>,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    // Whether the GraphQL playground is served at /graphiql.
+    pub playground: bool,
+    // `Message.replies` can be nested indefinitely, so bound how deep a query
+    // may select.
+    pub max_query_depth: usize,
+    // Unlimited unless set.
+    pub max_query_complexity: Option<usize>,
+    // In bytes.
+    pub max_body_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            playground: true,
+            max_query_depth: 32,
+            max_query_complexity: None,
+            max_body_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub connect_timeout: Duration,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_lifetime: Duration,
+    // Level SQL statements are logged at.
+    pub log_level: log::LevelFilter,
+    pub migrations: MigrationMode,
+}
+
+// What the server does about pending migrations at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MigrationMode {
+    // Applies them before serving.
+    #[default]
+    Auto,
+    // Refuses to start while any are pending, for deployments that migrate
+    // in a separate step.
+    Verify,
+    // Applies them and exits without serving.
+    MigrateAndExit,
+}
+
+impl FromStr for MigrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MigrationMode::Auto),
+            "verify" => Ok(MigrationMode::Verify),
+            "migrate-and-exit" => Ok(MigrationMode::MigrateAndExit),
+            _ => Err("expected auto, verify or migrate-and-exit".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Reads the settings from the environment (including a .env file) and
+    // the file named by CONFIG_FILE, if set. The environment takes
+    // precedence, so a deployment can override single values of a shared
+    // file.
+    pub fn load() -> Result<Self, ConfigError> {
+        dotenvy::dotenv().ok();
+        let env = |name: &str| std::env::var(name).ok();
+        let file =
+            match env(CONFIG_FILE_VAR) {
+                Some(path) => Some(std::fs::read_to_string(&path).map_err(|e| {
+                    ConfigError(format!("Could not read config file {}: {}", path, e))
+                })?),
+                None => None,
+            };
+        Self::from_sources(env, file.as_deref())
+    }
+
+    pub fn from_sources(
+        env: impl Fn(&str) -> Option<String>,
+        file: Option<&str>,
+    ) -> Result<Self, ConfigError> {
+        let file = file
+            .map(|contents| {
+                contents
+                    .parse::<Document>()
+                    .map_err(|e| ConfigError(format!("Invalid config file: {}", e)))
+            })
+            .transpose()?;
+        if let Some(file) = &file {
+            check_keys(file)?;
+        }
+        let settings = Settings { env: &env, file };
+
+        let defaults = ServerConfig::default();
+        let config = Config {
+            log_level: settings.parse("log_level")?.unwrap_or(LevelFilter::INFO),
+            jwt_secret: settings.get("jwt_secret")?,
+            server: ServerConfig {
+                listen_addr: settings
+                    .parse("server.listen_addr")?
+                    .unwrap_or(defaults.listen_addr),
+                playground: settings
+                    .parse("server.playground")?
+                    .unwrap_or(defaults.playground),
+                max_query_depth: settings
+                    .parse("server.max_query_depth")?
+                    .unwrap_or(defaults.max_query_depth),
+                max_query_complexity: settings.parse("server.max_query_complexity")?,
+                max_body_size: settings
+                    .parse("server.max_body_size")?
+                    .unwrap_or(defaults.max_body_size),
+            },
+            database: DatabaseConfig {
+                url: settings.required("database.url")?,
+                max_connections: settings.parse("database.max_connections")?.unwrap_or(100),
+                min_connections: settings.parse("database.min_connections")?.unwrap_or(5),
+                connect_timeout: settings.duration("database.connect_timeout", 8)?,
+                acquire_timeout: settings.duration("database.acquire_timeout", 8)?,
+                idle_timeout: settings.duration("database.idle_timeout", 8)?,
+                max_lifetime: settings.duration("database.max_lifetime", 8)?,
+                log_level: settings
+                    .parse("database.log_level")?
+                    .unwrap_or(log::LevelFilter::Info),
+                migrations: settings.parse("database.migrations")?.unwrap_or_default(),
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The token signing key, which is required to serve the API but not to
+    // run the maintenance commands.
+    pub fn jwt_secret(&self) -> Result<&str, ConfigError> {
+        self.jwt_secret
+            .as_deref()
+            .ok_or_else(|| not_set("jwt_secret"))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError(message.to_string()));
+        if self
+            .jwt_secret
+            .as_ref()
+            .is_some_and(|secret| secret.trim().is_empty())
+        {
+            return invalid("jwt_secret must not be empty");
+        }
+        if self.server.max_query_depth == 0 {
+            return invalid("server.max_query_depth must be positive");
+        }
+        if self.server.max_query_complexity == Some(0) {
+            return invalid("server.max_query_complexity must be positive");
+        }
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be positive");
+        }
+        if self.database.min_connections > self.database.max_connections {
+            return invalid("database.min_connections must not exceed database.max_connections");
+        }
+        Ok(())
+    }
+}
+
+// Rejects keys the server does not know, which are most likely typos.
+fn check_keys(file: &Document) -> Result<(), ConfigError> {
+    for (name, item) in file.iter() {
+        let keys: Vec<String> = match item.as_table_like() {
+            Some(table) => table
+                .iter()
+                .map(|(key, _)| format!("{}.{}", name, key))
+                .collect(),
+            None => vec![name.to_owned()],
+        };
+        for key in keys {
+            if !SETTINGS.iter().any(|(known, _)| *known == key) {
+                return Err(ConfigError(format!("Unknown setting {}", key)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn not_set(key: &str) -> ConfigError {
+    let (_, var) = SETTINGS.iter().find(|(known, _)| *known == key).unwrap();
+    ConfigError(format!(
+        "{} is not set (or {} in the config file)",
+        var, key
+    ))
+}
+
+struct Settings<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    file: Option<Document>,
+}
+
+impl Settings<'_> {
+    // The raw value of a setting, with empty environment variables counting
+    // as unset.
+    fn get(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        let (_, var) = SETTINGS
+            .iter()
+            .find(|(known, _)| *known == key)
+            .expect("Setting is not listed in SETTINGS");
+        if let Some(value) = (self.env)(var).filter(|value| !value.is_empty()) {
+            return Ok(Some(value));
+        }
+        let Some(file) = &self.file else {
+            return Ok(None);
+        };
+        let item = key
+            .split('.')
+            .try_fold(file.as_item(), |item: &Item, part| item.get(part));
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        if let Some(value) = item.as_str() {
+            Ok(Some(value.to_owned()))
+        } else if let Some(value) = item.as_integer() {
+            Ok(Some(value.to_string()))
+        } else if let Some(value) = item.as_bool() {
+            Ok(Some(value.to_string()))
+        } else {
+            Err(ConfigError(format!(
+                "{} must be a string, an integer or a boolean",
+                key
+            )))
+        }
+    }
+
+    fn parse<T>(&self, key: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(key)?
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| ConfigError(format!("Invalid {} \"{}\": {}", key, value, e)))
+            })
+            .transpose()
+    }
+
+    fn required(&self, key: &str) -> Result<String, ConfigError> {
+        self.get(key)?.ok_or_else(|| not_set(key))
+    }
+
+    // Written like the API's durations, e.g. "8s" or "5m".
+    fn duration(&self, key: &str, default_seconds: u64) -> Result<Duration, ConfigError> {
+        Ok(match self.parse::<DurationSetting>(key)? {
+            Some(duration) => duration.to_std(),
+            None => Duration::from_secs(default_seconds),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(env: &[(&str, &str)], file: Option<&str>) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::from_sources(|name| env.get(name).cloned(), file)
+    }
+
+    const REQUIRED: [(&str, &str); 2] = [
+        ("DATABASE_URL", "postgresql://localhost/pocketchange"),
+        ("JWT_SECRET", "secret"),
+    ];
+
+    #[test]
+    fn test_defaults() {
+        let config = load(&REQUIRED, None).unwrap();
+        assert_eq!(config.server, ServerConfig::default());
+        assert_eq!(config.log_level, LevelFilter::INFO);
+        assert_eq!(config.database.max_connections, 100);
+        assert_eq!(config.database.idle_timeout, Duration::from_secs(8));
+        assert_eq!(config.database.log_level, log::LevelFilter::Info);
+        assert_eq!(config.database.migrations, MigrationMode::Auto);
+
+        assert_eq!(config.jwt_secret(), Ok("secret"));
+
+        // Only serving needs the secret, so the configuration loads without it.
+        assert_eq!(
+            load(&REQUIRED[..1], None).unwrap().jwt_secret(),
+            Err(ConfigError(
+                "JWT_SECRET is not set (or jwt_secret in the config file)".to_string()
+            ))
+        );
+        assert_eq!(
+            load(&REQUIRED[1..], None).unwrap_err(),
+            ConfigError("DATABASE_URL is not set (or database.url in the config file)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let file = r#"
+            log_level = "warn"
+
+            [server]
+            listen_addr = "127.0.0.1:9000"
+            playground = false
+            max_query_complexity = 500
+
+            [database]
+            url = "postgresql://staging/pocketchange"
+            max_connections = 20
+            idle_timeout = "5m"
+        "#;
+        let mut env = REQUIRED.to_vec();
+        env.push(("DATABASE_MAX_CONNECTIONS", "40"));
+        env.push(("PLAYGROUND", ""));
+        env.push(("DATABASE_MIGRATIONS", "verify"));
+        let config = load(&env, Some(file)).unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::WARN);
+        assert_eq!(config.server.listen_addr.to_string(), "127.0.0.1:9000");
+        assert!(!config.server.playground);
+        assert_eq!(config.server.max_query_complexity, Some(500));
+        assert_eq!(config.database.url, "postgresql://localhost/pocketchange");
+        assert_eq!(config.database.max_connections, 40);
+        assert_eq!(config.database.idle_timeout, Duration::from_secs(300));
+        assert_eq!(config.database.migrations, MigrationMode::Verify);
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let invalid = |env: (&str, &str), file: Option<&str>| {
+            let mut vars = REQUIRED.to_vec();
+            vars.push(env);
+            load(&vars, file).unwrap_err().to_string()
+        };
+        assert_eq!(
+            invalid(("LISTEN_ADDR", "localhost"), None),
+            "Invalid server.listen_addr \"localhost\": invalid socket address syntax"
+        );
+        assert!(invalid(("DATABASE_IDLE_TIMEOUT", "8"), None)
+            .starts_with("Invalid database.idle_timeout \"8\""));
+        assert_eq!(
+            invalid(("DATABASE_MIN_CONNECTIONS", "200"), None),
+            "database.min_connections must not exceed database.max_connections"
+        );
+        assert_eq!(
+            invalid(("MAX_QUERY_DEPTH", "0"), None),
+            "server.max_query_depth must be positive"
+        );
+        assert_eq!(
+            invalid(
+                ("LOG_LEVEL", "info"),
+                Some("[server]\nlisten_address = \"0.0.0.0:80\"")
+            ),
+            "Unknown setting server.listen_address"
+        );
+        assert_eq!(
+            invalid(
+                ("LOG_LEVEL", "info"),
+                Some("[database]\nmax_connections = 1.5")
+            ),
+            "database.max_connections must be a string, an integer or a boolean"
+        );
+    }
+}
@@ -1,292 +1,1112 @@
-use crate::entity::{message, user};
+use crate::auth::password::{hash_password, verify_login};
+use crate::auth::personal_access_token::{generate_token, hash_token};
+use crate::auth::scope::{format_scopes, Scope};
+use crate::db::events::{self, DeletedMessage, EventBus, MessageEvent};
+use crate::db::mention::mentioned_names;
+use crate::db::repository::{
+    already_reacted, channel_name_taken, channel_not_found, channel_topic, check_can_hide,
+    check_can_purge, check_can_set_role, check_channel_access, check_channel_messages,
+    check_message_access, check_reply_parent, check_user_access, invalid_login, message_not_found,
+    name_taken, now, reaction_not_found, reply_channel, user_not_found, validate_channel_name,
+    validate_emoji, validate_registration, validate_token_scopes, Actor, ChannelChanges,
+    ChannelRepository, MessageCursor, MessagePage, MessageRepository, PageRequest, ReactionCount,
+    ReactionRepository, ThreadEntry, TokenRepository, UserRepository,
+};
+use crate::entity::channel::Visibility;
+use crate::entity::user::Role;
+use crate::entity::{
+    channel, message, message_mention, message_reaction, message_revision, personal_access_token,
+    user,
+};
+use crate::error::AppError;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use sea_orm::sea_query::{Expr, Query};
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, Set,
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseBackend, DatabaseConnection,
+    DbErr, EntityTrait, FromQueryResult, Order, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Select, Set, Statement, TransactionTrait,
 };
+use std::collections::HashSet;
 
-pub enum UserAction {
-    Create(String),
-    Delete(i32),
-    Update(i32, String),
-    Get(i32),
+// The repositories backed by a SeaORM connection.
+#[derive(Clone)]
+pub struct SeaOrmRepository {
+    db: DatabaseConnection,
+    events: EventBus,
 }
 
-pub enum MessageAction {
-    Create(i32, String, Option<i32>),
-    Get(i32),
-    GetAllForUser(i32),
-    GetInTimeRangeForUser(i32, DateTime<Utc>, DateTime<Utc>),
-    GetMessagesInThread(i32),
-    Update(i32, String),
-    Delete(i32),
-}
+impl SeaOrmRepository {
+    pub fn new(db: DatabaseConnection, events: EventBus) -> Self {
+        Self { db, events }
+    }
+
+    async fn user_by_name(&self, name: &str) -> Result<Option<user::Model>, DbErr> {
+        user::Entity::find()
+            .filter(user::Column::Name.eq(name))
+            .one(&self.db)
+            .await
+    }
 
-pub enum DatabaseAction {
-    Success,
-    Failure(String),
-    User(user::Model),
-    Message(message::Model),
-    Messages(Vec<message::Model>),
-    MessageThread(Vec<(message::Model, Option<user::Model>)>),
+    async fn find_user(&self, id: i32) -> Result<user::Model, AppError> {
+        user::Entity::find_by_id(id)
+            .one(&self.db)
+            .await?
+            .ok_or_else(user_not_found)
+    }
+
+    async fn find_message(&self, id: i32) -> Result<Option<message::Model>, DbErr> {
+        message::Entity::find_by_id(id).one(&self.db).await
+    }
+
+    async fn find_channel(&self, id: i32) -> Result<Option<channel::Model>, DbErr> {
+        channel::Entity::find_by_id(id).one(&self.db).await
+    }
 }
 
-pub async fn handle_user_action(
-    db: &DatabaseConnection,
-    action: UserAction,
-) -> Result<DatabaseAction, DbErr> {
-    match action {
-        UserAction::Create(name) => {
-            create_user(db, &name).await?;
-            Ok(DatabaseAction::Success)
-        }
-        UserAction::Delete(user_id) => {
-            delete_user(db, user_id).await?;
-            Ok(DatabaseAction::Success)
-        }
-        UserAction::Get(user_id) => {
-            let user = get_user(db, user_id).await?;
-            match user {
-                Some(user) => Ok(DatabaseAction::User(user)),
-                None => Ok(DatabaseAction::Failure("User not found".to_string())),
+#[async_trait]
+impl UserRepository for SeaOrmRepository {
+    async fn get(&self, id: i32) -> Result<Option<user::Model>, AppError> {
+        Ok(user::Entity::find_by_id(id).one(&self.db).await?)
+    }
+
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<user::Model>, AppError> {
+        Ok(user::Entity::find()
+            .filter(user::Column::Id.is_in(ids.iter().copied()))
+            .all(&self.db)
+            .await?)
+    }
+
+    async fn create(&self, name: &str) -> Result<user::Model, AppError> {
+        let user = user::ActiveModel {
+            name: Set(name.to_owned()),
+            ..Default::default()
+        };
+        Ok(user.insert(&self.db).await?)
+    }
+
+    async fn register(&self, name: &str, password: &str) -> Result<user::Model, AppError> {
+        validate_registration(name, password)?;
+        if self.user_by_name(name).await?.is_some() {
+            return Err(name_taken());
+        }
+
+        let password_hash =
+            hash_password(password).map_err(|e| AppError::Internal(e.to_string()))?;
+        let user = user::ActiveModel {
+            name: Set(name.to_owned()),
+            password_hash: Set(Some(password_hash)),
+            ..Default::default()
+        };
+        Ok(user.insert(&self.db).await?)
+    }
+
+    async fn create_with_role(
+        &self,
+        actor: Actor,
+        name: &str,
+        password: Option<&str>,
+        role: Role,
+    ) -> Result<user::Model, AppError> {
+        if role != Role::Member {
+            check_can_set_role(actor)?;
+        }
+        let password_hash = match password {
+            Some(password) => {
+                validate_registration(name, password)?;
+                Some(hash_password(password).map_err(|e| AppError::Internal(e.to_string()))?)
             }
+            None => None,
+        };
+        if self.user_by_name(name).await?.is_some() {
+            return Err(name_taken());
         }
-        UserAction::Update(user_id, name) => {
-            update_user(db, user_id, &name).await?;
-            Ok(DatabaseAction::Success)
+        let user = user::ActiveModel {
+            name: Set(name.to_owned()),
+            password_hash: Set(password_hash),
+            role: Set(role),
+            ..Default::default()
+        };
+        Ok(user.insert(&self.db).await?)
+    }
+
+    async fn login(&self, name: &str, password: &str) -> Result<user::Model, AppError> {
+        let user = self.user_by_name(name).await?;
+        let password_hash = user.as_ref().and_then(|user| user.password_hash.as_deref());
+        if verify_login(password, password_hash) {
+            user.ok_or_else(invalid_login)
+        } else {
+            Err(invalid_login())
         }
     }
-}
 
-async fn create_user(db: &DatabaseConnection, name: &str) -> Result<DatabaseAction, DbErr> {
-    let user = user::ActiveModel {
-        name: Set(name.to_owned()),
-        ..Default::default()
-    };
-    user.insert(db).await?;
-    Ok(DatabaseAction::Success)
-}
+    async fn update(&self, actor: Actor, id: i32, name: &str) -> Result<user::Model, AppError> {
+        check_user_access(actor, id)?;
+        let mut user: user::ActiveModel = self.find_user(id).await?.into();
+        user.name = Set(name.to_owned());
+        Ok(user.update(&self.db).await?)
+    }
 
-// Update the user's name
-async fn update_user(
-    db: &DatabaseConnection,
-    user_id: i32,
-    new_name: &str,
-) -> Result<DatabaseAction, DbErr> {
-    let filtered_user = user::Entity::find_by_id(user_id).one(db).await?;
-    if let Some(user) = filtered_user {
-        let mut mut_filtered_user: user::ActiveModel = user.into();
-        mut_filtered_user.name = Set(new_name.to_owned());
-        mut_filtered_user.update(db).await?;
-        Ok(DatabaseAction::Success)
-    } else {
-        Ok(DatabaseAction::Failure("User not found".to_string()))
+    async fn set_role(&self, actor: Actor, id: i32, role: Role) -> Result<user::Model, AppError> {
+        check_can_set_role(actor)?;
+        let mut user: user::ActiveModel = self.find_user(id).await?.into();
+        user.role = Set(role);
+        Ok(user.update(&self.db).await?)
     }
-}
 
-async fn delete_user(db: &DatabaseConnection, user_id: i32) -> Result<DatabaseAction, DbErr> {
-    let result = user::Entity::delete_by_id(user_id).exec(db).await?;
-    if result.rows_affected > 0 {
-        Ok(DatabaseAction::Success)
-    } else {
-        Ok(DatabaseAction::Failure("User not found".to_string()))
+    async fn delete(&self, actor: Actor, id: i32) -> Result<(), AppError> {
+        check_user_access(actor, id)?;
+        let transaction = self.db.begin().await?;
+        let authored = message::Entity::find()
+            .filter(message::Column::UserId.eq(id))
+            .all(&transaction)
+            .await?;
+        let ids: Vec<i32> = authored.iter().map(|message| message.id).collect();
+        tombstone_messages(&transaction, &ids).await?;
+        // The foreign key clears the author of the tombstones.
+        let result = user::Entity::delete_by_id(id).exec(&transaction).await?;
+        if result.rows_affected == 0 {
+            transaction.rollback().await?;
+            return Err(user_not_found());
+        }
+        transaction.commit().await?;
+        for message in authored
+            .iter()
+            .filter(|message| message.deleted_at.is_none())
+        {
+            events::emit(
+                &self.db,
+                &self.events,
+                MessageEvent::Deleted(DeletedMessage::from(message)),
+            )
+            .await;
+        }
+        Ok(())
     }
 }
 
-async fn get_user(db: &DatabaseConnection, user_id: i32) -> Result<Option<user::Model>, DbErr> {
-    let user = user::Entity::find_by_id(user_id).one(db).await?;
-    Ok(user)
-}
+#[async_trait]
+impl MessageRepository for SeaOrmRepository {
+    async fn get(&self, id: i32) -> Result<Option<message::Model>, AppError> {
+        Ok(self.find_message(id).await?)
+    }
 
-pub async fn handle_message_action(
-    db: &DatabaseConnection,
-    action: MessageAction,
-) -> Result<DatabaseAction, DbErr> {
-    match action {
-        MessageAction::Create(user_id, content, parent_id) => {
-            create_message(db, user_id, &content, parent_id).await?;
-            Ok(DatabaseAction::Success)
-        }
-        MessageAction::Get(message_id) => {
-            let message = get_message(db, message_id).await?;
-            match message {
-                Some(message) => Ok(DatabaseAction::Message(message)),
-                None => Ok(DatabaseAction::Failure("Message not found".to_string())),
+    async fn create(
+        &self,
+        user_id: i32,
+        content: &str,
+        parent_id: Option<i32>,
+        channel_id: Option<i32>,
+    ) -> Result<message::Model, AppError> {
+        let mut channel_id = channel_id;
+        if let Some(parent_id) = parent_id {
+            let parent = self.find_message(parent_id).await?;
+            check_reply_parent(parent.as_ref())?;
+            if let Some(parent) = parent {
+                channel_id = reply_channel(&parent, channel_id)?;
             }
         }
-        MessageAction::Update(message_id, content) => {
-            update_message(db, message_id, &content).await?;
-            Ok(DatabaseAction::Success)
+        if let Some(channel_id) = channel_id {
+            self.find_channel(channel_id)
+                .await?
+                .ok_or_else(channel_not_found)?;
+        }
+        let created_at = now();
+        let message = message::ActiveModel {
+            user_id: Set(Some(user_id)),
+            content: Set(content.to_owned()),
+            parent_id: Set(parent_id),
+            channel_id: Set(channel_id),
+            created_at: Set(created_at),
+            updated_at: Set(created_at),
+            ..Default::default()
+        };
+        let transaction = self.db.begin().await?;
+        let message = message.insert(&transaction).await?;
+        sync_mentions(&transaction, message.id, content).await?;
+        transaction.commit().await?;
+        events::emit(
+            &self.db,
+            &self.events,
+            MessageEvent::Created(message.clone()),
+        )
+        .await;
+        Ok(message)
+    }
+
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        content: &str,
+    ) -> Result<message::Model, AppError> {
+        let transaction = self.db.begin().await?;
+        // Concurrent edits take turns, so each saves the content it actually
+        // replaces.
+        let message = lock_message(&transaction, id).await?;
+        if let Err(e) = check_message_access(message.as_ref(), actor, false) {
+            transaction.rollback().await?;
+            return Err(e);
+        }
+        let message = message.ok_or_else(message_not_found)?;
+        let revision = message_revision::Entity::find()
+            .filter(message_revision::Column::MessageId.eq(id))
+            .count(&transaction)
+            .await?;
+        message_revision::ActiveModel {
+            message_id: Set(id),
+            revision: Set(revision as i32),
+            content: Set(message.content.clone()),
+            created_at: Set(message.updated_at),
+            ..Default::default()
         }
-        MessageAction::Delete(message_id) => {
-            delete_message(db, message_id).await?;
-            Ok(DatabaseAction::Success)
+        .insert(&transaction)
+        .await?;
+        let mut message: message::ActiveModel = message.into();
+        message.content = Set(content.to_owned());
+        message.updated_at = Set(now());
+        let message = message.update(&transaction).await?;
+        sync_mentions(&transaction, id, content).await?;
+        transaction.commit().await?;
+        events::emit(
+            &self.db,
+            &self.events,
+            MessageEvent::Updated(message.clone()),
+        )
+        .await;
+        Ok(message)
+    }
+
+    async fn revisions(
+        &self,
+        message_ids: &[i32],
+    ) -> Result<Vec<message_revision::Model>, AppError> {
+        Ok(message_revision::Entity::find()
+            .filter(message_revision::Column::MessageId.is_in(message_ids.iter().copied()))
+            .order_by_asc(message_revision::Column::MessageId)
+            .order_by_asc(message_revision::Column::Revision)
+            .all(&self.db)
+            .await?)
+    }
+
+    async fn mentions(&self, message_ids: &[i32]) -> Result<Vec<(i32, user::Model)>, AppError> {
+        let mentions = message_mention::Entity::find()
+            .find_also_related(user::Entity)
+            .filter(message_mention::Column::MessageId.is_in(message_ids.iter().copied()))
+            .order_by_asc(message_mention::Column::MessageId)
+            .order_by_asc(user::Column::Name)
+            .all(&self.db)
+            .await?;
+        Ok(mentions
+            .into_iter()
+            .filter_map(|(mention, user)| user.map(|user| (mention.message_id, user)))
+            .collect())
+    }
+
+    async fn hide(&self, actor: Actor, id: i32, hidden: bool) -> Result<message::Model, AppError> {
+        check_can_hide(actor)?;
+        let message = self
+            .find_message(id)
+            .await?
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or_else(message_not_found)?;
+        let mut message: message::ActiveModel = message.into();
+        message.hidden_at = Set(hidden.then(now));
+        let message = message.update(&self.db).await?;
+        events::emit(
+            &self.db,
+            &self.events,
+            MessageEvent::Updated(message.clone()),
+        )
+        .await;
+        Ok(message)
+    }
+
+    async fn delete(&self, actor: Actor, id: i32) -> Result<message::Model, AppError> {
+        let transaction = self.db.begin().await?;
+        // Checked against the locked row, so a message is deleted only once
+        // and never on the strength of an outdated read.
+        let message = lock_message(&transaction, id).await?;
+        if let Err(e) = check_message_access(message.as_ref(), actor, true) {
+            transaction.rollback().await?;
+            return Err(e);
+        }
+        let mut message: message::ActiveModel = message.ok_or_else(message_not_found)?.into();
+        message.deleted_at = Set(Some(now()));
+        let message = message.update(&transaction).await?;
+        message_reaction::Entity::delete_many()
+            .filter(message_reaction::Column::MessageId.eq(id))
+            .exec(&transaction)
+            .await?;
+        message_mention::Entity::delete_many()
+            .filter(message_mention::Column::MessageId.eq(id))
+            .exec(&transaction)
+            .await?;
+        transaction.commit().await?;
+        events::emit(
+            &self.db,
+            &self.events,
+            MessageEvent::Deleted(DeletedMessage::from(&message)),
+        )
+        .await;
+        Ok(message)
+    }
+
+    async fn purge(&self, actor: Actor, id: i32) -> Result<message::Model, AppError> {
+        check_can_purge(actor)?;
+        let transaction = self.db.begin().await?;
+        // Replies go with it through the foreign key, so they are looked up
+        // first to announce them as well.
+        let removed: Vec<message::Model> = match fetch_message_thread(&transaction, id, None).await
+        {
+            Ok(thread) => thread.into_iter().map(|entry| entry.message).collect(),
+            Err(AppError::NotFound(_)) => {
+                transaction.rollback().await?;
+                return Err(message_not_found());
+            }
+            Err(e) => return Err(e),
+        };
+        message::Entity::delete_by_id(id).exec(&transaction).await?;
+        transaction.commit().await?;
+        // Tombstones were announced as deleted already.
+        for message in removed
+            .iter()
+            .filter(|message| message.deleted_at.is_none())
+        {
+            events::emit(
+                &self.db,
+                &self.events,
+                MessageEvent::Deleted(DeletedMessage::from(message)),
+            )
+            .await;
+        }
+        // The rows are gone, but callers still want to know where the root was.
+        Ok(removed
+            .into_iter()
+            .next()
+            .expect("Threads start at their root"))
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let query = message::Entity::find()
+            .filter(message::Column::UserId.eq(user_id))
+            .filter(message::Column::DeletedAt.is_null());
+        Ok(paginate_messages(&self.db, query, page).await?)
+    }
+
+    async fn list_in_time_range(
+        &self,
+        user_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let mut query = message::Entity::find()
+            .filter(message::Column::UserId.eq(user_id))
+            .filter(message::Column::DeletedAt.is_null());
+        if let Some(start) = start {
+            query = query.filter(message::Column::CreatedAt.gte(start));
         }
-        MessageAction::GetAllForUser(user_id) => {
-            let messages = get_all_messages_for_user(db, user_id).await?;
-            Ok(DatabaseAction::Messages(messages))
+        if let Some(end) = end {
+            query = query.filter(message::Column::CreatedAt.lte(end));
         }
-        MessageAction::GetInTimeRangeForUser(user_id, start, end) => {
-            let messages = get_messages_in_time_range(db, user_id, start, end).await?;
-            Ok(DatabaseAction::Messages(messages))
+        Ok(paginate_messages(&self.db, query, page).await?)
+    }
+
+    async fn list_in_channel(
+        &self,
+        channel_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let mut query = message::Entity::find()
+            .filter(message::Column::ChannelId.eq(channel_id))
+            .filter(message::Column::DeletedAt.is_null());
+        if let Some(start) = start {
+            query = query.filter(message::Column::CreatedAt.gte(start));
         }
-        MessageAction::GetMessagesInThread(message_id) => {
-            let messages = fetch_message_thread(db, message_id).await?;
-            Ok(DatabaseAction::MessageThread(messages))
+        if let Some(end) = end {
+            query = query.filter(message::Column::CreatedAt.lte(end));
         }
+        Ok(paginate_messages(&self.db, query, page).await?)
+    }
+
+    async fn list_replies(
+        &self,
+        parent_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let query = message::Entity::find().filter(message::Column::ParentId.eq(parent_id));
+        Ok(paginate_messages(&self.db, query, page).await?)
+    }
+
+    async fn list_mentioning(
+        &self,
+        user_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let mentioned = Query::select()
+            .column(message_mention::Column::MessageId)
+            .from(message_mention::Entity)
+            .and_where(message_mention::Column::UserId.eq(user_id))
+            .to_owned();
+        let query = message::Entity::find().filter(message::Column::Id.in_subquery(mentioned));
+        Ok(paginate_messages(&self.db, query, page).await?)
+    }
+
+    async fn thread(
+        &self,
+        root_id: i32,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<ThreadEntry>, AppError> {
+        fetch_message_thread(&self.db, root_id, max_depth).await
     }
 }
 
-async fn create_message(
-    db: &DatabaseConnection,
-    user_id: i32,
-    content: &str,
-    parent_id: Option<i32>,
-) -> Result<DatabaseAction, DbErr> {
-    let message = message::ActiveModel {
-        user_id: Set(user_id),
-        content: Set(content.to_owned()),
-        parent_id: Set(parent_id),
-        ..Default::default()
-    };
-    message.insert(db).await?;
-    Ok(DatabaseAction::Success)
+#[async_trait]
+impl ChannelRepository for SeaOrmRepository {
+    async fn get(&self, id: i32) -> Result<Option<channel::Model>, AppError> {
+        Ok(self.find_channel(id).await?)
+    }
+
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<channel::Model>, AppError> {
+        Ok(channel::Entity::find()
+            .filter(channel::Column::Id.is_in(ids.iter().copied()))
+            .all(&self.db)
+            .await?)
+    }
+
+    async fn list_public(&self) -> Result<Vec<channel::Model>, AppError> {
+        Ok(channel::Entity::find()
+            .filter(channel::Column::Visibility.eq(Visibility::Public))
+            .order_by_asc(channel::Column::Name)
+            .all(&self.db)
+            .await?)
+    }
+
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        topic: Option<&str>,
+        visibility: Visibility,
+    ) -> Result<channel::Model, AppError> {
+        validate_channel_name(name)?;
+        let created_at = now();
+        let channel = channel::ActiveModel {
+            name: Set(name.trim().to_owned()),
+            topic: Set(channel_topic(topic)),
+            created_by: Set(Some(user_id)),
+            visibility: Set(visibility),
+            created_at: Set(created_at),
+            updated_at: Set(created_at),
+            ..Default::default()
+        };
+        channel.insert(&self.db).await.map_err(channel_error)
+    }
+
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        changes: ChannelChanges,
+    ) -> Result<channel::Model, AppError> {
+        let channel = self.find_channel(id).await?;
+        check_channel_access(channel.as_ref(), actor)?;
+        let mut channel: channel::ActiveModel = channel.ok_or_else(channel_not_found)?.into();
+        if let Some(name) = changes.name {
+            validate_channel_name(&name)?;
+            channel.name = Set(name.trim().to_owned());
+        }
+        if let Some(topic) = changes.topic {
+            channel.topic = Set(channel_topic(Some(&topic)));
+        }
+        if let Some(visibility) = changes.visibility {
+            channel.visibility = Set(visibility);
+        }
+        channel.updated_at = Set(now());
+        channel.update(&self.db).await.map_err(channel_error)
+    }
+
+    async fn delete(&self, actor: Actor, id: i32) -> Result<channel::Model, AppError> {
+        let transaction = self.db.begin().await?;
+        // Locked so no message can be posted to the channel meanwhile.
+        let channel = channel::Entity::find_by_id(id)
+            .lock_exclusive()
+            .one(&transaction)
+            .await?;
+        let messages = message::Entity::find()
+            .filter(message::Column::ChannelId.eq(id))
+            .all(&transaction)
+            .await?;
+        let allowed = check_channel_access(channel.as_ref(), actor)
+            .and_then(|()| check_channel_messages(&messages, actor));
+        if let Err(e) = allowed {
+            transaction.rollback().await?;
+            return Err(e);
+        }
+        let channel = channel.ok_or_else(channel_not_found)?;
+        let ids: Vec<i32> = messages.iter().map(|message| message.id).collect();
+        tombstone_messages(&transaction, &ids).await?;
+        // Detached, or the foreign key would take the tombstones along.
+        message::Entity::update_many()
+            .col_expr(message::Column::ChannelId, Expr::value(None::<i32>))
+            .filter(message::Column::ChannelId.eq(id))
+            .exec(&transaction)
+            .await?;
+        channel::Entity::delete_by_id(id).exec(&transaction).await?;
+        transaction.commit().await?;
+        for message in messages
+            .iter()
+            .filter(|message| message.deleted_at.is_none())
+        {
+            events::emit(
+                &self.db,
+                &self.events,
+                MessageEvent::Deleted(DeletedMessage::from(message)),
+            )
+            .await;
+        }
+        Ok(channel)
+    }
 }
 
-async fn get_message(
-    db: &DatabaseConnection,
-    message_id: i32,
-) -> Result<Option<message::Model>, DbErr> {
-    let message = message::Entity::find_by_id(message_id).one(db).await?;
-    Ok(message)
+fn channel_error(error: DbErr) -> AppError {
+    match AppError::from(error) {
+        AppError::Conflict(_) => channel_name_taken(),
+        error => error,
+    }
 }
 
-async fn update_message(
-    db: &DatabaseConnection,
-    message_id: i32,
-    new_content: &str,
-) -> Result<DatabaseAction, DbErr> {
-    let filtered_message = message::Entity::find_by_id(message_id).one(db).await?;
-    if let Some(filtered_message) = filtered_message {
-        let mut mut_filtered_message: message::ActiveModel = filtered_message.into();
-        mut_filtered_message.content = Set(new_content.to_owned());
-        mut_filtered_message.updated_at = Set(chrono::Utc::now());
-        mut_filtered_message.update(db).await?;
-        Ok(DatabaseAction::Success)
-    } else {
-        Ok(DatabaseAction::Failure("Message not found".to_string()))
+// Reads the message and locks it until the transaction ends, so changes to it
+// take turns. SQLite has no row locks but lets one transaction write at a
+// time; writing before reading claims that right up front, where two
+// transactions that had both read first would deadlock.
+async fn lock_message<C>(db: &C, id: i32) -> Result<Option<message::Model>, DbErr>
+where
+    C: ConnectionTrait,
+{
+    if db.get_database_backend() == DatabaseBackend::Sqlite {
+        message::Entity::update_many()
+            .col_expr(
+                message::Column::UpdatedAt,
+                Expr::col(message::Column::UpdatedAt).into(),
+            )
+            .filter(message::Column::Id.eq(id))
+            .exec(db)
+            .await?;
     }
+    message::Entity::find_by_id(id)
+        .lock_exclusive()
+        .one(db)
+        .await
 }
 
-async fn delete_message(db: &DatabaseConnection, message_id: i32) -> Result<DatabaseAction, DbErr> {
-    let result = message::Entity::delete_by_id(message_id).exec(db).await?;
-    if result.rows_affected > 0 {
-        Ok(DatabaseAction::Success)
-    } else {
-        Ok(DatabaseAction::Failure("Message not found".to_string()))
+// Marks the messages deleted, keeping the rows so the replies below them stay
+// in place, and drops their reactions and mentions like `delete` does.
+// Messages deleted before keep their time.
+async fn tombstone_messages<C>(db: &C, ids: &[i32]) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    if ids.is_empty() {
+        return Ok(());
     }
+    message::Entity::update_many()
+        .col_expr(message::Column::DeletedAt, Expr::value(now()))
+        .filter(message::Column::Id.is_in(ids.iter().copied()))
+        .filter(message::Column::DeletedAt.is_null())
+        .exec(db)
+        .await?;
+    message_reaction::Entity::delete_many()
+        .filter(message_reaction::Column::MessageId.is_in(ids.iter().copied()))
+        .exec(db)
+        .await?;
+    message_mention::Entity::delete_many()
+        .filter(message_mention::Column::MessageId.is_in(ids.iter().copied()))
+        .exec(db)
+        .await?;
+    Ok(())
 }
 
-async fn get_all_messages_for_user(
-    db: &DatabaseConnection,
-    user_id: i32,
-) -> Result<Vec<message::Model>, DbErr> {
-    let messages = message::Entity::find()
-        .filter(message::Column::UserId.eq(user_id))
-        .all(db)
+// Records the users the content mentions and drops those it no longer does.
+// Mentions that still apply are left alone, so they keep their time.
+pub async fn sync_mentions<C>(db: &C, message_id: i32, content: &str) -> Result<(), DbErr>
+where
+    C: ConnectionTrait,
+{
+    let names = mentioned_names(content);
+    let mut user_ids = Vec::new();
+    if !names.is_empty() {
+        user_ids = user::Entity::find()
+            .filter(user::Column::Name.is_in(names))
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+    }
+    message_mention::Entity::delete_many()
+        .filter(message_mention::Column::MessageId.eq(message_id))
+        .filter(message_mention::Column::UserId.is_not_in(user_ids.iter().copied()))
+        .exec(db)
         .await?;
+    let kept: HashSet<i32> = message_mention::Entity::find()
+        .filter(message_mention::Column::MessageId.eq(message_id))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|mention| mention.user_id)
+        .collect();
+    let created_at = now();
+    let added: Vec<message_mention::ActiveModel> = user_ids
+        .into_iter()
+        .filter(|user_id| !kept.contains(user_id))
+        .map(|user_id| message_mention::ActiveModel {
+            message_id: Set(message_id),
+            user_id: Set(user_id),
+            created_at: Set(created_at),
+            ..Default::default()
+        })
+        .collect();
+    if !added.is_empty() {
+        message_mention::Entity::insert_many(added).exec(db).await?;
+    }
+    Ok(())
+}
 
-    Ok(messages)
+#[derive(FromQueryResult)]
+struct EmojiCount {
+    message_id: i32,
+    emoji: String,
+    count: i64,
 }
 
-async fn get_messages_in_time_range(
-    db: &DatabaseConnection,
-    user_id: i32,
-    start: DateTime<Utc>,
-    end: DateTime<Utc>,
-) -> Result<Vec<message::Model>, DbErr> {
-    let messages = message::Entity::find()
-        .filter(message::Column::UserId.eq(user_id))
-        .filter(message::Column::CreatedAt.between(start, end))
-        .all(db)
-        .await?;
+#[async_trait]
+impl ReactionRepository for SeaOrmRepository {
+    async fn add(
+        &self,
+        user_id: i32,
+        message_id: i32,
+        emoji: &str,
+    ) -> Result<message_reaction::Model, AppError> {
+        validate_emoji(emoji)?;
+        self.find_message(message_id)
+            .await?
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or_else(message_not_found)?;
+        let reaction = message_reaction::ActiveModel {
+            message_id: Set(message_id),
+            user_id: Set(user_id),
+            emoji: Set(emoji.to_owned()),
+            created_at: Set(now()),
+            ..Default::default()
+        };
+        reaction
+            .insert(&self.db)
+            .await
+            .map_err(|e| match AppError::from(e) {
+                AppError::Conflict(_) => already_reacted(),
+                e => e,
+            })
+    }
 
-    Ok(messages)
+    async fn remove(&self, user_id: i32, message_id: i32, emoji: &str) -> Result<(), AppError> {
+        let result = message_reaction::Entity::delete_many()
+            .filter(message_reaction::Column::MessageId.eq(message_id))
+            .filter(message_reaction::Column::UserId.eq(user_id))
+            .filter(message_reaction::Column::Emoji.eq(emoji))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected > 0 {
+            Ok(())
+        } else {
+            Err(reaction_not_found())
+        }
+    }
+
+    async fn counts(
+        &self,
+        message_ids: &[i32],
+        viewer_id: Option<i32>,
+    ) -> Result<Vec<ReactionCount>, AppError> {
+        let counts = message_reaction::Entity::find()
+            .select_only()
+            .column(message_reaction::Column::MessageId)
+            .column(message_reaction::Column::Emoji)
+            .column_as(message_reaction::Column::Id.count(), "count")
+            .filter(message_reaction::Column::MessageId.is_in(message_ids.iter().copied()))
+            .group_by(message_reaction::Column::MessageId)
+            .group_by(message_reaction::Column::Emoji)
+            .into_model::<EmojiCount>()
+            .all(&self.db)
+            .await?;
+        let mut own = HashSet::new();
+        if let Some(viewer_id) = viewer_id {
+            own = message_reaction::Entity::find()
+                .filter(message_reaction::Column::MessageId.is_in(message_ids.iter().copied()))
+                .filter(message_reaction::Column::UserId.eq(viewer_id))
+                .all(&self.db)
+                .await?
+                .into_iter()
+                .map(|reaction| (reaction.message_id, reaction.emoji))
+                .collect();
+        }
+        Ok(counts
+            .into_iter()
+            .map(|count| ReactionCount {
+                reacted: own.contains(&(count.message_id, count.emoji.clone())),
+                message_id: count.message_id,
+                emoji: count.emoji,
+                count: count.count,
+            })
+            .collect())
+    }
 }
 
-async fn fetch_replies(
+// Keyset pagination over (created_at, id). One extra row is fetched to find
+// out whether the list continues in the direction we are reading.
+async fn paginate_messages(
     db: &DatabaseConnection,
-    parent_id: i32,
-    thread: &mut Vec<(message::Model, Option<user::Model>)>,
-) -> Result<(), DbErr> {
-    let replies = message::Entity::find()
-        .filter(message::Column::ParentId.eq(parent_id))
+    mut query: Select<message::Entity>,
+    page: PageRequest,
+) -> Result<MessagePage, DbErr> {
+    if let Some(after) = page.after {
+        query = query.filter(beyond(after, !page.newest_first));
+    }
+    if let Some(before) = page.before {
+        query = query.filter(beyond(before, page.newest_first));
+    }
+
+    let order = if page.reads_descending() {
+        Order::Desc
+    } else {
+        Order::Asc
+    };
+    let messages = query
+        .order_by(message::Column::CreatedAt, order.clone())
+        .order_by(message::Column::Id, order)
+        .limit(page.clamped_limit() + 1)
         .all(db)
         .await?;
+    Ok(MessagePage::from_rows(messages, &page))
+}
 
-    for reply in replies {
-        let user = user::Entity::find_by_id(reply.user_id).one(db).await?;
-        thread.push((reply.clone(), user));
-        Box::pin(fetch_replies(db, reply.id, thread)).await?;
-    }
-    Ok(())
+// Rows past `cursor` in (created_at, id) order, looking upwards when
+// `ascending` and downwards otherwise.
+fn beyond(cursor: MessageCursor, ascending: bool) -> Condition {
+    let (created_at, id) = if ascending {
+        (
+            message::Column::CreatedAt.gt(cursor.created_at),
+            message::Column::Id.gt(cursor.id),
+        )
+    } else {
+        (
+            message::Column::CreatedAt.lt(cursor.created_at),
+            message::Column::Id.lt(cursor.id),
+        )
+    };
+    Condition::any().add(created_at).add(
+        Condition::all()
+            .add(message::Column::CreatedAt.eq(cursor.created_at))
+            .add(id),
+    )
 }
 
-async fn fetch_message_thread(
-    db: &DatabaseConnection,
-    message_id: i32,
-) -> Result<Vec<(message::Model, Option<user::Model>)>, DbErr> {
-    // Fetch the root message
-    let root_message = message::Entity::find_by_id(message_id)
-        .one(db)
-        .await?
-        .ok_or_else(|| DbErr::Custom("Root message not found".to_owned()))?;
+// Walks the reply tree below the root in a single query, with each message's
+// author, depth and path from the root. Without `max_depth` the whole subtree
+// is returned.
+const THREAD_QUERY: &str = r#"
+WITH RECURSIVE thread AS (
+    SELECT id, user_id, content, created_at, updated_at, parent_id, hidden_at, deleted_at,
+           channel_id, 0 AS depth, CAST(id AS TEXT) AS path
+    FROM message
+    WHERE id = $1
+    UNION ALL
+    SELECT m.id, m.user_id, m.content, m.created_at, m.updated_at, m.parent_id, m.hidden_at,
+           m.deleted_at, m.channel_id, t.depth + 1, t.path || '/' || CAST(m.id AS TEXT)
+    FROM message m
+    JOIN thread t ON m.parent_id = t.id
+    WHERE t.depth < $2
+)
+SELECT thread.*,
+       u.id AS u_id, u.name AS u_name, u.password_hash AS u_password_hash, u.role AS u_role
+FROM thread
+LEFT JOIN "user" u ON u.id = thread.user_id
+"#;
 
-    let root_user = user::Entity::find_by_id(root_message.user_id)
-        .one(db)
+async fn fetch_message_thread<C>(
+    db: &C,
+    message_id: i32,
+    max_depth: Option<u32>,
+) -> Result<Vec<ThreadEntry>, AppError>
+where
+    C: ConnectionTrait,
+{
+    let max_depth = max_depth.map_or(i32::MAX, |depth| depth.min(i32::MAX as u32) as i32);
+    let rows = db
+        .query_all(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            THREAD_QUERY,
+            [message_id.into(), max_depth.into()],
+        ))
         .await?;
 
-    let mut thread = vec![(root_message, root_user)];
-    fetch_replies(db, message_id, &mut thread).await?;
+    let mut thread = rows
+        .iter()
+        .map(|row| {
+            let path: String = row.try_get("", "path")?;
+            Ok(ThreadEntry {
+                message: message::Model::from_query_result(row, "")?,
+                user: user::Model::from_query_result_optional(row, "u_")?,
+                depth: row.try_get("", "depth")?,
+                path: path
+                    .split('/')
+                    .map(|id| {
+                        id.parse()
+                            .map_err(|_| DbErr::Custom("Bad thread path".into()))
+                    })
+                    .collect::<Result<_, DbErr>>()?,
+            })
+        })
+        .collect::<Result<Vec<_>, DbErr>>()?;
+    if thread.is_empty() {
+        return Err(AppError::NotFound("Root message not found".to_string()));
+    }
+    thread.sort_by(|a, b| a.path.cmp(&b.path));
     Ok(thread)
 }
 
+#[async_trait]
+impl TokenRepository for SeaOrmRepository {
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: &[Scope],
+        expires_at: DateTime<Utc>,
+    ) -> Result<(String, personal_access_token::Model), AppError> {
+        validate_token_scopes(scopes)?;
+
+        let raw_token = generate_token();
+        let token = personal_access_token::ActiveModel {
+            user_id: Set(user_id),
+            name: Set(name.to_owned()),
+            token_hash: Set(hash_token(&raw_token)),
+            scopes: Set(format_scopes(scopes)),
+            expires_at: Set(expires_at),
+            created_at: Set(now()),
+            ..Default::default()
+        };
+        let token = token.insert(&self.db).await?;
+        Ok((raw_token, token))
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<personal_access_token::Model>, AppError> {
+        Ok(personal_access_token::Entity::find()
+            .filter(personal_access_token::Column::UserId.eq(user_id))
+            .all(&self.db)
+            .await?)
+    }
+
+    async fn revoke(&self, user_id: i32, token_id: i32) -> Result<(), AppError> {
+        // Scoping the delete by owner means other users' tokens look "not found".
+        let result = personal_access_token::Entity::delete_many()
+            .filter(personal_access_token::Column::Id.eq(token_id))
+            .filter(personal_access_token::Column::UserId.eq(user_id))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected > 0 {
+            Ok(())
+        } else {
+            Err(AppError::NotFound("Token not found".to_string()))
+        }
+    }
+
+    async fn authenticate(&self, token: &str) -> Result<personal_access_token::Model, AppError> {
+        let found = personal_access_token::Entity::find()
+            .filter(personal_access_token::Column::TokenHash.eq(hash_token(token)))
+            .one(&self.db)
+            .await?;
+        match found {
+            Some(found) if found.expires_at > Utc::now() => {
+                let mut used: personal_access_token::ActiveModel = found.into();
+                used.last_used_at = Set(Some(now()));
+                Ok(used.update(&self.db).await?)
+            }
+            _ => Err(AppError::Unauthenticated(
+                "Invalid or expired token".to_string(),
+            )),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dotenvy::dotenv;
-    use migration::{Migrator, MigratorTrait};
-    use sea_orm::{Database, TransactionTrait};
+    use crate::db::repository::{Repositories, MAX_PAGE_SIZE};
+    use crate::db::testing::{self, TestDb};
+    use sea_orm::TransactionTrait;
     use std::env;
     use tokio::time;
 
-    async fn setup() -> DatabaseConnection {
-        dotenv().ok();
-        let db_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
-        let db = Database::connect(&db_url)
+    // The database has to be kept around for as long as the repositories are
+    // used, since dropping it drops the test's schema.
+    async fn setup() -> (TestDb, Repositories) {
+        let db = testing::connect("TEST_DATABASE_URL").await;
+        let repos = Repositories::sea_orm(db.clone());
+        (db, repos)
+    }
+
+    // Every migration can be rolled back and applied again on SQLite, not
+    // just on Postgres.
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_migrations_round_trip() {
+        use migration::{Migrator, MigratorTrait};
+
+        let db = testing::connect("TEST_DATABASE_URL").await;
+        Migrator::down(&*db, None)
             .await
-            .expect("Failed to connect to test database");
-        user::Entity::delete_many()
-            .filter(user::Column::Id.gt(0))
-            .exec(&db)
+            .expect("Failed to roll back migrations");
+        Migrator::up(&*db, None)
+            .await
+            .expect("Failed to reapply migrations");
+        let repos = Repositories::sea_orm(db.clone());
+        let user = repos.users.create("Migrated Mia").await.unwrap();
+        assert_eq!(user.role, Role::Member);
+    }
+
+    // SQLite rebuilds the message table to change its author key, which must
+    // not take the rows referring to messages along with the old table.
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_migrations_rebuild_messages_in_place() {
+        use migration::{Migrator, MigratorTrait};
+
+        let (db, repos) = setup().await;
+        let enforced = db
+            .query_one(Statement::from_string(
+                db.get_database_backend(),
+                "PRAGMA foreign_keys",
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(enforced.try_get_by_index::<i32>(0).unwrap(), 1);
+        let author = repos.users.create("Rae").await.unwrap();
+        let replier = repos.users.create("Cy").await.unwrap();
+        let root = repos
+            .messages
+            .create(author.id, "Before", None, None)
+            .await
+            .unwrap();
+        repos
+            .messages
+            .update(member(&author), root.id, "After")
+            .await
+            .unwrap();
+        let reply = repos
+            .messages
+            .create(replier.id, "Hi @Rae", Some(root.id), None)
+            .await
+            .unwrap();
+        repos
+            .reactions
+            .add(replier.id, root.id, "👍")
             .await
             .unwrap();
-        Migrator::up(&db, None)
+
+        let later = Migrator::migrations()
+            .iter()
+            .rev()
+            .position(|m| m.name() == "m20240426_000010_keep_messages_of_deleted_users")
+            .unwrap() as u32
+            + 1;
+        Migrator::down(&*db, Some(later)).await.unwrap();
+        Migrator::up(&*db, None).await.unwrap();
+
+        let revisions = repos.messages.revisions(&[root.id]).await.unwrap();
+        assert_eq!(revisions.len(), 1);
+        let counts = repos.reactions.counts(&[root.id], None).await.unwrap();
+        assert_eq!(counts.len(), 1);
+        let mentions = message_mention::Entity::find()
+            .filter(message_mention::Column::MessageId.eq(reply.id))
+            .count(&*db)
             .await
-            .expect("Failed to apply migrations");
-        db
+            .unwrap();
+        assert_eq!(mentions, 1);
+
+        // And the author key does what it was rebuilt for.
+        repos
+            .users
+            .delete(member(&author), author.id)
+            .await
+            .unwrap();
+        let root = repos.messages.get(root.id).await.unwrap().unwrap();
+        assert_eq!(root.user_id, None);
+        assert!(repos.messages.get(reply.id).await.unwrap().is_some());
+    }
+
+    // Rolling back would have to delete the tombstones of deleted users, and
+    // with them every reply to those, so it refuses instead. The migrator
+    // leaves its failed transaction to be rolled back in the background, which
+    // needs a worker free while the test's schema is being dropped.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrations_keep_authorless_messages() {
+        use migration::{Migrator, MigratorTrait};
+
+        let (db, repos) = setup().await;
+        let author = repos.users.create("Leaving Lou").await.unwrap();
+        let replier = repos.users.create("Staying Sam").await.unwrap();
+        let root = repos
+            .messages
+            .create(author.id, "Bye", None, None)
+            .await
+            .unwrap();
+        let reply = repos
+            .messages
+            .create(replier.id, "See you", Some(root.id), None)
+            .await
+            .unwrap();
+        repos
+            .users
+            .delete(member(&author), author.id)
+            .await
+            .unwrap();
+
+        let err = Migrator::down(&*db, None).await.unwrap_err();
+        assert!(matches!(err, DbErr::Migration(_)), "{err}");
+        let kept = message::Entity::find_by_id(reply.id)
+            .one(&*db)
+            .await
+            .unwrap();
+        assert!(kept.is_some(), "Reply was deleted");
+    }
+
+    fn member(user: &user::Model) -> Actor {
+        Actor {
+            id: user.id,
+            role: user.role,
+        }
     }
 
     #[tokio::test]
     async fn test_create_user() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let name = "Alice";
-        let new_user_result = create_user(&db, name).await;
+        let new_user_result = repos.users.create(name).await;
         assert!(new_user_result.is_ok(), "Failed to create user");
 
         let user = user::Entity::find()
             .filter(user::Column::Name.eq(name))
-            .one(&db)
+            .one(&*db)
             .await
             .expect("Failed to find user");
         assert!(user.is_some(), "User not found");
@@ -299,26 +1119,24 @@ mod tests {
 
     #[tokio::test]
     async fn test_update_user() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let name = "Bob";
         let new_name = "Carl";
-        create_user(&db, name).await.expect("Failed to create user");
-
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(name))
-            .one(&db)
+        let user = repos
+            .users
+            .create(name)
             .await
-            .expect("Failed to find user")
-            .expect("User not found");
+            .expect("Failed to create user");
 
-        let user_id = user.id;
-        update_user(&db, user_id, new_name)
+        repos
+            .users
+            .update(member(&user), user.id, new_name)
             .await
             .expect("Failed to update user");
 
-        let updated_user = user::Entity::find_by_id(user_id)
-            .one(&db)
+        let updated_user = user::Entity::find_by_id(user.id)
+            .one(&*db)
             .await
             .expect("Failed to find user")
             .expect("User not found");
@@ -332,25 +1150,23 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_user() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let name = "Dave";
-        create_user(&db, name).await.expect("Failed to create user");
-
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(name))
-            .one(&db)
+        let user = repos
+            .users
+            .create(name)
             .await
-            .expect("Failed to find user")
-            .expect("User not found");
+            .expect("Failed to create user");
 
-        let user_id = user.id;
-        delete_user(&db, user_id)
+        repos
+            .users
+            .delete(member(&user), user.id)
             .await
             .expect("Failed to delete user");
 
-        let deleted_user = user::Entity::find_by_id(user_id)
-            .one(&db)
+        let deleted_user = user::Entity::find_by_id(user.id)
+            .one(&*db)
             .await
             .expect("Failed to find user");
 
@@ -361,22 +1177,71 @@ mod tests {
             .expect("Failed to rollback transaction");
     }
 
+    #[tokio::test]
+    async fn test_deleted_users_messages_stay_as_tombstones() {
+        let (_db, sea_orm) = setup().await;
+        for repos in [sea_orm, Repositories::in_memory()] {
+            let dave = repos.users.create("Dave").await.unwrap();
+            let gina = repos.users.create("Gina").await.unwrap();
+            let root = repos
+                .messages
+                .create(dave.id, "Root", None, None)
+                .await
+                .unwrap();
+            let reply = repos
+                .messages
+                .create(gina.id, "Reply", Some(root.id), None)
+                .await
+                .unwrap();
+            let nested = repos
+                .messages
+                .create(dave.id, "Nested", Some(reply.id), None)
+                .await
+                .unwrap();
+
+            let mut receiver = repos.events.subscribe();
+            repos
+                .users
+                .delete(member(&dave), dave.id)
+                .await
+                .expect("Failed to delete user");
+
+            let mut deleted = Vec::new();
+            while let Ok(event) = receiver.try_recv() {
+                match event {
+                    MessageEvent::Deleted(message) => deleted.push((message.id, message.user_id)),
+                    other => panic!("Expected a deleted event, got {:?}", other),
+                }
+            }
+            deleted.sort();
+            assert_eq!(
+                deleted,
+                vec![(root.id, Some(dave.id)), (nested.id, Some(dave.id))]
+            );
+
+            let reply = repos.messages.get(reply.id).await.unwrap().unwrap();
+            assert_eq!(reply.user_id, Some(gina.id));
+            assert_eq!(reply.deleted_at, None);
+            for id in [root.id, nested.id] {
+                let tombstone = repos.messages.get(id).await.unwrap().unwrap();
+                assert_eq!(tombstone.user_id, None);
+                assert!(tombstone.deleted_at.is_some());
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_get_user() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let name = "Igor";
-        create_user(&db, name).await.expect("Failed to create user");
-
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(name))
-            .one(&db)
+        let user = repos
+            .users
+            .create(name)
             .await
-            .expect("Failed to find user")
-            .expect("User not found");
+            .expect("Failed to create user");
 
-        let user_id = user.id;
-        let found_user = get_user(&db, user_id).await.expect("Failed to get user");
+        let found_user = repos.users.get(user.id).await.expect("Failed to get user");
         assert!(found_user.is_some(), "User not found");
         assert_eq!(found_user.unwrap().name, name);
         transaction
@@ -385,31 +1250,277 @@ mod tests {
             .expect("Failed to rollback transaction");
     }
 
+    #[tokio::test]
+    async fn test_register_and_login_user() {
+        let (_db, repos) = setup().await;
+        let name = "Erin";
+        let password = "correct horse battery";
+        let registered = repos
+            .users
+            .register(name, password)
+            .await
+            .expect("Failed to register user");
+        assert_eq!(registered.name, name);
+        assert_ne!(registered.password_hash.as_deref(), Some(password));
+
+        let user = repos
+            .users
+            .login(name, password)
+            .await
+            .expect("Login failed");
+        assert_eq!(user.id, registered.id);
+        assert!(matches!(
+            repos.users.login(name, "wrong password").await,
+            Err(AppError::Unauthenticated(_))
+        ));
+        assert!(matches!(
+            repos.users.register(name, password).await,
+            Err(AppError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_user_with_role() {
+        let (_db, sea_orm) = setup().await;
+        for repos in [sea_orm, Repositories::in_memory()] {
+            let admin = repos
+                .users
+                .create_with_role(
+                    Actor::OPERATOR,
+                    "Ada",
+                    Some("correct horse battery"),
+                    Role::Admin,
+                )
+                .await
+                .expect("Failed to create admin");
+            assert_eq!(admin.role, Role::Admin);
+            let user = repos
+                .users
+                .login("Ada", "correct horse battery")
+                .await
+                .expect("Login failed");
+            assert_eq!(user.id, admin.id);
+
+            let mo = repos
+                .users
+                .create_with_role(Actor::OPERATOR, "Mo", None, Role::Member)
+                .await
+                .unwrap();
+            assert_eq!(mo.role, Role::Member);
+            assert!(mo.password_hash.is_none());
+            assert!(matches!(
+                repos
+                    .users
+                    .create_with_role(member(&mo), "Eve", None, Role::Moderator)
+                    .await,
+                Err(AppError::Forbidden(_))
+            ));
+            assert!(matches!(
+                repos
+                    .users
+                    .create_with_role(Actor::OPERATOR, "Ada", None, Role::Moderator)
+                    .await,
+                Err(AppError::Conflict(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_personal_access_token_lifecycle() {
+        let (_db, repos) = setup().await;
+        let owner = repos
+            .users
+            .create("Bot Owner")
+            .await
+            .expect("Failed to create user");
+
+        let expires_at = Utc::now() + chrono::Duration::days(1);
+        let (raw_token, token) = repos
+            .tokens
+            .create(owner.id, "ci", &[Scope::MessagesWrite], expires_at)
+            .await
+            .expect("Failed to create token");
+        assert_ne!(token.token_hash, raw_token);
+        assert_eq!(token.scopes, "messages:write");
+        assert!(token.last_used_at.is_none());
+
+        let used = repos
+            .tokens
+            .authenticate(&raw_token)
+            .await
+            .expect("Failed to authenticate token");
+        assert_eq!(used.user_id, owner.id);
+        assert!(used.last_used_at.is_some());
+
+        assert!(matches!(
+            repos.tokens.revoke(owner.id + 1, token.id).await,
+            Err(AppError::NotFound(_))
+        ));
+        repos
+            .tokens
+            .revoke(owner.id, token.id)
+            .await
+            .expect("Failed to revoke token");
+        assert!(matches!(
+            repos.tokens.authenticate(&raw_token).await,
+            Err(AppError::Unauthenticated(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_rejected() {
+        let (_db, repos) = setup().await;
+        let owner = repos
+            .users
+            .create("Old Bot")
+            .await
+            .expect("Failed to create user");
+
+        let expires_at = Utc::now() - chrono::Duration::minutes(1);
+        let (raw_token, _) = repos
+            .tokens
+            .create(owner.id, "stale", &[Scope::MessagesRead], expires_at)
+            .await
+            .expect("Failed to create token");
+        assert!(matches!(
+            repos.tokens.authenticate(&raw_token).await,
+            Err(AppError::Unauthenticated(_))
+        ));
+    }
+
+    // Other tests publish on the same channel, so skip events for other users.
+    async fn next_event_for(
+        receiver: &mut tokio::sync::broadcast::Receiver<MessageEvent>,
+        user_id: i32,
+    ) -> MessageEvent {
+        loop {
+            let event = time::timeout(time::Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("Timed out waiting for an event")
+                .expect("Event channel closed");
+            if event.user_id() == Some(user_id) {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_writes_publish_events() {
+        let (_db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Frank")
+            .await
+            .expect("Failed to create user");
+        let mut receiver = repos.events.subscribe();
+        repos
+            .messages
+            .create(user.id, "First", None, None)
+            .await
+            .expect("Failed to create message");
+        let created = match next_event_for(&mut receiver, user.id).await {
+            MessageEvent::Created(message) => message,
+            other => panic!("Expected a created event, got {:?}", other),
+        };
+        assert_eq!(created.content, "First");
+
+        repos
+            .messages
+            .update(member(&user), created.id, "Edited")
+            .await
+            .expect("Failed to update message");
+        match next_event_for(&mut receiver, user.id).await {
+            MessageEvent::Updated(message) => assert_eq!(message.content, "Edited"),
+            other => panic!("Expected an updated event, got {:?}", other),
+        }
+
+        repos
+            .messages
+            .delete(member(&user), created.id)
+            .await
+            .expect("Failed to delete message");
+        match next_event_for(&mut receiver, user.id).await {
+            MessageEvent::Deleted(message) => assert_eq!(message.id, created.id),
+            other => panic!("Expected a deleted event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_announces_removed_replies() {
+        let (_db, sea_orm) = setup().await;
+        for repos in [sea_orm, Repositories::in_memory()] {
+            let frank = repos.users.create("Frank").await.unwrap();
+            let gina = repos.users.create("Gina").await.unwrap();
+            let root = repos
+                .messages
+                .create(frank.id, "Root", None, None)
+                .await
+                .unwrap();
+            let reply = repos
+                .messages
+                .create(gina.id, "Reply", Some(root.id), None)
+                .await
+                .unwrap();
+            let nested = repos
+                .messages
+                .create(frank.id, "Nested", Some(reply.id), None)
+                .await
+                .unwrap();
+            let tombstone = repos
+                .messages
+                .create(gina.id, "Gone", Some(root.id), None)
+                .await
+                .unwrap();
+            repos
+                .messages
+                .delete(member(&gina), tombstone.id)
+                .await
+                .unwrap();
+
+            let mut receiver = repos.events.subscribe();
+            let purged = repos
+                .messages
+                .purge(Actor::OPERATOR, root.id)
+                .await
+                .expect("Failed to purge message");
+            assert_eq!(purged.id, root.id);
+
+            let mut deleted = Vec::new();
+            while let Ok(event) = receiver.try_recv() {
+                match event {
+                    MessageEvent::Deleted(message) => deleted.push(message.id),
+                    other => panic!("Expected a deleted event, got {:?}", other),
+                }
+            }
+            deleted.sort();
+            assert_eq!(deleted, vec![root.id, reply.id, nested.id]);
+            for id in [root.id, reply.id, nested.id, tombstone.id] {
+                assert_eq!(repos.messages.get(id).await.unwrap(), None);
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_create_message() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let user_name = "Alex";
         let message_content = "Hello, world!";
-        create_user(&db, user_name)
+        let user = repos
+            .users
+            .create(user_name)
             .await
             .expect("Failed to create user");
 
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(user_name))
-            .one(&db)
-            .await
-            .expect("Failed to find user")
-            .expect("User not found");
-
-        let user_id = user.id;
-        create_message(&db, user_id, message_content, None)
+        repos
+            .messages
+            .create(user.id, message_content, None, None)
             .await
             .expect("Failed to create message");
 
         let message = message::Entity::find()
-            .filter(message::Column::UserId.eq(user_id))
-            .one(&db)
+            .filter(message::Column::UserId.eq(user.id))
+            .one(&*db)
             .await
             .expect("Failed to find message")
             .expect("Message not found");
@@ -423,41 +1534,31 @@ mod tests {
 
     #[tokio::test]
     async fn test_update_message() {
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let user_name = "Bob Dylan";
         let message_content = "Hello, world!";
-        create_user(&db, user_name)
+        let user = repos
+            .users
+            .create(user_name)
             .await
             .expect("Failed to create user");
 
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(user_name))
-            .one(&db)
-            .await
-            .expect("Failed to find user")
-            .expect("User not found");
-
-        let user_id = user.id;
-        create_message(&db, user_id, message_content, None)
+        let message = repos
+            .messages
+            .create(user.id, message_content, None, None)
             .await
             .expect("Failed to create message");
 
-        let message = message::Entity::find()
-            .filter(message::Column::UserId.eq(user_id))
-            .one(&db)
-            .await
-            .expect("Failed to find message")
-            .expect("Message not found");
-
-        let message_id = message.id;
         let new_content = "Goodbye, world!";
-        update_message(&db, message_id, new_content)
+        repos
+            .messages
+            .update(member(&user), message.id, new_content)
             .await
             .expect("Failed to update message");
 
-        let updated_message = message::Entity::find_by_id(message_id)
-            .one(&db)
+        let updated_message = message::Entity::find_by_id(message.id)
+            .one(&*db)
             .await
             .expect("Failed to find message")
             .expect("Message not found");
@@ -470,49 +1571,742 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_delete_message() {
-        let db = setup().await;
-        let transaction = db.begin().await.expect("Failed to start transaction");
-        let user_name = "Charlie";
-        let message_content = "Hello, world!";
-        create_user(&db, user_name)
+    async fn test_update_saves_revisions() {
+        let (_db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Grace")
             .await
             .expect("Failed to create user");
+        let other = repos
+            .messages
+            .create(user.id, "Untouched", None, None)
+            .await
+            .expect("Failed to create message");
+        let message = repos
+            .messages
+            .create(user.id, "First", None, None)
+            .await
+            .expect("Failed to create message");
+        for content in ["Second", "Third"] {
+            repos
+                .messages
+                .update(member(&user), message.id, content)
+                .await
+                .expect("Failed to update message");
+        }
 
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(user_name))
-            .one(&db)
+        let revisions = repos
+            .messages
+            .revisions(&[message.id, other.id])
             .await
-            .expect("Failed to find user")
-            .expect("User not found");
+            .unwrap();
+        let saved: Vec<(i32, i32, &str)> = revisions
+            .iter()
+            .map(|r| (r.message_id, r.revision, r.content.as_str()))
+            .collect();
+        assert_eq!(
+            saved,
+            vec![(message.id, 0, "First"), (message.id, 1, "Second")]
+        );
+        assert_eq!(revisions[0].created_at, message.created_at);
+
+        // A refused edit saves nothing.
+        let stranger = repos.users.create("Heidi").await.unwrap();
+        assert!(repos
+            .messages
+            .update(member(&stranger), message.id, "Mine now")
+            .await
+            .is_err());
+        assert_eq!(
+            repos.messages.revisions(&[message.id]).await.unwrap().len(),
+            2
+        );
+    }
 
-        let user_id = user.id;
-        create_message(&db, user_id, message_content, None)
+    #[tokio::test]
+    async fn test_concurrent_updates_save_what_they_replace() {
+        let (_db, repos) = setup().await;
+        let user = repos.users.create("Ivan").await.unwrap();
+        let message = repos
+            .messages
+            .create(user.id, "First", None, None)
+            .await
+            .unwrap();
+        let (a, b) = tokio::join!(
+            repos.messages.update(member(&user), message.id, "Edit A"),
+            repos.messages.update(member(&user), message.id, "Edit B"),
+        );
+        let (a, b) = (a.expect("Edit A failed"), b.expect("Edit B failed"));
+
+        // Whichever edit went second replaced the first one's content.
+        let (first, last) = if a.updated_at <= b.updated_at {
+            ("Edit A", "Edit B")
+        } else {
+            ("Edit B", "Edit A")
+        };
+        let revisions = repos.messages.revisions(&[message.id]).await.unwrap();
+        let saved: Vec<(i32, &str)> = revisions
+            .iter()
+            .map(|r| (r.revision, r.content.as_str()))
+            .collect();
+        assert_eq!(saved, vec![(0, "First"), (1, first)]);
+        let current = repos.messages.get(message.id).await.unwrap().unwrap();
+        assert_eq!(current.content, last);
+    }
+
+    #[tokio::test]
+    async fn test_only_author_can_modify_message() {
+        let (_db, repos) = setup().await;
+        let mallory = repos
+            .users
+            .create("Mallory")
+            .await
+            .expect("Failed to create user");
+        let trent = repos
+            .users
+            .create("Trent")
+            .await
+            .expect("Failed to create user");
+
+        let message = repos
+            .messages
+            .create(trent.id, "Trent's message", None, None)
             .await
             .expect("Failed to create message");
 
-        let message = message::Entity::find()
-            .filter(message::Column::UserId.eq(user_id))
-            .one(&db)
+        let mallory = member(&mallory);
+        let trent = member(&trent);
+
+        let result = repos.messages.update(mallory, message.id, "Hijacked").await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+
+        let result = repos.messages.delete(mallory, message.id).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+
+        let unchanged = repos
+            .messages
+            .get(message.id)
             .await
             .expect("Failed to find message")
-            .expect("Message not found");
+            .expect("Message was deleted");
+        assert_eq!(unchanged.content, "Trent's message");
 
-        let message_id = message.id;
-        delete_message(&db, message_id)
+        let deleted = repos
+            .messages
+            .delete(trent, message.id)
             .await
             .expect("Failed to delete message");
+        assert_eq!(deleted.id, message.id);
+    }
+
+    #[tokio::test]
+    async fn test_moderator_and_admin_overrides() {
+        let (_db, repos) = setup().await;
+        let peggy = repos
+            .users
+            .create("Peggy")
+            .await
+            .expect("Failed to create user");
+        let victor = repos
+            .users
+            .create("Victor")
+            .await
+            .expect("Failed to create user");
+        assert_eq!(peggy.role, Role::Member);
 
-        let deleted_message = message::Entity::find_by_id(message_id)
-            .one(&db)
+        let message = repos
+            .messages
+            .create(peggy.id, "Spam", None, None)
             .await
-            .expect("Failed to find message");
+            .expect("Failed to create message");
 
-        assert!(deleted_message.is_none(), "Message not deleted");
-        transaction
-            .rollback()
+        let member = Actor {
+            id: victor.id,
+            role: Role::Member,
+        };
+        let moderator = Actor {
+            id: victor.id,
+            role: Role::Moderator,
+        };
+        let admin = Actor {
+            id: victor.id,
+            role: Role::Admin,
+        };
+
+        // Members cannot hide, moderators can but still cannot rewrite
+        let result = repos.messages.hide(member, message.id, true).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+        let hidden = repos
+            .messages
+            .hide(moderator, message.id, true)
             .await
-            .expect("Failed to rollback transaction");
+            .expect("Failed to hide message");
+        assert!(hidden.hidden_at.is_some());
+        let result = repos
+            .messages
+            .update(moderator, message.id, "Rewritten")
+            .await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+        repos
+            .messages
+            .delete(moderator, message.id)
+            .await
+            .expect("Failed to delete message");
+
+        // Only admins manage other users
+        let result = repos.users.update(moderator, peggy.id, "Renamed").await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+        let renamed = repos
+            .users
+            .update(admin, peggy.id, "Renamed")
+            .await
+            .expect("Failed to update user");
+        assert_eq!(renamed.name, "Renamed");
+        let result = repos.users.set_role(moderator, peggy.id, Role::Admin).await;
+        assert!(matches!(result, Err(AppError::Forbidden(_))));
+        repos
+            .users
+            .delete(admin, peggy.id)
+            .await
+            .expect("Failed to delete user");
+    }
+
+    #[tokio::test]
+    async fn test_message_is_deleted_once() {
+        let (_db, sea_orm) = setup().await;
+        for repos in [sea_orm, Repositories::in_memory()] {
+            let user = repos.users.create("Kim").await.unwrap();
+            let message = repos
+                .messages
+                .create(user.id, "Twice?", None, None)
+                .await
+                .unwrap();
+            let mut receiver = repos.events.subscribe();
+            let (a, b) = tokio::join!(
+                repos.messages.delete(member(&user), message.id),
+                repos.messages.delete(member(&user), message.id),
+            );
+            let (deleted, refused) = if a.is_ok() { (a, b) } else { (b, a) };
+            let deleted = deleted.expect("Failed to delete message");
+            assert!(matches!(refused, Err(AppError::NotFound(_))));
+            assert!(matches!(
+                repos.messages.delete(member(&user), message.id).await,
+                Err(AppError::NotFound(_))
+            ));
+
+            let stored = repos.messages.get(message.id).await.unwrap().unwrap();
+            assert_eq!(stored.deleted_at, deleted.deleted_at);
+            match receiver.try_recv() {
+                Ok(MessageEvent::Deleted(message)) => assert_eq!(message.id, deleted.id),
+                other => panic!("Expected a deleted event, got {:?}", other),
+            }
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_message() {
+        let (db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Charlie")
+            .await
+            .expect("Failed to create user");
+        let root = repos
+            .messages
+            .create(user.id, "Hello, world!", None, None)
+            .await
+            .expect("Failed to create message");
+        let reply = repos
+            .messages
+            .create(user.id, "Anyone?", Some(root.id), None)
+            .await
+            .expect("Failed to create reply");
+
+        let deleted = repos
+            .messages
+            .delete(member(&user), root.id)
+            .await
+            .expect("Failed to delete message");
+        assert!(deleted.deleted_at.is_some());
+
+        // The tombstone keeps the reply in the thread.
+        let thread = repos.messages.thread(root.id, None).await.unwrap();
+        let ids: Vec<i32> = thread.iter().map(|entry| entry.message.id).collect();
+        assert_eq!(ids, vec![root.id, reply.id]);
+        assert!(thread[0].message.deleted_at.is_some());
+
+        // Neither listed under its author nor open to further changes.
+        let page = repos
+            .messages
+            .list_for_user(user.id, PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert!(matches!(
+            repos.messages.delete(member(&user), root.id).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            repos.messages.update(member(&user), root.id, "Back").await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            repos
+                .messages
+                .create(user.id, "Late", Some(root.id), None)
+                .await,
+            Err(AppError::Validation(_))
+        ));
+
+        // Purging is for admins, and takes the replies along.
+        assert!(matches!(
+            repos.messages.purge(member(&user), root.id).await,
+            Err(AppError::Forbidden(_))
+        ));
+        let admin = Actor {
+            id: user.id,
+            role: Role::Admin,
+        };
+        repos
+            .messages
+            .purge(admin, root.id)
+            .await
+            .expect("Failed to purge message");
+        for id in [root.id, reply.id] {
+            let found = message::Entity::find_by_id(id)
+                .one(&*db)
+                .await
+                .expect("Failed to find message");
+            assert!(found.is_none(), "Message not purged");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_reactions() {
+        let (_db, repos) = setup().await;
+        let ivan = repos.users.create("Ivan").await.unwrap();
+        let judy = repos.users.create("Judy").await.unwrap();
+        let message = repos
+            .messages
+            .create(ivan.id, "Ship it?", None, None)
+            .await
+            .unwrap();
+        for (user, emoji) in [(&ivan, "👍"), (&judy, "👍"), (&judy, "🎉")] {
+            repos
+                .reactions
+                .add(user.id, message.id, emoji)
+                .await
+                .expect("Failed to add reaction");
+        }
+        assert!(matches!(
+            repos.reactions.add(judy.id, message.id, "👍").await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            repos.reactions.add(judy.id, message.id, "thumbs up").await,
+            Err(AppError::Validation(_))
+        ));
+
+        let mut counts = repos
+            .reactions
+            .counts(&[message.id], Some(ivan.id))
+            .await
+            .unwrap();
+        counts.sort_by(|a, b| a.emoji.cmp(&b.emoji));
+        let summary: Vec<(&str, i64, bool)> = counts
+            .iter()
+            .map(|c| (c.emoji.as_str(), c.count, c.reacted))
+            .collect();
+        assert_eq!(summary, vec![("🎉", 1, false), ("👍", 2, true)]);
+
+        repos
+            .reactions
+            .remove(ivan.id, message.id, "👍")
+            .await
+            .expect("Failed to remove reaction");
+        assert!(matches!(
+            repos.reactions.remove(ivan.id, message.id, "👍").await,
+            Err(AppError::NotFound(_))
+        ));
+
+        // Reactions go when their user does, and when the message is deleted.
+        let admin = Actor {
+            id: ivan.id,
+            role: Role::Admin,
+        };
+        repos.users.delete(admin, judy.id).await.unwrap();
+        assert!(repos
+            .reactions
+            .counts(&[message.id], None)
+            .await
+            .unwrap()
+            .is_empty());
+        repos
+            .reactions
+            .add(ivan.id, message.id, "🚀")
+            .await
+            .unwrap();
+        repos
+            .messages
+            .delete(member(&ivan), message.id)
+            .await
+            .unwrap();
+        assert!(repos
+            .reactions
+            .counts(&[message.id], None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            repos.reactions.add(ivan.id, message.id, "🚀").await,
+            Err(AppError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_mentions() {
+        let (db, repos) = setup().await;
+        let ivan = repos.users.create("Ivan").await.unwrap();
+        let judy = repos.users.create("Judy").await.unwrap();
+        let mallory = repos.users.create("mallory").await.unwrap();
+        let names = |mentions: Vec<(i32, user::Model)>| -> Vec<String> {
+            mentions.into_iter().map(|(_, user)| user.name).collect()
+        };
+
+        let message = repos
+            .messages
+            .create(
+                ivan.id,
+                "@Judy @nobody, meet @mallory and @Judy.",
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let mentions = repos.messages.mentions(&[message.id]).await.unwrap();
+        assert_eq!(names(mentions.clone()), vec!["Judy", "mallory"]);
+        let judy_mentioned_at = message_mention::Entity::find()
+            .filter(message_mention::Column::UserId.eq(judy.id))
+            .one(&*db)
+            .await
+            .unwrap()
+            .unwrap()
+            .created_at;
+
+        // Edits drop and add mentions but keep those that still apply
+        repos
+            .messages
+            .update(member(&ivan), message.id, "Sorry @Judy, I meant @Ivan")
+            .await
+            .unwrap();
+        let mentions = repos.messages.mentions(&[message.id]).await.unwrap();
+        assert_eq!(names(mentions), vec!["Ivan", "Judy"]);
+        let judy_mention = message_mention::Entity::find()
+            .filter(message_mention::Column::UserId.eq(judy.id))
+            .one(&*db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(judy_mention.created_at, judy_mentioned_at);
+
+        let other = repos
+            .messages
+            .create(mallory.id, "Thanks @Judy", None, None)
+            .await
+            .unwrap();
+        let page = repos
+            .messages
+            .list_mentioning(judy.id, PageRequest::default())
+            .await
+            .unwrap();
+        let ids: Vec<i32> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![message.id, other.id]);
+        let page = repos
+            .messages
+            .list_mentioning(mallory.id, PageRequest::default())
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+
+        repos
+            .messages
+            .delete(member(&mallory), other.id)
+            .await
+            .unwrap();
+        let admin = Actor {
+            id: 0,
+            role: Role::Admin,
+        };
+        repos.users.delete(admin, ivan.id).await.unwrap();
+        let page = repos
+            .messages
+            .list_mentioning(judy.id, PageRequest::default())
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let (db, repos) = setup().await;
+        let ivan = repos.users.create("Ivan").await.unwrap();
+        let judy = repos.users.create("Judy").await.unwrap();
+        let general = repos
+            .channels
+            .create(ivan.id, " general ", Some("  "), Visibility::Public)
+            .await
+            .expect("Failed to create channel");
+        assert_eq!(general.name, "general");
+        assert_eq!(general.topic, None);
+        let secret = repos
+            .channels
+            .create(judy.id, "secret", Some("Plans"), Visibility::Unlisted)
+            .await
+            .unwrap();
+        assert!(matches!(
+            repos
+                .channels
+                .create(judy.id, "general", None, Visibility::Public)
+                .await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            repos
+                .channels
+                .create(judy.id, " ", None, Visibility::Public)
+                .await,
+            Err(AppError::Validation(_))
+        ));
+        let public = repos.channels.list_public().await.unwrap();
+        assert_eq!(public, vec![general.clone()]);
+
+        // Replies follow their parent into its channel
+        let root = repos
+            .messages
+            .create(ivan.id, "Welcome", None, Some(general.id))
+            .await
+            .unwrap();
+        let reply = repos
+            .messages
+            .create(judy.id, "Thanks", Some(root.id), None)
+            .await
+            .unwrap();
+        assert_eq!(reply.channel_id, Some(general.id));
+        assert!(matches!(
+            repos
+                .messages
+                .create(judy.id, "Elsewhere", Some(root.id), Some(secret.id))
+                .await,
+            Err(AppError::Validation(_))
+        ));
+        assert!(matches!(
+            repos
+                .messages
+                .create(judy.id, "Nowhere", None, Some(secret.id + 100))
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+        repos
+            .messages
+            .create(judy.id, "Psst", None, Some(secret.id))
+            .await
+            .unwrap();
+        repos
+            .messages
+            .create(judy.id, "Outside", None, None)
+            .await
+            .unwrap();
+        let page = repos
+            .messages
+            .list_in_channel(general.id, None, None, PageRequest::default())
+            .await
+            .unwrap();
+        let ids: Vec<i32> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![root.id, reply.id]);
+        let page = repos
+            .messages
+            .list_in_channel(general.id, Some(now()), None, PageRequest::default())
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+
+        let changes = ChannelChanges {
+            topic: Some("Say hi".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            repos
+                .channels
+                .update(member(&judy), general.id, changes.clone())
+                .await,
+            Err(AppError::Forbidden(_))
+        ));
+        let updated = repos
+            .channels
+            .update(member(&ivan), general.id, changes)
+            .await
+            .expect("Failed to update channel");
+        assert_eq!(updated.topic.as_deref(), Some("Say hi"));
+        assert_eq!(updated.name, "general");
+        let rename = ChannelChanges {
+            name: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            repos
+                .channels
+                .update(member(&ivan), general.id, rename)
+                .await,
+            Err(AppError::Conflict(_))
+        ));
+
+        // The creator cannot wipe the messages others posted to the channel
+        assert!(matches!(
+            repos.channels.delete(member(&ivan), general.id).await,
+            Err(AppError::Forbidden(_))
+        ));
+        let kept = repos.messages.get(reply.id).await.unwrap().unwrap();
+        assert_eq!(kept.deleted_at, None);
+        assert_eq!(kept.channel_id, Some(general.id));
+
+        // Once the rest is deleted, deleting the channel leaves tombstones
+        repos
+            .messages
+            .delete(member(&judy), reply.id)
+            .await
+            .unwrap();
+        repos
+            .channels
+            .delete(member(&ivan), general.id)
+            .await
+            .expect("Failed to delete channel");
+        for id in [root.id, reply.id] {
+            let found = message::Entity::find_by_id(id)
+                .one(&*db)
+                .await
+                .expect("Failed to find message")
+                .expect("Message removed with its channel");
+            assert!(found.deleted_at.is_some());
+            assert_eq!(found.channel_id, None);
+        }
+        assert!(repos.channels.get(general.id).await.unwrap().is_none());
+
+        // Moderators may delete a channel regardless
+        let moderator = Actor {
+            id: ivan.id,
+            role: Role::Moderator,
+        };
+        repos
+            .channels
+            .delete(moderator, secret.id)
+            .await
+            .expect("Failed to delete channel");
+        let psst = repos
+            .messages
+            .list_for_user(judy.id, PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(psst.messages.len(), 1);
+        assert_eq!(psst.messages[0].content, "Outside");
+
+        // Channels outlive their creator
+        let lounge = repos
+            .channels
+            .create(judy.id, "lounge", None, Visibility::Public)
+            .await
+            .unwrap();
+        repos.users.delete(Actor::OPERATOR, judy.id).await.unwrap();
+        let lounge = repos.channels.get(lounge.id).await.unwrap().unwrap();
+        assert_eq!(lounge.created_by, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_message_thread() {
+        let (_db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Thread Tess")
+            .await
+            .expect("Failed to create user");
+        let post = |parent_id: Option<i32>| {
+            let repos = &repos;
+            async move {
+                repos
+                    .messages
+                    .create(user.id, "Post", parent_id, None)
+                    .await
+                    .expect("Failed to create message")
+                    .id
+            }
+        };
+        // root <- a <- b, root <- c
+        let root = post(None).await;
+        let a = post(Some(root)).await;
+        let c = post(Some(root)).await;
+        let b = post(Some(a)).await;
+
+        let thread = repos
+            .messages
+            .thread(root, None)
+            .await
+            .expect("Failed to fetch thread");
+        let shape: Vec<_> = thread
+            .iter()
+            .map(|entry| (entry.message.id, entry.depth, entry.path.clone()))
+            .collect();
+        assert_eq!(
+            shape,
+            vec![
+                (root, 0, vec![root]),
+                (a, 1, vec![root, a]),
+                (b, 2, vec![root, a, b]),
+                (c, 1, vec![root, c]),
+            ]
+        );
+        assert!(thread
+            .iter()
+            .all(|entry| entry.user.as_ref().map(|u| u.id) == Some(user.id)));
+
+        let shallow = repos
+            .messages
+            .thread(root, Some(1))
+            .await
+            .expect("Failed to fetch thread");
+        assert_eq!(shallow.len(), 3);
+        assert!(repos.messages.thread(b + 1000, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_page_size_is_clamped() {
+        let (_db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Prolific Pat")
+            .await
+            .expect("Failed to create user");
+        for i in 0..=MAX_PAGE_SIZE {
+            repos
+                .messages
+                .create(user.id, &format!("Post {}", i), None, None)
+                .await
+                .expect("Failed to create message");
+        }
+
+        let page = PageRequest {
+            limit: Some(MAX_PAGE_SIZE * 10),
+            ..Default::default()
+        };
+        let page = repos
+            .messages
+            .list_for_user(user.id, page)
+            .await
+            .expect("Failed to get messages");
+        assert_eq!(page.messages.len() as u64, MAX_PAGE_SIZE);
+        assert!(page.has_next_page);
+        assert!(!page.has_previous_page);
+        assert_eq!(page.messages[0].content, "Post 0");
     }
 
     #[tokio::test]
@@ -521,24 +2315,20 @@ mod tests {
             println!("Skipping this test on GitHub Actions.");
             return;
         }
-        let db = setup().await;
+        let (db, repos) = setup().await;
         let transaction = db.begin().await.expect("Failed to start transaction");
         let user_name = "David";
         let message_content = "Hello, world!";
-        create_user(&db, user_name)
+        let user = repos
+            .users
+            .create(user_name)
             .await
             .expect("Failed to create user");
 
-        let user = user::Entity::find()
-            .filter(user::Column::Name.eq(user_name))
-            .one(&db)
-            .await
-            .expect("Failed to find user")
-            .expect("User not found");
-
-        let user_id = user.id;
         for _ in 0..10 {
-            create_message(&db, user_id, message_content, None)
+            repos
+                .messages
+                .create(user.id, message_content, None, None)
                 .await
                 .expect("Failed to create message");
         }
@@ -546,8 +2336,13 @@ mod tests {
 
         let start = chrono::Utc::now() - chrono::Duration::days(1);
         let end = chrono::Utc::now() + chrono::Duration::days(1);
-        let messages = get_messages_in_time_range(&db, user_id, start, end).await;
-        let result = messages.expect("Failed to get messages in time range");
+        let messages = repos
+            .messages
+            .list_in_time_range(user.id, Some(start), Some(end), PageRequest::default())
+            .await;
+        let result = messages
+            .expect("Failed to get messages in time range")
+            .messages;
         match result.len() {
             0 => panic!("No messages found in time range"),
             1 => assert_eq!(result.len(), 1),
@@ -558,4 +2353,44 @@ mod tests {
             .await
             .expect("Failed to rollback transaction");
     }
+
+    #[tokio::test]
+    async fn test_get_messages_in_open_time_range() {
+        let (_db, repos) = setup().await;
+        let user = repos
+            .users
+            .create("Open Olga")
+            .await
+            .expect("Failed to create user");
+        let created_at = repos
+            .messages
+            .create(user.id, "Timeless", None, None)
+            .await
+            .expect("Failed to create message")
+            .created_at;
+
+        let count = |start, end| {
+            let repos = repos.clone();
+            async move {
+                repos
+                    .messages
+                    .list_in_time_range(user.id, start, end, PageRequest::default())
+                    .await
+                    .expect("Failed to get messages in time range")
+                    .messages
+                    .len()
+            }
+        };
+        assert_eq!(count(None, None).await, 1);
+        assert_eq!(count(Some(created_at), None).await, 1);
+        assert_eq!(count(None, Some(created_at)).await, 1);
+        assert_eq!(
+            count(Some(created_at + chrono::Duration::seconds(1)), None).await,
+            0
+        );
+        assert_eq!(
+            count(None, Some(created_at - chrono::Duration::seconds(1))).await,
+            0
+        );
+    }
 }
@@ -0,0 +1,208 @@
+use crate::entity::{
+    channel, message, message_mention, message_reaction, message_revision, personal_access_token,
+    user,
+};
+use crate::error::AppError;
+use sea_orm::{
+    ActiveModelTrait, ConnectionTrait, DatabaseBackend, DatabaseConnection, EntityTrait,
+    IntoActiveModel, PaginatorTrait, QueryOrder, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+
+// Every row of the database, as written by `backend export`. Password and
+// token hashes are included, so a restored database accepts the same logins
+// and tokens.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Dump {
+    pub users: Vec<user::Model>,
+    // Missing from files written before these were kept.
+    #[serde(default)]
+    pub channels: Vec<channel::Model>,
+    pub messages: Vec<message::Model>,
+    #[serde(default)]
+    pub message_revisions: Vec<message_revision::Model>,
+    #[serde(default)]
+    pub message_reactions: Vec<message_reaction::Model>,
+    #[serde(default)]
+    pub message_mentions: Vec<message_mention::Model>,
+    pub personal_access_tokens: Vec<personal_access_token::Model>,
+}
+
+pub async fn export(db: &DatabaseConnection) -> Result<Dump, AppError> {
+    Ok(Dump {
+        users: user::Entity::find()
+            .order_by_asc(user::Column::Id)
+            .all(db)
+            .await?,
+        channels: channel::Entity::find()
+            .order_by_asc(channel::Column::Id)
+            .all(db)
+            .await?,
+        messages: message::Entity::find()
+            .order_by_asc(message::Column::Id)
+            .all(db)
+            .await?,
+        message_revisions: message_revision::Entity::find()
+            .order_by_asc(message_revision::Column::Id)
+            .all(db)
+            .await?,
+        message_reactions: message_reaction::Entity::find()
+            .order_by_asc(message_reaction::Column::Id)
+            .all(db)
+            .await?,
+        message_mentions: message_mention::Entity::find()
+            .order_by_asc(message_mention::Column::Id)
+            .all(db)
+            .await?,
+        personal_access_tokens: personal_access_token::Entity::find()
+            .order_by_asc(personal_access_token::Column::Id)
+            .all(db)
+            .await?,
+    })
+}
+
+// Restores a dump with its ids, so references between rows stay intact. The
+// database has to be empty; either everything is imported or nothing.
+pub async fn import(db: &DatabaseConnection, dump: Dump) -> Result<(), AppError> {
+    if user::Entity::find().count(db).await? > 0 {
+        return Err(AppError::Conflict(
+            "The database already has users; import needs an empty one".to_string(),
+        ));
+    }
+
+    let transaction = db.begin().await?;
+    for user in dump.users {
+        user.into_active_model().insert(&transaction).await?;
+    }
+    for channel in dump.channels {
+        channel.into_active_model().insert(&transaction).await?;
+    }
+    // Replies are newer than what they reply to, so in id order every parent
+    // is inserted before its replies.
+    let mut messages = dump.messages;
+    messages.sort_by_key(|message| message.id);
+    for message in messages {
+        message.into_active_model().insert(&transaction).await?;
+    }
+    for revision in dump.message_revisions {
+        revision.into_active_model().insert(&transaction).await?;
+    }
+    for reaction in dump.message_reactions {
+        reaction.into_active_model().insert(&transaction).await?;
+    }
+    for mention in dump.message_mentions {
+        mention.into_active_model().insert(&transaction).await?;
+    }
+    for token in dump.personal_access_tokens {
+        token.into_active_model().insert(&transaction).await?;
+    }
+
+    // Rows inserted with explicit ids leave Postgres sequences where they
+    // were, which would hand out taken ids next. SQLite keeps up by itself.
+    if transaction.get_database_backend() == DatabaseBackend::Postgres {
+        for table in [
+            "\"user\"",
+            "channel",
+            "message",
+            "message_revision",
+            "message_reaction",
+            "message_mention",
+            "personal_access_token",
+        ] {
+            transaction
+                .execute_unprepared(&format!(
+                    "SELECT setval(pg_get_serial_sequence('{0}', 'id'), \
+                     COALESCE(MAX(id), 0) + 1, false) FROM {0}",
+                    table
+                ))
+                .await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::scope::Scope;
+    use crate::db::repository::{Actor, Repositories};
+    use crate::db::testing;
+    use crate::entity::channel::Visibility;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_export_and_import_round_trip() {
+        let source = testing::connect("TEST_DATABASE_URL").await;
+        let repos = Repositories::sea_orm(source.clone());
+        let alice = repos
+            .users
+            .register("Alice", "correct horse battery")
+            .await
+            .unwrap();
+        let bob = repos.users.create("Bob").await.unwrap();
+        let channel = repos
+            .channels
+            .create(alice.id, "general", None, Visibility::Public)
+            .await
+            .unwrap();
+        let post = repos
+            .messages
+            .create(alice.id, "Hello", None, Some(channel.id))
+            .await
+            .unwrap();
+        repos
+            .messages
+            .create(bob.id, "Hi @Alice", Some(post.id), None)
+            .await
+            .unwrap();
+        repos
+            .messages
+            .update(
+                Actor {
+                    id: alice.id,
+                    role: alice.role,
+                },
+                post.id,
+                "Hello!",
+            )
+            .await
+            .unwrap();
+        repos.reactions.add(bob.id, post.id, "🎉").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::days(1);
+        let (raw_token, _) = repos
+            .tokens
+            .create(alice.id, "ci", &[Scope::MessagesRead], expires_at)
+            .await
+            .unwrap();
+        let dump = export(&source).await.unwrap();
+        assert_eq!(dump.channels.len(), 1);
+        assert_eq!(dump.messages.len(), 2);
+        assert_eq!(dump.message_revisions.len(), 1);
+        assert_eq!(dump.message_reactions.len(), 1);
+        assert_eq!(dump.message_mentions.len(), 1);
+
+        let json = serde_json::to_string(&dump).unwrap();
+        let target = testing::connect("TEST_DATABASE_URL").await;
+        import(&target, serde_json::from_str(&json).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(export(&target).await.unwrap(), dump);
+
+        // Logins, tokens and new rows work as before
+        let restored = Repositories::sea_orm(target.clone());
+        restored
+            .users
+            .login("Alice", "correct horse battery")
+            .await
+            .unwrap();
+        restored.tokens.authenticate(&raw_token).await.unwrap();
+        let carol = restored.users.create("Carol").await.unwrap();
+        assert!(carol.id > bob.id);
+
+        assert!(matches!(
+            import(&target, dump).await,
+            Err(AppError::Conflict(_))
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::entity::message;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sea_orm::{ConnectionTrait, DatabaseBackend, DatabaseConnection, EntityTrait, Statement};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use std::error::Error;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+// Subscribers that fall further behind than this skip the missed events
+// instead of holding up writers.
+const CHANNEL_CAPACITY: usize = 1024;
+
+// Postgres channel shared by every instance of the server.
+pub const NOTIFY_CHANNEL: &str = "message_events";
+
+// A change to a message, published after the write has been committed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageEvent {
+    Created(message::Model),
+    Updated(message::Model),
+    Deleted(DeletedMessage),
+}
+
+// What subscribers are told about a deleted or purged message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedMessage {
+    pub id: i32,
+    // None for messages of deleted users.
+    pub user_id: Option<i32>,
+    pub parent_id: Option<i32>,
+}
+
+impl From<&message::Model> for DeletedMessage {
+    fn from(message: &message::Model) -> Self {
+        Self {
+            id: message.id,
+            user_id: message.user_id,
+            parent_id: message.parent_id,
+        }
+    }
+}
+
+impl MessageEvent {
+    pub fn user_id(&self) -> Option<i32> {
+        match self {
+            MessageEvent::Created(message) | MessageEvent::Updated(message) => message.user_id,
+            MessageEvent::Deleted(message) => message.user_id,
+        }
+    }
+}
+
+// NOTIFY payloads are capped at 8000 bytes, so they only carry ids and the
+// receiving instance reloads the row.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Notification {
+    Created { id: i32 },
+    Updated { id: i32 },
+    Deleted(DeletedMessage),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    // The instance that made the write; it has already delivered the event
+    // to its own subscribers.
+    origin: u64,
+    event: Notification,
+}
+
+impl From<&MessageEvent> for Notification {
+    fn from(event: &MessageEvent) -> Self {
+        match event {
+            MessageEvent::Created(message) => Notification::Created { id: message.id },
+            MessageEvent::Updated(message) => Notification::Updated { id: message.id },
+            MessageEvent::Deleted(message) => Notification::Deleted(*message),
+        }
+    }
+}
+
+fn instance_id() -> u64 {
+    static INSTANCE_ID: OnceLock<u64> = OnceLock::new();
+    *INSTANCE_ID.get_or_init(|| OsRng.next_u64())
+}
+
+// Hands message events to this instance's subscribers. Each set of
+// repositories has its own, so separate databases (one per test, say) do not
+// see each other's events.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<MessageEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: MessageEvent) {
+        // Sending only fails when nobody is subscribed, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MessageEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// Delivers the event to this instance's subscribers and, on Postgres, to
+// every other instance through NOTIFY. The write has already happened, so a
+// failed NOTIFY is logged rather than reported to the caller.
+pub async fn emit(db: &DatabaseConnection, bus: &EventBus, event: MessageEvent) {
+    if db.get_database_backend() == DatabaseBackend::Postgres {
+        if let Err(e) = notify(db, &event).await {
+            tracing::error!("Failed to notify other instances: {}", e);
+        }
+    }
+    bus.publish(event);
+}
+
+async fn notify(db: &DatabaseConnection, event: &MessageEvent) -> Result<(), Box<dyn Error>> {
+    let payload = serde_json::to_string(&Envelope {
+        origin: instance_id(),
+        event: event.into(),
+    })?;
+    db.execute(Statement::from_sql_and_values(
+        DatabaseBackend::Postgres,
+        "SELECT pg_notify($1, $2)",
+        [NOTIFY_CHANNEL.into(), payload.into()],
+    ))
+    .await?;
+    Ok(())
+}
+
+// Runs for the lifetime of the process, forwarding events written by other
+// instances to local subscribers. LISTEN needs a dedicated connection, so it
+// opens its own instead of borrowing one from `db`. Events sent while the
+// listener is reconnecting are lost.
+pub fn spawn_listener(db: DatabaseConnection, database_url: String, bus: EventBus) {
+    if db.get_database_backend() != DatabaseBackend::Postgres {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            let result = match listen(&database_url).await {
+                Ok(listener) => forward(listener, &db, &bus).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::error!("Message event listener failed: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn listen(database_url: &str) -> Result<PgListener, Box<dyn Error + Send + Sync>> {
+    let mut listener = PgListener::connect(database_url).await?;
+    listener.listen(NOTIFY_CHANNEL).await?;
+    Ok(listener)
+}
+
+async fn forward(
+    mut listener: PgListener,
+    db: &DatabaseConnection,
+    bus: &EventBus,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let notification = listener.recv().await?;
+        let envelope = match serde_json::from_str::<Envelope>(notification.payload()) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                tracing::warn!("Ignoring malformed message event: {}", e);
+                continue;
+            }
+        };
+        if envelope.origin == instance_id() {
+            continue;
+        }
+        // A row that is already gone again has nothing left to announce.
+        let event = match envelope.event {
+            Notification::Created { id } => message::Entity::find_by_id(id)
+                .one(db)
+                .await?
+                .map(MessageEvent::Created),
+            Notification::Updated { id } => message::Entity::find_by_id(id)
+                .one(db)
+                .await?
+                .map(MessageEvent::Updated),
+            Notification::Deleted(message) => Some(MessageEvent::Deleted(message)),
+        };
+        if let Some(event) = event {
+            bus.publish(event);
+        }
+    }
+}
+
+// Needs Postgres for LISTEN/NOTIFY.
+#[cfg(all(test, not(feature = "sqlite")))]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use crate::entity::user;
+    use sea_orm::{ActiveModelTrait, Set};
+    use std::env;
+
+    #[tokio::test]
+    async fn test_forwards_events_from_other_instances() {
+        let db = testing::connect("TEST_DATABASE_URL").await;
+        let db_url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let author = user::ActiveModel {
+            name: Set("Remote Rita".to_owned()),
+            ..Default::default()
+        }
+        .insert(&*db)
+        .await
+        .expect("Failed to create user");
+        let message = message::ActiveModel {
+            user_id: Set(Some(author.id)),
+            content: Set("Written elsewhere".to_owned()),
+            ..Default::default()
+        }
+        .insert(&*db)
+        .await
+        .expect("Failed to create message");
+
+        let listener = listen(&db_url).await.expect("Failed to listen");
+        let forward_db = db.clone();
+        let bus = EventBus::default();
+        let mut receiver = bus.subscribe();
+        tokio::spawn(async move { forward(listener, &forward_db, &bus).await });
+
+        // Pretend another instance wrote these.
+        for event in [
+            Notification::Created { id: message.id },
+            Notification::Deleted(DeletedMessage::from(&message)),
+        ] {
+            let payload = serde_json::to_string(&Envelope {
+                origin: instance_id().wrapping_add(1),
+                event,
+            })
+            .unwrap();
+            db.execute(Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "SELECT pg_notify($1, $2)",
+                [NOTIFY_CHANNEL.into(), payload.into()],
+            ))
+            .await
+            .expect("Failed to notify");
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("Timed out waiting for an event")
+                .expect("Event channel closed");
+            if event.user_id() == Some(author.id) {
+                received.push(event);
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                MessageEvent::Created(message.clone()),
+                MessageEvent::Deleted(DeletedMessage::from(&message)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1015 @@
+use crate::auth::password::{hash_password, verify_login};
+use crate::auth::personal_access_token::{generate_token, hash_token};
+use crate::auth::scope::{format_scopes, Scope};
+use crate::db::events::{DeletedMessage, EventBus, MessageEvent};
+use crate::db::mention::mentioned_names;
+use crate::db::repository::{
+    already_reacted, channel_name_taken, channel_not_found, channel_topic, check_can_hide,
+    check_can_purge, check_can_set_role, check_channel_access, check_channel_messages,
+    check_message_access, check_reply_parent, check_user_access, invalid_login, message_not_found,
+    name_taken, now, reaction_not_found, reply_channel, user_not_found, validate_channel_name,
+    validate_emoji, validate_registration, validate_token_scopes, Actor, ChannelChanges,
+    ChannelRepository, MessageCursor, MessagePage, MessageRepository, PageRequest, ReactionCount,
+    ReactionRepository, ThreadEntry, TokenRepository, UserRepository,
+};
+use crate::entity::channel::Visibility;
+use crate::entity::user::Role;
+use crate::entity::{
+    channel, message, message_mention, message_reaction, message_revision, personal_access_token,
+    user,
+};
+use crate::error::AppError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// The repositories kept in process memory. Mirrors the constraints of the
+// database schema (unique names, foreign keys, cascading deletes) so code
+// tested against it behaves the same on Postgres. Events only reach
+// subscribers in this process.
+#[derive(Clone)]
+pub struct InMemoryRepository {
+    state: Arc<Mutex<State>>,
+    events: EventBus,
+}
+
+#[derive(Default)]
+struct State {
+    users: BTreeMap<i32, user::Model>,
+    messages: BTreeMap<i32, message::Model>,
+    channels: BTreeMap<i32, channel::Model>,
+    revisions: BTreeMap<i32, message_revision::Model>,
+    reactions: BTreeMap<i32, message_reaction::Model>,
+    mentions: BTreeMap<i32, message_mention::Model>,
+    tokens: BTreeMap<i32, personal_access_token::Model>,
+    last_id: i32,
+}
+
+impl State {
+    fn next_id(&mut self) -> i32 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn user_by_name(&self, name: &str) -> Option<&user::Model> {
+        self.users.values().find(|user| user.name == name)
+    }
+
+    fn user_mut(&mut self, id: i32) -> Result<&mut user::Model, AppError> {
+        self.users.get_mut(&id).ok_or_else(user_not_found)
+    }
+
+    fn channel_by_name(&self, name: &str) -> Option<&channel::Model> {
+        self.channels.values().find(|channel| channel.name == name)
+    }
+
+    fn message_mut(&mut self, id: i32) -> Result<&mut message::Model, AppError> {
+        self.messages.get_mut(&id).ok_or_else(message_not_found)
+    }
+
+    fn children(&self, parent_id: i32) -> impl Iterator<Item = &message::Model> {
+        self.messages
+            .values()
+            .filter(move |message| message.parent_id == Some(parent_id))
+    }
+
+    fn find_reaction(&self, user_id: i32, message_id: i32, emoji: &str) -> Option<i32> {
+        self.reactions
+            .values()
+            .find(|reaction| {
+                reaction.message_id == message_id
+                    && reaction.user_id == user_id
+                    && reaction.emoji == emoji
+            })
+            .map(|reaction| reaction.id)
+    }
+
+    // Same as the database version: mentions that still apply are kept.
+    fn sync_mentions(&mut self, message_id: i32, content: &str) {
+        let names = mentioned_names(content);
+        let user_ids: Vec<i32> = self
+            .users
+            .values()
+            .filter(|user| names.contains(user.name.as_str()))
+            .map(|user| user.id)
+            .collect();
+        self.mentions.retain(|_, mention| {
+            mention.message_id != message_id || user_ids.contains(&mention.user_id)
+        });
+        let created_at = now();
+        for user_id in user_ids {
+            let kept = self
+                .mentions
+                .values()
+                .any(|mention| mention.message_id == message_id && mention.user_id == user_id);
+            if !kept {
+                let mention = message_mention::Model {
+                    id: self.next_id(),
+                    message_id,
+                    user_id,
+                    created_at,
+                };
+                self.mentions.insert(mention.id, mention);
+            }
+        }
+    }
+
+    // Marks the messages deleted and drops their reactions and mentions.
+    // Returns the ones that were not deleted before, as they were until now.
+    fn tombstone(&mut self, ids: &[i32]) -> Vec<message::Model> {
+        let deleted_at = now();
+        let mut tombstoned = Vec::new();
+        for id in ids {
+            if let Some(message) = self.messages.get_mut(id) {
+                if message.deleted_at.is_none() {
+                    tombstoned.push(message.clone());
+                    message.deleted_at = Some(deleted_at);
+                }
+            }
+        }
+        self.reactions
+            .retain(|_, reaction| !ids.contains(&reaction.message_id));
+        self.mentions
+            .retain(|_, mention| !ids.contains(&mention.message_id));
+        tombstoned
+    }
+
+    // Removes the message and every reply below it, like ON DELETE CASCADE,
+    // and returns what was removed, starting with the message itself.
+    fn remove_message(&mut self, id: i32) -> Vec<message::Model> {
+        let replies: Vec<i32> = self.children(id).map(|reply| reply.id).collect();
+        let Some(message) = self.messages.remove(&id) else {
+            return Vec::new();
+        };
+        self.revisions
+            .retain(|_, revision| revision.message_id != id);
+        self.reactions
+            .retain(|_, reaction| reaction.message_id != id);
+        self.mentions.retain(|_, mention| mention.message_id != id);
+        let mut removed = vec![message];
+        for reply in replies {
+            removed.extend(self.remove_message(reply));
+        }
+        removed
+    }
+
+    fn page(&self, filter: impl Fn(&message::Model) -> bool, page: &PageRequest) -> MessagePage {
+        let mut messages: Vec<message::Model> = self
+            .messages
+            .values()
+            .filter(|message| filter(message))
+            .filter(|message| within(MessageCursor::from(*message), page))
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| MessageCursor::from(message));
+        if page.reads_descending() {
+            messages.reverse();
+        }
+        messages.truncate(page.clamped_limit() as usize + 1);
+        MessagePage::from_rows(messages, page)
+    }
+}
+
+// Whether the message lies strictly between the page's cursors.
+fn within(cursor: MessageCursor, page: &PageRequest) -> bool {
+    let past = |bound: MessageCursor, ascending: bool| {
+        if ascending {
+            cursor > bound
+        } else {
+            cursor < bound
+        }
+    };
+    page.after
+        .is_none_or(|after| past(after, !page.newest_first))
+        && page
+            .before
+            .is_none_or(|before| past(before, page.newest_first))
+}
+
+fn missing_reference() -> AppError {
+    AppError::NotFound("Referenced record not found".to_string())
+}
+
+impl InMemoryRepository {
+    pub fn new(events: EventBus) -> Self {
+        Self {
+            state: Arc::default(),
+            events,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Repository lock poisoned")
+    }
+}
+
+#[async_trait]
+impl UserRepository for InMemoryRepository {
+    async fn get(&self, id: i32) -> Result<Option<user::Model>, AppError> {
+        Ok(self.state().users.get(&id).cloned())
+    }
+
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<user::Model>, AppError> {
+        let state = self.state();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.users.get(id).cloned())
+            .collect())
+    }
+
+    async fn create(&self, name: &str) -> Result<user::Model, AppError> {
+        let mut state = self.state();
+        if state.user_by_name(name).is_some() {
+            return Err(AppError::Conflict("Already exists".to_string()));
+        }
+        let user = user::Model {
+            id: state.next_id(),
+            name: name.to_owned(),
+            password_hash: None,
+            role: Role::Member,
+        };
+        state.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    async fn register(&self, name: &str, password: &str) -> Result<user::Model, AppError> {
+        validate_registration(name, password)?;
+        if self.state().user_by_name(name).is_some() {
+            return Err(name_taken());
+        }
+        let password_hash =
+            hash_password(password).map_err(|e| AppError::Internal(e.to_string()))?;
+
+        let mut state = self.state();
+        // Someone may have taken the name while the password was hashing.
+        if state.user_by_name(name).is_some() {
+            return Err(name_taken());
+        }
+        let user = user::Model {
+            id: state.next_id(),
+            name: name.to_owned(),
+            password_hash: Some(password_hash),
+            role: Role::Member,
+        };
+        state.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    async fn create_with_role(
+        &self,
+        actor: Actor,
+        name: &str,
+        password: Option<&str>,
+        role: Role,
+    ) -> Result<user::Model, AppError> {
+        if role != Role::Member {
+            check_can_set_role(actor)?;
+        }
+        let password_hash = match password {
+            Some(password) => {
+                validate_registration(name, password)?;
+                Some(hash_password(password).map_err(|e| AppError::Internal(e.to_string()))?)
+            }
+            None => None,
+        };
+
+        let mut state = self.state();
+        if state.user_by_name(name).is_some() {
+            return Err(name_taken());
+        }
+        let user = user::Model {
+            id: state.next_id(),
+            name: name.to_owned(),
+            password_hash,
+            role,
+        };
+        state.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    async fn login(&self, name: &str, password: &str) -> Result<user::Model, AppError> {
+        let user = self.state().user_by_name(name).cloned();
+        let password_hash = user.as_ref().and_then(|user| user.password_hash.as_deref());
+        if verify_login(password, password_hash) {
+            user.ok_or_else(invalid_login)
+        } else {
+            Err(invalid_login())
+        }
+    }
+
+    async fn update(&self, actor: Actor, id: i32, name: &str) -> Result<user::Model, AppError> {
+        check_user_access(actor, id)?;
+        let mut state = self.state();
+        if state.user_by_name(name).is_some_and(|other| other.id != id) {
+            return Err(AppError::Conflict("Already exists".to_string()));
+        }
+        let user = state.user_mut(id)?;
+        user.name = name.to_owned();
+        Ok(user.clone())
+    }
+
+    async fn set_role(&self, actor: Actor, id: i32, role: Role) -> Result<user::Model, AppError> {
+        check_can_set_role(actor)?;
+        let mut state = self.state();
+        let user = state.user_mut(id)?;
+        user.role = role;
+        Ok(user.clone())
+    }
+
+    async fn delete(&self, actor: Actor, id: i32) -> Result<(), AppError> {
+        check_user_access(actor, id)?;
+        let tombstoned = {
+            let mut state = self.state();
+            if state.users.remove(&id).is_none() {
+                return Err(user_not_found());
+            }
+            let authored: Vec<i32> = state
+                .messages
+                .values()
+                .filter(|message| message.user_id == Some(id))
+                .map(|message| message.id)
+                .collect();
+            let tombstoned = state.tombstone(&authored);
+            // Like the foreign key, which clears the author.
+            for message in state.messages.values_mut() {
+                if message.user_id == Some(id) {
+                    message.user_id = None;
+                }
+            }
+            for channel in state.channels.values_mut() {
+                if channel.created_by == Some(id) {
+                    channel.created_by = None;
+                }
+            }
+            state.reactions.retain(|_, reaction| reaction.user_id != id);
+            state.mentions.retain(|_, mention| mention.user_id != id);
+            state.tokens.retain(|_, token| token.user_id != id);
+            tombstoned
+        };
+        for message in &tombstoned {
+            self.events
+                .publish(MessageEvent::Deleted(DeletedMessage::from(message)));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageRepository for InMemoryRepository {
+    async fn get(&self, id: i32) -> Result<Option<message::Model>, AppError> {
+        Ok(self.state().messages.get(&id).cloned())
+    }
+
+    async fn create(
+        &self,
+        user_id: i32,
+        content: &str,
+        parent_id: Option<i32>,
+        channel_id: Option<i32>,
+    ) -> Result<message::Model, AppError> {
+        let message = {
+            let mut state = self.state();
+            let parent_exists = parent_id.is_none_or(|id| state.messages.contains_key(&id));
+            if !state.users.contains_key(&user_id) || !parent_exists {
+                return Err(missing_reference());
+            }
+            let parent = parent_id.and_then(|id| state.messages.get(&id));
+            check_reply_parent(parent)?;
+            let channel_id = match parent {
+                Some(parent) => reply_channel(parent, channel_id)?,
+                None => channel_id,
+            };
+            if channel_id.is_some_and(|id| !state.channels.contains_key(&id)) {
+                return Err(channel_not_found());
+            }
+            let created_at = now();
+            let message = message::Model {
+                id: state.next_id(),
+                user_id: Some(user_id),
+                content: content.to_owned(),
+                created_at,
+                updated_at: created_at,
+                parent_id,
+                hidden_at: None,
+                deleted_at: None,
+                channel_id,
+            };
+            state.messages.insert(message.id, message.clone());
+            state.sync_mentions(message.id, content);
+            message
+        };
+        self.events.publish(MessageEvent::Created(message.clone()));
+        Ok(message)
+    }
+
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        content: &str,
+    ) -> Result<message::Model, AppError> {
+        let message = {
+            let mut state = self.state();
+            check_message_access(state.messages.get(&id), actor, false)?;
+            let revision = state
+                .revisions
+                .values()
+                .filter(|revision| revision.message_id == id)
+                .count();
+            let revision = message_revision::Model {
+                id: state.next_id(),
+                message_id: id,
+                revision: revision as i32,
+                content: state.messages[&id].content.clone(),
+                created_at: state.messages[&id].updated_at,
+            };
+            state.revisions.insert(revision.id, revision);
+            let message = state.message_mut(id)?;
+            message.content = content.to_owned();
+            message.updated_at = now();
+            let message = message.clone();
+            state.sync_mentions(id, content);
+            message
+        };
+        self.events.publish(MessageEvent::Updated(message.clone()));
+        Ok(message)
+    }
+
+    async fn revisions(
+        &self,
+        message_ids: &[i32],
+    ) -> Result<Vec<message_revision::Model>, AppError> {
+        let mut revisions: Vec<message_revision::Model> = self
+            .state()
+            .revisions
+            .values()
+            .filter(|revision| message_ids.contains(&revision.message_id))
+            .cloned()
+            .collect();
+        revisions.sort_by_key(|revision| (revision.message_id, revision.revision));
+        Ok(revisions)
+    }
+
+    async fn mentions(&self, message_ids: &[i32]) -> Result<Vec<(i32, user::Model)>, AppError> {
+        let state = self.state();
+        let mut mentions: Vec<(i32, user::Model)> = state
+            .mentions
+            .values()
+            .filter(|mention| message_ids.contains(&mention.message_id))
+            .filter_map(|mention| {
+                let user = state.users.get(&mention.user_id)?;
+                Some((mention.message_id, user.clone()))
+            })
+            .collect();
+        mentions.sort_by(|a, b| (a.0, &a.1.name).cmp(&(b.0, &b.1.name)));
+        Ok(mentions)
+    }
+
+    async fn hide(&self, actor: Actor, id: i32, hidden: bool) -> Result<message::Model, AppError> {
+        check_can_hide(actor)?;
+        let message = {
+            let mut state = self.state();
+            let message = state
+                .messages
+                .get_mut(&id)
+                .filter(|message| message.deleted_at.is_none())
+                .ok_or_else(message_not_found)?;
+            message.hidden_at = hidden.then(now);
+            message.clone()
+        };
+        self.events.publish(MessageEvent::Updated(message.clone()));
+        Ok(message)
+    }
+
+    async fn delete(&self, actor: Actor, id: i32) -> Result<message::Model, AppError> {
+        let message = {
+            let mut state = self.state();
+            check_message_access(state.messages.get(&id), actor, true)?;
+            let message = state.message_mut(id)?;
+            message.deleted_at = Some(now());
+            let message = message.clone();
+            state
+                .reactions
+                .retain(|_, reaction| reaction.message_id != id);
+            state.mentions.retain(|_, mention| mention.message_id != id);
+            message
+        };
+        self.events
+            .publish(MessageEvent::Deleted(DeletedMessage::from(&message)));
+        Ok(message)
+    }
+
+    async fn purge(&self, actor: Actor, id: i32) -> Result<message::Model, AppError> {
+        check_can_purge(actor)?;
+        let removed = self.state().remove_message(id);
+        let message = removed.first().cloned().ok_or_else(message_not_found)?;
+        for removed in removed
+            .iter()
+            .filter(|removed| removed.deleted_at.is_none())
+        {
+            self.events
+                .publish(MessageEvent::Deleted(DeletedMessage::from(removed)));
+        }
+        Ok(message)
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        Ok(self.state().page(
+            |message| message.user_id == Some(user_id) && message.deleted_at.is_none(),
+            &page,
+        ))
+    }
+
+    async fn list_in_time_range(
+        &self,
+        user_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        Ok(self.state().page(
+            |message| {
+                message.user_id == Some(user_id)
+                    && message.deleted_at.is_none()
+                    && start.is_none_or(|start| message.created_at >= start)
+                    && end.is_none_or(|end| message.created_at <= end)
+            },
+            &page,
+        ))
+    }
+
+    async fn list_in_channel(
+        &self,
+        channel_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        Ok(self.state().page(
+            |message| {
+                message.channel_id == Some(channel_id)
+                    && message.deleted_at.is_none()
+                    && start.is_none_or(|start| message.created_at >= start)
+                    && end.is_none_or(|end| message.created_at <= end)
+            },
+            &page,
+        ))
+    }
+
+    async fn list_replies(
+        &self,
+        parent_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        Ok(self
+            .state()
+            .page(|message| message.parent_id == Some(parent_id), &page))
+    }
+
+    async fn list_mentioning(
+        &self,
+        user_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError> {
+        let state = self.state();
+        let mentioned: Vec<i32> = state
+            .mentions
+            .values()
+            .filter(|mention| mention.user_id == user_id)
+            .map(|mention| mention.message_id)
+            .collect();
+        Ok(state.page(|message| mentioned.contains(&message.id), &page))
+    }
+
+    async fn thread(
+        &self,
+        root_id: i32,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<ThreadEntry>, AppError> {
+        let state = self.state();
+        let Some(root) = state.messages.get(&root_id) else {
+            return Err(AppError::NotFound("Root message not found".to_string()));
+        };
+
+        let entry = |message: &message::Model, depth: i32, path: Vec<i32>| ThreadEntry {
+            message: message.clone(),
+            user: message
+                .user_id
+                .and_then(|user_id| state.users.get(&user_id))
+                .cloned(),
+            depth,
+            path,
+        };
+        let max_depth = max_depth.map_or(i32::MAX, |depth| depth.min(i32::MAX as u32) as i32);
+        let mut thread = vec![entry(root, 0, vec![root.id])];
+        let mut next = 0;
+        while next < thread.len() {
+            let (parent_id, depth, path) = {
+                let parent = &thread[next];
+                (parent.message.id, parent.depth, parent.path.clone())
+            };
+            next += 1;
+            if depth >= max_depth {
+                continue;
+            }
+            for reply in state.children(parent_id) {
+                let mut reply_path = path.clone();
+                reply_path.push(reply.id);
+                thread.push(entry(reply, depth + 1, reply_path));
+            }
+        }
+        thread.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(thread)
+    }
+}
+
+#[async_trait]
+impl ChannelRepository for InMemoryRepository {
+    async fn get(&self, id: i32) -> Result<Option<channel::Model>, AppError> {
+        Ok(self.state().channels.get(&id).cloned())
+    }
+
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<channel::Model>, AppError> {
+        let state = self.state();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.channels.get(id).cloned())
+            .collect())
+    }
+
+    async fn list_public(&self) -> Result<Vec<channel::Model>, AppError> {
+        let mut channels: Vec<channel::Model> = self
+            .state()
+            .channels
+            .values()
+            .filter(|channel| channel.visibility == Visibility::Public)
+            .cloned()
+            .collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(channels)
+    }
+
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        topic: Option<&str>,
+        visibility: Visibility,
+    ) -> Result<channel::Model, AppError> {
+        validate_channel_name(name)?;
+        let mut state = self.state();
+        if !state.users.contains_key(&user_id) {
+            return Err(missing_reference());
+        }
+        let name = name.trim();
+        if state.channel_by_name(name).is_some() {
+            return Err(channel_name_taken());
+        }
+        let created_at = now();
+        let channel = channel::Model {
+            id: state.next_id(),
+            name: name.to_owned(),
+            topic: channel_topic(topic),
+            created_by: Some(user_id),
+            visibility,
+            created_at,
+            updated_at: created_at,
+        };
+        state.channels.insert(channel.id, channel.clone());
+        Ok(channel)
+    }
+
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        changes: ChannelChanges,
+    ) -> Result<channel::Model, AppError> {
+        let mut state = self.state();
+        check_channel_access(state.channels.get(&id), actor)?;
+        if let Some(name) = &changes.name {
+            validate_channel_name(name)?;
+            if state
+                .channel_by_name(name.trim())
+                .is_some_and(|channel| channel.id != id)
+            {
+                return Err(channel_name_taken());
+            }
+        }
+        let channel = state.channels.get_mut(&id).ok_or_else(channel_not_found)?;
+        if let Some(name) = changes.name {
+            channel.name = name.trim().to_owned();
+        }
+        if let Some(topic) = changes.topic {
+            channel.topic = channel_topic(Some(&topic));
+        }
+        if let Some(visibility) = changes.visibility {
+            channel.visibility = visibility;
+        }
+        channel.updated_at = now();
+        Ok(channel.clone())
+    }
+
+    async fn delete(&self, actor: Actor, id: i32) -> Result<channel::Model, AppError> {
+        let (channel, tombstoned) = {
+            let mut state = self.state();
+            check_channel_access(state.channels.get(&id), actor)?;
+            let messages: Vec<message::Model> = state
+                .messages
+                .values()
+                .filter(|message| message.channel_id == Some(id))
+                .cloned()
+                .collect();
+            check_channel_messages(&messages, actor)?;
+            let ids: Vec<i32> = messages.iter().map(|message| message.id).collect();
+            let tombstoned = state.tombstone(&ids);
+            for message in state.messages.values_mut() {
+                if message.channel_id == Some(id) {
+                    message.channel_id = None;
+                }
+            }
+            let channel = state.channels.remove(&id).ok_or_else(channel_not_found)?;
+            (channel, tombstoned)
+        };
+        for message in &tombstoned {
+            self.events
+                .publish(MessageEvent::Deleted(DeletedMessage::from(message)));
+        }
+        Ok(channel)
+    }
+}
+
+#[async_trait]
+impl ReactionRepository for InMemoryRepository {
+    async fn add(
+        &self,
+        user_id: i32,
+        message_id: i32,
+        emoji: &str,
+    ) -> Result<message_reaction::Model, AppError> {
+        validate_emoji(emoji)?;
+        let mut state = self.state();
+        state
+            .messages
+            .get(&message_id)
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or_else(message_not_found)?;
+        if !state.users.contains_key(&user_id) {
+            return Err(missing_reference());
+        }
+        if state.find_reaction(user_id, message_id, emoji).is_some() {
+            return Err(already_reacted());
+        }
+        let reaction = message_reaction::Model {
+            id: state.next_id(),
+            message_id,
+            user_id,
+            emoji: emoji.to_owned(),
+            created_at: now(),
+        };
+        state.reactions.insert(reaction.id, reaction.clone());
+        Ok(reaction)
+    }
+
+    async fn remove(&self, user_id: i32, message_id: i32, emoji: &str) -> Result<(), AppError> {
+        let mut state = self.state();
+        let id = state
+            .find_reaction(user_id, message_id, emoji)
+            .ok_or_else(reaction_not_found)?;
+        state.reactions.remove(&id);
+        Ok(())
+    }
+
+    async fn counts(
+        &self,
+        message_ids: &[i32],
+        viewer_id: Option<i32>,
+    ) -> Result<Vec<ReactionCount>, AppError> {
+        let state = self.state();
+        let mut counts: HashMap<(i32, &str), ReactionCount> = HashMap::new();
+        for reaction in state.reactions.values() {
+            if !message_ids.contains(&reaction.message_id) {
+                continue;
+            }
+            let count = counts
+                .entry((reaction.message_id, reaction.emoji.as_str()))
+                .or_insert_with(|| ReactionCount {
+                    message_id: reaction.message_id,
+                    emoji: reaction.emoji.clone(),
+                    count: 0,
+                    reacted: false,
+                });
+            count.count += 1;
+            count.reacted |= viewer_id == Some(reaction.user_id);
+        }
+        Ok(counts.into_values().collect())
+    }
+}
+
+#[async_trait]
+impl TokenRepository for InMemoryRepository {
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: &[Scope],
+        expires_at: DateTime<Utc>,
+    ) -> Result<(String, personal_access_token::Model), AppError> {
+        validate_token_scopes(scopes)?;
+
+        let mut state = self.state();
+        if !state.users.contains_key(&user_id) {
+            return Err(missing_reference());
+        }
+        let raw_token = generate_token();
+        let token = personal_access_token::Model {
+            id: state.next_id(),
+            user_id,
+            name: name.to_owned(),
+            token_hash: hash_token(&raw_token),
+            scopes: format_scopes(scopes),
+            expires_at,
+            last_used_at: None,
+            created_at: now(),
+        };
+        state.tokens.insert(token.id, token.clone());
+        Ok((raw_token, token))
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<personal_access_token::Model>, AppError> {
+        Ok(self
+            .state()
+            .tokens
+            .values()
+            .filter(|token| token.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn revoke(&self, user_id: i32, token_id: i32) -> Result<(), AppError> {
+        let mut state = self.state();
+        // Other users' tokens look "not found", as on Postgres.
+        match state.tokens.get(&token_id) {
+            Some(token) if token.user_id == user_id => {
+                state.tokens.remove(&token_id);
+                Ok(())
+            }
+            _ => Err(AppError::NotFound("Token not found".to_string())),
+        }
+    }
+
+    async fn authenticate(&self, token: &str) -> Result<personal_access_token::Model, AppError> {
+        let token_hash = hash_token(token);
+        let mut state = self.state();
+        let found = state
+            .tokens
+            .values_mut()
+            .find(|found| found.token_hash == token_hash);
+        match found {
+            Some(found) if found.expires_at > Utc::now() => {
+                found.last_used_at = Some(now());
+                Ok(found.clone())
+            }
+            _ => Err(AppError::Unauthenticated(
+                "Invalid or expired token".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repository::Repositories;
+
+    fn actor(user: &user::Model) -> Actor {
+        Actor {
+            id: user.id,
+            role: user.role,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_follow_database_constraints() {
+        let repos = Repositories::in_memory();
+        let alice = repos.users.create("Alice").await.unwrap();
+        assert!(matches!(
+            repos.users.create("Alice").await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            repos
+                .messages
+                .create(alice.id + 100, "Orphan", None, None)
+                .await,
+            Err(AppError::NotFound(_))
+        ));
+
+        let registered = repos
+            .users
+            .register("Bob", "correct horse battery")
+            .await
+            .unwrap();
+        let logged_in = repos
+            .users
+            .login("Bob", "correct horse battery")
+            .await
+            .unwrap();
+        assert_eq!(logged_in.id, registered.id);
+        assert!(matches!(
+            repos.users.login("Alice", "anything").await,
+            Err(AppError::Unauthenticated(_))
+        ));
+
+        // Deleting a user leaves their messages as tombstones, and the replies
+        // to them in place
+        let post = repos
+            .messages
+            .create(alice.id, "Hello", None, None)
+            .await
+            .unwrap();
+        let reply = repos
+            .messages
+            .create(registered.id, "Hi", Some(post.id), None)
+            .await
+            .unwrap();
+        repos.users.delete(actor(&alice), alice.id).await.unwrap();
+        let post = repos.messages.get(post.id).await.unwrap().unwrap();
+        assert_eq!(post.user_id, None);
+        assert!(post.deleted_at.is_some());
+        assert_eq!(repos.messages.get(reply.id).await.unwrap(), Some(reply));
+    }
+
+    #[tokio::test]
+    async fn test_pages_and_threads_match_database_order() {
+        let repos = Repositories::in_memory();
+        let user = repos.users.create("Tess").await.unwrap();
+        // root <- a <- b, root <- c
+        let root = repos
+            .messages
+            .create(user.id, "root", None, None)
+            .await
+            .unwrap();
+        let a = repos
+            .messages
+            .create(user.id, "a", Some(root.id), None)
+            .await
+            .unwrap();
+        let c = repos
+            .messages
+            .create(user.id, "c", Some(root.id), None)
+            .await
+            .unwrap();
+        let b = repos
+            .messages
+            .create(user.id, "b", Some(a.id), None)
+            .await
+            .unwrap();
+
+        let thread = repos.messages.thread(root.id, None).await.unwrap();
+        let order: Vec<_> = thread.iter().map(|entry| entry.message.id).collect();
+        assert_eq!(order, vec![root.id, a.id, b.id, c.id]);
+        assert_eq!(thread[2].path, vec![root.id, a.id, b.id]);
+        assert_eq!(
+            repos.messages.thread(root.id, Some(1)).await.unwrap().len(),
+            3
+        );
+
+        let first = repos
+            .messages
+            .list_for_user(
+                user.id,
+                PageRequest {
+                    limit: Some(2),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(first.has_next_page);
+        let rest = repos
+            .messages
+            .list_for_user(
+                user.id,
+                PageRequest {
+                    after: first.messages.last().map(MessageCursor::from),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let order: Vec<_> = first
+            .messages
+            .iter()
+            .chain(&rest.messages)
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(order, vec![root.id, a.id, c.id, b.id]);
+        assert!(rest.has_previous_page && !rest.has_next_page);
+    }
+}
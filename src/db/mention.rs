@@ -0,0 +1,48 @@
+use std::collections::BTreeSet;
+
+// Characters a mentioned name can be made of. Users whose names contain
+// anything else, such as spaces, cannot be mentioned.
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+// The names written as `@name` in a message, each once. An `@` right after a
+// name character does not start a mention, so email addresses mention no
+// one. A trailing period is taken to end the sentence rather than the name.
+pub fn mentioned_names(content: &str) -> BTreeSet<&str> {
+    let mut names = BTreeSet::new();
+    let mut previous = None;
+    for (i, c) in content.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let rest = &content[i + 1..];
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = rest[..end].trim_end_matches('.');
+            if !name.is_empty() {
+                names.insert(name);
+            }
+        }
+        previous = Some(c);
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mentioned_names() {
+        let names = mentioned_names("@Bob, ask @carol_2 and @Bob. Mail me@example.com @ @...");
+        assert_eq!(
+            names.into_iter().collect::<Vec<_>>(),
+            vec!["Bob", "carol_2"]
+        );
+        assert_eq!(
+            mentioned_names("(@Dave)@Eve @j.doe.")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["Dave", "Eve", "j.doe"]
+        );
+        assert!(mentioned_names("no mentions here").is_empty());
+    }
+}
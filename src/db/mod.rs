@@ -1 +1,9 @@
 pub mod database;
+pub mod dump;
+pub mod events;
+pub mod memory;
+pub mod mention;
+pub mod repository;
+pub mod seed;
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,570 @@
+use crate::auth::password::MIN_PASSWORD_LENGTH;
+use crate::auth::scope::Scope;
+use crate::db::database::SeaOrmRepository;
+use crate::db::events::EventBus;
+use crate::db::memory::InMemoryRepository;
+use crate::entity::channel::Visibility;
+use crate::entity::user::Role;
+use crate::entity::{
+    channel, message, message_reaction, message_revision, personal_access_token, user,
+};
+use crate::error::AppError;
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use sea_orm::DatabaseConnection;
+use std::sync::Arc;
+
+// Page size used when the client does not ask for one.
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+// Larger page requests are clamped to this.
+pub const MAX_PAGE_SIZE: u64 = 100;
+// Long enough for emoji joined from several code points, such as families
+// and flags.
+pub const MAX_EMOJI_LENGTH: usize = 16;
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 64;
+
+// The user performing an action, for permission checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Actor {
+    pub id: i32,
+    pub role: Role,
+}
+
+impl Actor {
+    // Someone running the management CLI. They have the database at hand
+    // anyway, so they act as an admin. No user has id 0.
+    pub const OPERATOR: Actor = Actor {
+        id: 0,
+        role: Role::Admin,
+    };
+}
+
+// A message's position in the stable (created_at, id) ordering of lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: i32,
+}
+
+impl From<&message::Model> for MessageCursor {
+    fn from(message: &message::Model) -> Self {
+        Self {
+            created_at: message.created_at,
+            id: message.id,
+        }
+    }
+}
+
+// Which slice of a message list to return. Rows strictly between `after` and
+// `before` are considered; `limit` of them are taken from the start, or from
+// the end when `from_end` is set. Lists run oldest first unless
+// `newest_first` is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageRequest {
+    pub after: Option<MessageCursor>,
+    pub before: Option<MessageCursor>,
+    pub limit: Option<u64>,
+    pub from_end: bool,
+    pub newest_first: bool,
+}
+
+impl PageRequest {
+    pub fn clamped_limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+
+    // Whether rows are read newest first, which is the case when the list
+    // runs newest first or when reading an oldest-first list from its end.
+    pub fn reads_descending(&self) -> bool {
+        self.newest_first != self.from_end
+    }
+}
+
+#[derive(Debug)]
+pub struct MessagePage {
+    pub messages: Vec<message::Model>,
+    pub has_previous_page: bool,
+    pub has_next_page: bool,
+}
+
+impl MessagePage {
+    // Builds a page from rows already filtered by the cursors and sorted in
+    // reading order, with up to one row more than the limit to tell whether
+    // the list continues.
+    pub fn from_rows(mut messages: Vec<message::Model>, page: &PageRequest) -> Self {
+        let limit = page.clamped_limit();
+        let has_more = messages.len() as u64 > limit;
+        messages.truncate(limit as usize);
+        if page.from_end {
+            messages.reverse();
+        }
+        // Having been given a cursor means the client came from that side, so
+        // the list is reported as continuing there without checking.
+        let (has_previous_page, has_next_page) = if page.from_end {
+            (has_more, page.before.is_some())
+        } else {
+            (page.after.is_some(), has_more)
+        };
+        Self {
+            messages,
+            has_previous_page,
+            has_next_page,
+        }
+    }
+}
+
+// What to change on a channel; fields left as None stay as they are. An empty
+// topic removes it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelChanges {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub visibility: Option<Visibility>,
+}
+
+// How many users reacted to a message with one emoji.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReactionCount {
+    pub message_id: i32,
+    pub emoji: String,
+    pub count: i64,
+    // Whether the viewer is one of them.
+    pub reacted: bool,
+}
+
+// One message of a thread with its author. `path` holds the ids from the
+// root down to this message, so sorting by it yields depth-first order.
+#[derive(Clone, Debug)]
+pub struct ThreadEntry {
+    pub message: message::Model,
+    pub user: Option<user::Model>,
+    pub depth: i32,
+    pub path: Vec<i32>,
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn get(&self, id: i32) -> Result<Option<user::Model>, AppError>;
+    // Users that do not exist are left out.
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<user::Model>, AppError>;
+    // Creates a user without a password, who cannot log in.
+    async fn create(&self, name: &str) -> Result<user::Model, AppError>;
+    async fn register(&self, name: &str, password: &str) -> Result<user::Model, AppError>;
+    // Creates a user with their role in one step, for admins adding accounts.
+    // Only admins may give a role other than member. Without a password the
+    // user cannot log in, as with `create`.
+    async fn create_with_role(
+        &self,
+        actor: Actor,
+        name: &str,
+        password: Option<&str>,
+        role: Role,
+    ) -> Result<user::Model, AppError>;
+    async fn login(&self, name: &str, password: &str) -> Result<user::Model, AppError>;
+    async fn update(&self, actor: Actor, id: i32, name: &str) -> Result<user::Model, AppError>;
+    async fn set_role(&self, actor: Actor, id: i32, role: Role) -> Result<user::Model, AppError>;
+    // Their messages are left as tombstones without an author, so replies
+    // from others keep their place, and are announced as deleted. Their
+    // reactions, mentions and tokens go with them; the channels they created
+    // are kept.
+    async fn delete(&self, actor: Actor, id: i32) -> Result<(), AppError>;
+}
+
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    async fn get(&self, id: i32) -> Result<Option<message::Model>, AppError>;
+    // Records the users mentioned in the content. Replies go to the channel of
+    // their parent, so `channel_id` only needs to be given for root messages.
+    async fn create(
+        &self,
+        user_id: i32,
+        content: &str,
+        parent_id: Option<i32>,
+        channel_id: Option<i32>,
+    ) -> Result<message::Model, AppError>;
+    // Saves the content being replaced as a revision along with the edit, and
+    // brings the recorded mentions in line with the new content. Users
+    // mentioned before and after keep their original mention.
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        content: &str,
+    ) -> Result<message::Model, AppError>;
+    // The saved revisions of the given messages, by message and then oldest
+    // first. Messages that were never edited have none.
+    async fn revisions(
+        &self,
+        message_ids: &[i32],
+    ) -> Result<Vec<message_revision::Model>, AppError>;
+    // The users mentioned in the given messages, by message and then name.
+    async fn mentions(&self, message_ids: &[i32]) -> Result<Vec<(i32, user::Model)>, AppError>;
+    async fn hide(&self, actor: Actor, id: i32, hidden: bool) -> Result<message::Model, AppError>;
+    // Turns the message into a tombstone and returns it. Replies are kept, and
+    // the tombstone keeps its place in threads and reply lists; its reactions
+    // and mentions are removed.
+    async fn delete(&self, actor: Actor, id: i32) -> Result<message::Model, AppError>;
+    // Removes the row for good, tombstone or not, and every reply below it
+    // with it. Returns the removed row.
+    async fn purge(&self, actor: Actor, id: i32) -> Result<message::Model, AppError>;
+    // Deleted messages are left out of a user's messages, since they no
+    // longer show who wrote them.
+    async fn list_for_user(&self, user_id: i32, page: PageRequest)
+        -> Result<MessagePage, AppError>;
+    // Either bound may be left open; both are inclusive. Deleted messages are
+    // left out, as above.
+    async fn list_in_time_range(
+        &self,
+        user_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError>;
+    // Like `list_in_time_range`, for the messages of a channel.
+    async fn list_in_channel(
+        &self,
+        channel_id: i32,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError>;
+    async fn list_replies(
+        &self,
+        parent_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError>;
+    // Messages that mention the user, in the order of the other lists.
+    async fn list_mentioning(
+        &self,
+        user_id: i32,
+        page: PageRequest,
+    ) -> Result<MessagePage, AppError>;
+    // The root and its replies down to `max_depth` levels (all of them
+    // without a limit), in depth-first order.
+    async fn thread(
+        &self,
+        root_id: i32,
+        max_depth: Option<u32>,
+    ) -> Result<Vec<ThreadEntry>, AppError>;
+}
+
+#[async_trait]
+pub trait ChannelRepository: Send + Sync {
+    async fn get(&self, id: i32) -> Result<Option<channel::Model>, AppError>;
+    // Channels that do not exist are left out.
+    async fn get_many(&self, ids: &[i32]) -> Result<Vec<channel::Model>, AppError>;
+    // Public channels by name.
+    async fn list_public(&self) -> Result<Vec<channel::Model>, AppError>;
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        topic: Option<&str>,
+        visibility: Visibility,
+    ) -> Result<channel::Model, AppError>;
+    async fn update(
+        &self,
+        actor: Actor,
+        id: i32,
+        changes: ChannelChanges,
+    ) -> Result<channel::Model, AppError>;
+    // Removes the channel. The messages in it are left as tombstones outside
+    // of any channel, so a member may only delete a channel once the live
+    // messages in it are all their own; moderators may delete any. Returns
+    // the removed row.
+    async fn delete(&self, actor: Actor, id: i32) -> Result<channel::Model, AppError>;
+}
+
+#[async_trait]
+pub trait ReactionRepository: Send + Sync {
+    // Deleted messages cannot be reacted to.
+    async fn add(
+        &self,
+        user_id: i32,
+        message_id: i32,
+        emoji: &str,
+    ) -> Result<message_reaction::Model, AppError>;
+    async fn remove(&self, user_id: i32, message_id: i32, emoji: &str) -> Result<(), AppError>;
+    // Every emoji used on the given messages, in no particular order.
+    async fn counts(
+        &self,
+        message_ids: &[i32],
+        viewer_id: Option<i32>,
+    ) -> Result<Vec<ReactionCount>, AppError>;
+}
+
+#[async_trait]
+pub trait TokenRepository: Send + Sync {
+    // Returns the raw token along with the stored row. The raw token is not
+    // stored and only available here.
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        scopes: &[Scope],
+        expires_at: DateTime<Utc>,
+    ) -> Result<(String, personal_access_token::Model), AppError>;
+    async fn list(&self, user_id: i32) -> Result<Vec<personal_access_token::Model>, AppError>;
+    async fn revoke(&self, user_id: i32, token_id: i32) -> Result<(), AppError>;
+    // Looks up a raw token presented by a client and records its use.
+    async fn authenticate(&self, token: &str) -> Result<personal_access_token::Model, AppError>;
+}
+
+// The repositories a request works with, backed by the same store.
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub messages: Arc<dyn MessageRepository>,
+    pub channels: Arc<dyn ChannelRepository>,
+    pub reactions: Arc<dyn ReactionRepository>,
+    pub tokens: Arc<dyn TokenRepository>,
+    // Where message writes are announced.
+    pub events: EventBus,
+}
+
+impl Repositories {
+    pub fn sea_orm(db: DatabaseConnection) -> Self {
+        let events = EventBus::default();
+        Self::from_store(Arc::new(SeaOrmRepository::new(db, events.clone())), events)
+    }
+
+    // Keeps everything in process memory, for tests that should not need a
+    // database.
+    pub fn in_memory() -> Self {
+        let events = EventBus::default();
+        Self::from_store(Arc::new(InMemoryRepository::new(events.clone())), events)
+    }
+
+    fn from_store<S>(store: Arc<S>, events: EventBus) -> Self
+    where
+        S: UserRepository
+            + MessageRepository
+            + ChannelRepository
+            + ReactionRepository
+            + TokenRepository
+            + 'static,
+    {
+        Self {
+            users: store.clone(),
+            messages: store.clone(),
+            channels: store.clone(),
+            reactions: store.clone(),
+            tokens: store,
+            events,
+        }
+    }
+}
+
+// Rules shared by the implementations.
+
+// Timestamps are written by the application rather than left to column
+// defaults, at the microsecond precision Postgres keeps. That way every
+// backend stores them in the same format, which matters for SQLite where
+// they are compared as text.
+pub fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(6)
+}
+
+// Users may manage their own account; admins may manage anyone's.
+pub fn check_user_access(actor: Actor, user_id: i32) -> Result<(), AppError> {
+    if actor.id == user_id || actor.role.is_admin() {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Only the user or an admin can modify this user".to_string(),
+        ))
+    }
+}
+
+pub fn check_can_set_role(actor: Actor) -> Result<(), AppError> {
+    if actor.role.is_admin() {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Only admins can change roles".to_string(),
+        ))
+    }
+}
+
+pub fn check_can_hide(actor: Actor) -> Result<(), AppError> {
+    if actor.role.can_moderate() {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Only moderators can hide messages".to_string(),
+        ))
+    }
+}
+
+pub fn check_can_purge(actor: Actor) -> Result<(), AppError> {
+    if actor.role.is_admin() {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Only admins can purge messages".to_string(),
+        ))
+    }
+}
+
+// Only the author of a message may modify it. With `moderator_override`,
+// moderators and admins are let through as well (used for removal, never for
+// rewriting someone else's words). Deleted messages cannot be modified.
+pub fn check_message_access(
+    message: Option<&message::Model>,
+    actor: Actor,
+    moderator_override: bool,
+) -> Result<(), AppError> {
+    match message {
+        None => Err(AppError::NotFound("Message not found".to_string())),
+        Some(message) if message.deleted_at.is_some() => Err(message_not_found()),
+        Some(message) if message.user_id == Some(actor.id) => Ok(()),
+        Some(_) if moderator_override && actor.role.can_moderate() => Ok(()),
+        Some(_) if moderator_override => Err(AppError::Forbidden(
+            "Only the author or a moderator can delete this message".to_string(),
+        )),
+        Some(_) => Err(AppError::Forbidden(
+            "Only the author can modify this message".to_string(),
+        )),
+    }
+}
+
+// A missing parent is left to the foreign key, which reports it as not found.
+pub fn check_reply_parent(parent: Option<&message::Model>) -> Result<(), AppError> {
+    match parent {
+        Some(parent) if parent.deleted_at.is_some() => Err(AppError::Validation(
+            "Cannot reply to a deleted message".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Replies stay in the channel of their parent. Naming a different one is an
+// error rather than being ignored.
+pub fn reply_channel(
+    parent: &message::Model,
+    channel_id: Option<i32>,
+) -> Result<Option<i32>, AppError> {
+    match channel_id {
+        Some(channel_id) if parent.channel_id != Some(channel_id) => Err(AppError::Validation(
+            "A reply has to be in the channel of its parent".to_string(),
+        )),
+        _ => Ok(parent.channel_id),
+    }
+}
+
+// Channels are managed by whoever created them, and by moderators.
+pub fn check_channel_access(
+    channel: Option<&channel::Model>,
+    actor: Actor,
+) -> Result<(), AppError> {
+    match channel {
+        None => Err(channel_not_found()),
+        Some(channel) if channel.created_by == Some(actor.id) => Ok(()),
+        Some(_) if actor.role.can_moderate() => Ok(()),
+        Some(_) => Err(AppError::Forbidden(
+            "Only the creator or a moderator can change this channel".to_string(),
+        )),
+    }
+}
+
+// Deleting a channel deletes the messages in it, and members may only delete
+// their own messages.
+pub fn check_channel_messages(messages: &[message::Model], actor: Actor) -> Result<(), AppError> {
+    let others_live = messages
+        .iter()
+        .any(|message| message.deleted_at.is_none() && message.user_id != Some(actor.id));
+    if others_live && !actor.role.can_moderate() {
+        return Err(AppError::Forbidden(
+            "Only a moderator can delete a channel with messages from others".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_channel_name(name: &str) -> Result<(), AppError> {
+    if name.trim().is_empty() || name.chars().count() > MAX_CHANNEL_NAME_LENGTH {
+        return Err(AppError::Validation(format!(
+            "A channel name must be 1 to {} characters",
+            MAX_CHANNEL_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Leading and trailing whitespace is dropped; nothing left means no topic.
+pub fn channel_topic(topic: Option<&str>) -> Option<String> {
+    topic
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .map(str::to_owned)
+}
+
+pub fn validate_registration(name: &str, password: &str) -> Result<(), AppError> {
+    if name.trim().is_empty() {
+        return Err(AppError::Validation("Name must not be empty".to_string()));
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::Validation(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Emoji are not checked against a list, only kept short and free of spaces.
+pub fn validate_emoji(emoji: &str) -> Result<(), AppError> {
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_EMOJI_LENGTH
+        || emoji.chars().any(char::is_whitespace)
+    {
+        return Err(AppError::Validation(format!(
+            "An emoji must be 1 to {} characters without spaces",
+            MAX_EMOJI_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_token_scopes(scopes: &[Scope]) -> Result<(), AppError> {
+    if scopes.is_empty() {
+        return Err(AppError::Validation(
+            "A token needs at least one scope".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Same message for unknown names and wrong passwords so callers cannot probe
+// which names are registered.
+pub fn invalid_login() -> AppError {
+    AppError::Unauthenticated("Invalid name or password".to_string())
+}
+
+pub fn name_taken() -> AppError {
+    AppError::Conflict("Name is already taken".to_string())
+}
+
+pub fn user_not_found() -> AppError {
+    AppError::NotFound("User not found".to_string())
+}
+
+pub fn message_not_found() -> AppError {
+    AppError::NotFound("Message not found".to_string())
+}
+
+pub fn channel_not_found() -> AppError {
+    AppError::NotFound("Channel not found".to_string())
+}
+
+pub fn channel_name_taken() -> AppError {
+    AppError::Conflict("Channel name is already taken".to_string())
+}
+
+pub fn already_reacted() -> AppError {
+    AppError::Conflict("Already reacted with this emoji".to_string())
+}
+
+pub fn reaction_not_found() -> AppError {
+    AppError::NotFound("Reaction not found".to_string())
+}
@@ -0,0 +1,306 @@
+use crate::auth::password::hash_password;
+use crate::db::database::sync_mentions;
+use crate::db::repository::validate_registration;
+use crate::entity::{message, user};
+use crate::error::AppError;
+use chrono::{DateTime, Duration, SubsecRound, Timelike, Utc};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+    Set, TransactionTrait,
+};
+use std::collections::VecDeque;
+
+// Password of every generated user, so they can log in to try things out.
+pub const SEED_PASSWORD: &str = "password123";
+
+// How much is posted in each hour of the day (UTC), relative to the busiest.
+const HOURLY_ACTIVITY: [f64; 24] = [
+    0.3, 0.2, 0.1, 0.1, 0.1, 0.1, 0.2, 0.4, 0.6, 0.7, 0.8, 0.8, //
+    0.9, 0.8, 0.8, 0.8, 0.8, 0.9, 1.0, 1.0, 1.0, 0.9, 0.7, 0.5,
+];
+
+// Replies come in this many seconds after what they reply to, on average.
+const MEAN_REPLY_DELAY_SECONDS: f64 = 20.0 * 60.0;
+
+const WORDS: [&str; 32] = [
+    "coffee", "deploy", "weekend", "meeting", "idea", "lunch", "release", "bug", "review", "today",
+    "tomorrow", "great", "really", "maybe", "think", "agree", "thanks", "quick", "question",
+    "update", "team", "plan", "later", "again", "fixed", "broken", "nice", "works", "what",
+    "about", "the", "new",
+];
+
+// What to generate. The same options always give the same users, contents,
+// thread shapes and timestamps, so a scenario can be reproduced by passing
+// the seed and the date range again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeedOptions {
+    pub users: u32,
+    // Root posts and replies together.
+    pub messages: u32,
+    // Replies are nested at most this many levels below their root.
+    pub max_depth: u32,
+    // Each message gets up to this many direct replies.
+    pub branching: u32,
+    pub seed: u64,
+    // Messages are posted between these, more of them during the day.
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeedSummary {
+    pub users: u32,
+    pub messages: u32,
+    pub threads: u32,
+}
+
+struct PlannedMessage {
+    // Index of the author in the generated users.
+    author: usize,
+    // Index of the message replied to, which comes earlier in the plan.
+    parent: Option<usize>,
+    content: String,
+    created_at: DateTime<Utc>,
+}
+
+// Creates users named "User 1", "User 2", ... and threads of messages by them,
+// each message stamped with its planned time. Names are validated and
+// mentions recorded the way the repositories do, but nothing is announced to
+// subscribers. It all happens in one transaction, so a seed either completes
+// or leaves nothing behind; if any of the names is taken it stops with a
+// conflict.
+pub async fn seed(db: &DatabaseConnection, options: &SeedOptions) -> Result<SeedSummary, AppError> {
+    if options.users == 0 && options.messages > 0 {
+        return Err(AppError::Validation(
+            "Messages need at least one user".to_string(),
+        ));
+    }
+    if options.start > options.end {
+        return Err(AppError::Validation(
+            "start must not be after end".to_string(),
+        ));
+    }
+
+    let names: Vec<String> = (1..=options.users).map(|i| format!("User {}", i)).collect();
+    let transaction = db.begin().await?;
+    let taken = user::Entity::find()
+        .filter(user::Column::Name.is_in(names.clone()))
+        .count(&transaction)
+        .await?;
+    if taken > 0 {
+        return Err(AppError::Conflict("Sample users already exist".to_string()));
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(options.seed);
+    let (plan, threads) = plan_messages(&mut rng, options);
+
+    let mut user_ids = Vec::with_capacity(names.len());
+    for name in &names {
+        validate_registration(name, SEED_PASSWORD)?;
+        let password_hash =
+            hash_password(SEED_PASSWORD).map_err(|e| AppError::Internal(e.to_string()))?;
+        let user = user::ActiveModel {
+            name: Set(name.clone()),
+            password_hash: Set(Some(password_hash)),
+            ..Default::default()
+        }
+        .insert(&transaction)
+        .await?;
+        user_ids.push(user.id);
+    }
+    let mut message_ids = Vec::with_capacity(plan.len());
+    for planned in plan {
+        let message = message::ActiveModel {
+            user_id: Set(Some(user_ids[planned.author])),
+            content: Set(planned.content.clone()),
+            parent_id: Set(planned.parent.map(|parent| message_ids[parent])),
+            created_at: Set(planned.created_at),
+            updated_at: Set(planned.created_at),
+            ..Default::default()
+        }
+        .insert(&transaction)
+        .await?;
+        sync_mentions(&transaction, message.id, &planned.content).await?;
+        message_ids.push(message.id);
+    }
+    transaction.commit().await?;
+
+    Ok(SeedSummary {
+        users: options.users,
+        messages: message_ids.len() as u32,
+        threads,
+    })
+}
+
+// Starts threads at random times until there are enough messages. Every
+// message below the depth limit gets up to `branching` replies, each a little
+// later than what it replies to; replies that would come after `end` are
+// never written.
+fn plan_messages(rng: &mut ChaCha8Rng, options: &SeedOptions) -> (Vec<PlannedMessage>, u32) {
+    let total = options.messages as usize;
+    let mut plan: Vec<PlannedMessage> = Vec::with_capacity(total);
+    let mut threads = 0;
+    while plan.len() < total {
+        plan.push(PlannedMessage {
+            author: rng.gen_range(0..options.users as usize),
+            parent: None,
+            content: sentence(rng),
+            created_at: posting_time(rng, options.start, options.end),
+        });
+        threads += 1;
+
+        let mut pending = VecDeque::from([(plan.len() - 1, 0)]);
+        while let Some((parent, depth)) = pending.pop_front() {
+            if depth >= options.max_depth {
+                continue;
+            }
+            for _ in 0..rng.gen_range(0..=options.branching) {
+                if plan.len() >= total {
+                    break;
+                }
+                let created_at = reply_time(rng, plan[parent].created_at);
+                if created_at > options.end {
+                    continue;
+                }
+                plan.push(PlannedMessage {
+                    author: rng.gen_range(0..options.users as usize),
+                    parent: Some(parent),
+                    content: sentence(rng),
+                    created_at,
+                });
+                pending.push_back((plan.len() - 1, depth + 1));
+            }
+        }
+    }
+    (plan, threads)
+}
+
+// A time in the range, with night hours less likely than the evening.
+fn posting_time(rng: &mut ChaCha8Rng, start: DateTime<Utc>, end: DateTime<Utc>) -> DateTime<Utc> {
+    let span = (end - start).num_seconds();
+    loop {
+        let time = start + Duration::seconds(rng.gen_range(0..=span));
+        if rng.gen::<f64>() < HOURLY_ACTIVITY[time.hour() as usize] {
+            // At the precision the database keeps.
+            return time.trunc_subsecs(6);
+        }
+    }
+}
+
+// Exponentially distributed delays: most replies are quick, a few come hours
+// later.
+fn reply_time(rng: &mut ChaCha8Rng, after: DateTime<Utc>) -> DateTime<Utc> {
+    let delay = -(1.0 - rng.gen::<f64>()).ln() * MEAN_REPLY_DELAY_SECONDS;
+    after + Duration::seconds(delay.ceil() as i64)
+}
+
+fn sentence(rng: &mut ChaCha8Rng) -> String {
+    let length = rng.gen_range(3..=12);
+    let words: Vec<&str> = (0..length)
+        .map(|_| *WORDS.choose(rng).expect("WORDS is not empty"))
+        .collect();
+    let sentence = words.join(" ");
+    let mut chars = sentence.chars();
+    let first = chars.next().expect("Sentences are not empty");
+    format!("{}{}.", first.to_uppercase(), chars.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+    use sea_orm::QueryOrder;
+    use std::collections::HashMap;
+
+    fn options(seed: u64) -> SeedOptions {
+        let start = "2024-03-01T00:00:00Z".parse().unwrap();
+        SeedOptions {
+            users: 4,
+            messages: 60,
+            max_depth: 2,
+            branching: 3,
+            seed,
+            start,
+            end: start + Duration::days(7),
+        }
+    }
+
+    async fn seeded_messages(options: &SeedOptions) -> Vec<message::Model> {
+        let db = testing::connect("TEST_DATABASE_URL").await;
+        let summary = seed(&db, options).await.unwrap();
+        assert_eq!(summary.users, options.users);
+        assert_eq!(summary.messages, options.messages);
+        message::Entity::find()
+            .order_by_asc(message::Column::Id)
+            .all(&*db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_seed_is_reproducible() {
+        let options = options(7);
+        let messages = seeded_messages(&options).await;
+        assert_eq!(seeded_messages(&options).await, messages);
+
+        let other = seeded_messages(&SeedOptions { seed: 8, ..options }).await;
+        assert_ne!(
+            other.iter().map(|m| &m.content).collect::<Vec<_>>(),
+            messages.iter().map(|m| &m.content).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_seed_respects_shape_and_range() {
+        let options = options(1);
+        let messages = seeded_messages(&options).await;
+        let by_id: HashMap<i32, &message::Model> = messages.iter().map(|m| (m.id, m)).collect();
+        let mut replies: HashMap<i32, u32> = HashMap::new();
+        for message in &messages {
+            assert!(message.created_at >= options.start && message.created_at <= options.end);
+            let mut depth = 0;
+            let mut parent_id = message.parent_id;
+            while let Some(id) = parent_id {
+                let parent = by_id[&id];
+                if depth == 0 {
+                    assert!(parent.created_at <= message.created_at);
+                    *replies.entry(id).or_default() += 1;
+                }
+                depth += 1;
+                parent_id = parent.parent_id;
+            }
+            assert!(depth <= options.max_depth);
+        }
+        assert!(replies.values().all(|count| *count <= options.branching));
+        assert!(!replies.is_empty());
+
+        let db = testing::connect("TEST_DATABASE_URL").await;
+        seed(
+            &db,
+            &SeedOptions {
+                messages: 5,
+                ..options
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            seed(&db, &options).await.unwrap_err(),
+            AppError::Conflict("Sample users already exist".to_string())
+        );
+        assert_eq!(message::Entity::find().count(&*db).await.unwrap(), 5);
+
+        let invalid = SeedOptions {
+            start: options.end,
+            end: options.start,
+            ..options
+        };
+        assert!(matches!(
+            seed(&db, &invalid).await,
+            Err(AppError::Validation(_))
+        ));
+    }
+}
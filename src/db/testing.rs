@@ -0,0 +1,80 @@
+use migration::{Migrator, MigratorTrait};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sea_orm::{ConnectOptions, ConnectionTrait, Database, DatabaseConnection};
+use std::ops::Deref;
+
+// A migrated database of a test's own, so tests can run in parallel without
+// seeing each other's rows. With the `sqlite` feature it is a fresh in-memory
+// database. Otherwise it is a new schema in the Postgres database named by
+// the environment variable passed to `connect`, dropped again along with
+// everything in it when this goes out of scope.
+pub struct TestDb {
+    db: DatabaseConnection,
+    schema: Option<(String, String)>,
+}
+
+impl Deref for TestDb {
+    type Target = DatabaseConnection;
+
+    fn deref(&self) -> &DatabaseConnection {
+        &self.db
+    }
+}
+
+pub async fn connect(url_var: &str) -> TestDb {
+    let (options, schema) = if cfg!(feature = "sqlite") {
+        // SeaORM defaults SQLite pools to one connection, which a test holding
+        // a transaction open would starve. The connections of one pool share
+        // the same in-memory database.
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        options.max_connections(4);
+        (options, None)
+    } else {
+        dotenvy::dotenv().ok();
+        let url = std::env::var(url_var).unwrap_or_else(|_| panic!("{} must be set", url_var));
+        let schema = format!("test_{:016x}", OsRng.next_u64());
+        let db = Database::connect(&url)
+            .await
+            .expect("Failed to connect to test database");
+        db.execute_unprepared(&format!("CREATE SCHEMA \"{}\"", schema))
+            .await
+            .expect("Failed to create test schema");
+        db.close().await.expect("Failed to close connection");
+        let mut options = ConnectOptions::new(url.clone());
+        options.set_schema_search_path(schema.clone());
+        (options, Some((url, schema)))
+    };
+    let db = Database::connect(options)
+        .await
+        .expect("Failed to connect to test database");
+    Migrator::up(&db, None)
+        .await
+        .expect("Failed to apply migrations");
+    TestDb { db, schema }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let Some((url, schema)) = self.schema.take() else {
+            return;
+        };
+        // The test's runtime cannot be blocked on from inside, so the schema
+        // is dropped from a thread and runtime of its own.
+        let cleanup = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to start runtime");
+            runtime.block_on(async {
+                let db = Database::connect(&url).await?;
+                db.execute_unprepared(&format!("DROP SCHEMA \"{}\" CASCADE", schema))
+                    .await?;
+                db.close().await
+            })
+        });
+        if let Ok(Err(e)) = cleanup.join() {
+            eprintln!("Failed to drop test schema: {}", e);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+// A length of time written as a whole number and a unit, e.g. "90s", "30m",
+// "24h", "7d" or "2w". The API, the config file and the command line all
+// take durations in this form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Duration(pub chrono::Duration);
+
+const UNITS: [(char, i64); 5] = [
+    ('w', 7 * 24 * 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('h', 60 * 60),
+    ('m', 60),
+    ('s', 1),
+];
+
+impl Duration {
+    // Durations are always positive, so this cannot fail.
+    pub fn to_std(self) -> std::time::Duration {
+        self.0.to_std().expect("Durations are positive")
+    }
+}
+
+impl FromStr for Duration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid duration \"{}\", expected e.g. \"30m\" or \"24h\"",
+                s
+            )
+        };
+        let unit = s.chars().last().ok_or_else(invalid)?;
+        let (_, seconds) = UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .ok_or_else(invalid)?;
+        let amount = s[..s.len() - 1].parse::<i64>().map_err(|_| invalid())?;
+        if amount <= 0 {
+            return Err(format!("Duration \"{}\" must be positive", s));
+        }
+        amount
+            .checked_mul(*seconds)
+            .and_then(chrono::Duration::try_seconds)
+            .map(Duration)
+            .ok_or_else(|| format!("Duration \"{}\" is too long", s))
+    }
+}
+
+// Written in the largest unit that divides it evenly.
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.num_seconds();
+        let (unit, size) = UNITS
+            .iter()
+            .find(|(_, size)| seconds % size == 0)
+            .unwrap_or(&('s', 1));
+        write!(f, "{}{}", seconds / size, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        for (text, seconds) in [("90s", 90), ("30m", 1800), ("36h", 129600), ("2w", 1209600)] {
+            let duration: Duration = text.parse().unwrap();
+            assert_eq!(duration.0.num_seconds(), seconds);
+            assert_eq!(duration.to_string(), text);
+        }
+        assert_eq!("60m".parse::<Duration>().unwrap().to_string(), "1h");
+        for text in ["", "5", "5x", "h", "0h", "-1d", "99999999999999w"] {
+            assert!(text.parse::<Duration>().is_err(), "{:?} parsed", text);
+        }
+    }
+}
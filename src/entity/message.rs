@@ -1,18 +1,30 @@
-use crate::entity::user;
+use crate::entity::{channel, user};
 use chrono::DateTime;
 use chrono::Utc;
 use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "message")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
-    pub user_id: i32,
+    // None once the author's account is deleted. Their messages stay behind
+    // as tombstones, so replies from others keep their place.
+    pub user_id: Option<i32>,
     pub content: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub parent_id: Option<i32>,
+    // Set when a moderator hides the message; its content is masked for others.
+    pub hidden_at: Option<DateTime<Utc>>,
+    // Set when the message is deleted. The row stays as a tombstone so the
+    // replies below it keep their place; its content and author are no
+    // longer shown.
+    pub deleted_at: Option<DateTime<Utc>>,
+    // None for messages outside of any channel. Replies are always in the
+    // channel of their parent.
+    pub channel_id: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -22,9 +34,17 @@ pub enum Relation {
         from = "Column::UserId",
         to = "user::Column::Id",
         on_update = "Cascade",
-        on_delete = "Cascade"
+        on_delete = "SetNull"
     )]
     User,
+    #[sea_orm(
+        belongs_to = "channel::Entity",
+        from = "Column::ChannelId",
+        to = "channel::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Channel,
     #[sea_orm(has_many = "Entity", from = "Column::Id", to = "Column::ParentId")]
     Children,
     #[sea_orm(belongs_to = "Entity", from = "Column::ParentId", to = "Column::Id")]
@@ -37,6 +57,12 @@ impl Related<user::Entity> for Entity {
     }
 }
 
+impl Related<channel::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Channel.def()
+    }
+}
+
 impl Related<Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Parent.def()
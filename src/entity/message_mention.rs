@@ -0,0 +1,51 @@
+use crate::entity::{message, user};
+use chrono::DateTime;
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// A user named as `@name` in the current content of a message, once per
+// message however often the name appears.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "message_mention")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub message_id: i32,
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "message::Entity",
+        from = "Column::MessageId",
+        to = "message::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Message,
+    #[sea_orm(
+        belongs_to = "user::Entity",
+        from = "Column::UserId",
+        to = "user::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    User,
+}
+
+impl Related<message::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Message.def()
+    }
+}
+
+impl Related<user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,41 @@
+use crate::entity::message;
+use chrono::DateTime;
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// An earlier version of a message, saved when an edit replaced it.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "message_revision")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub message_id: i32,
+    // 0 for the original content, 1 for what the first edit wrote and so on.
+    // The message itself holds the latest revision, numbered by how many
+    // edits it has had.
+    pub revision: i32,
+    pub content: String,
+    // When this version was written.
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "message::Entity",
+        from = "Column::MessageId",
+        to = "message::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Message,
+}
+
+impl Related<message::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Message.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
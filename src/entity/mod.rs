@@ -1,2 +1,7 @@
+pub mod channel;
 pub mod message;
+pub mod message_mention;
+pub mod message_reaction;
+pub mod message_revision;
+pub mod personal_access_token;
 pub mod user;
@@ -1,18 +1,60 @@
-use crate::entity::message;
+use crate::entity::{message, personal_access_token};
+use async_graphql::Enum;
 use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Enum, Serialize, Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[sea_orm(string_value = "member")]
+    Member,
+    #[sea_orm(string_value = "moderator")]
+    Moderator,
+    #[sea_orm(string_value = "admin")]
+    Admin,
+}
+
+impl Role {
+    // Moderators can delete or hide any message; admins can also manage users.
+    pub fn can_moderate(&self) -> bool {
+        matches!(self, Role::Moderator | Role::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Role::Admin)
+    }
+}
+
+// Parses the names stored in the database, e.g. "moderator".
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::try_from_value(&s.to_owned())
+            .map_err(|_| "expected member, moderator or admin".to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "user")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     pub name: String,
+    pub password_hash: Option<String>,
+    pub role: Role,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "message::Entity")]
     Message,
+    #[sea_orm(has_many = "personal_access_token::Entity")]
+    PersonalAccessToken,
 }
 
 impl Related<message::Entity> for Entity {
@@ -21,4 +63,10 @@ impl Related<message::Entity> for Entity {
     }
 }
 
+impl Related<personal_access_token::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PersonalAccessToken.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
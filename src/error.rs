@@ -0,0 +1,109 @@
+use async_graphql::ErrorExtensions;
+use sea_orm::{DbErr, SqlErr};
+
+// Everything that can go wrong while serving a request. Each kind has a
+// stable code that clients can match on, exposed as `extensions.code` on
+// GraphQL errors.
+//
+// This deliberately does not implement `Display`: async-graphql converts any
+// `Display` type into an error with its text as the message, which would
+// bypass the code and leak internal details. `?` in resolvers goes through
+// the `From` impl below instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppError {
+    NotFound(String),
+    Validation(String),
+    Conflict(String),
+    Forbidden(String),
+    Unauthenticated(String),
+    // The detail is for the server log only.
+    Internal(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "NOT_FOUND",
+            AppError::Validation(_) => "VALIDATION",
+            AppError::Conflict(_) => "CONFLICT",
+            AppError::Forbidden(_) => "FORBIDDEN",
+            AppError::Unauthenticated(_) => "UNAUTHENTICATED",
+            AppError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    // What the client is told.
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Validation(message)
+            | AppError::Conflict(message)
+            | AppError::Forbidden(message)
+            | AppError::Unauthenticated(message) => message,
+            AppError::Internal(_) => "Internal server error",
+        }
+    }
+
+    // Problems with the request that the client can show or fix, such as a
+    // wrong password, as opposed to being refused or the server failing.
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, AppError::Forbidden(_) | AppError::Internal(_))
+    }
+}
+
+impl From<DbErr> for AppError {
+    fn from(err: DbErr) -> Self {
+        match err.sql_err() {
+            Some(SqlErr::UniqueConstraintViolation(_)) => {
+                AppError::Conflict("Already exists".to_string())
+            }
+            Some(SqlErr::ForeignKeyConstraintViolation(_)) => {
+                AppError::NotFound("Referenced record not found".to_string())
+            }
+            _ => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl ErrorExtensions for AppError {
+    fn extend(&self) -> async_graphql::Error {
+        if let AppError::Internal(detail) = self {
+            tracing::error!("Internal error: {}", detail);
+        }
+        async_graphql::Error::new(self.message()).extend_with(|_, e| e.set("code", self.code()))
+    }
+}
+
+impl From<AppError> for async_graphql::Error {
+    fn from(err: AppError) -> Self {
+        err.extend()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_errors_are_not_leaked() {
+        let error: async_graphql::Error =
+            AppError::from(DbErr::Custom("connection reset by peer".to_string())).into();
+        assert_eq!(error.message, "Internal server error");
+        let extensions = error.extensions.expect("Missing extensions");
+        assert_eq!(
+            extensions.get("code"),
+            Some(&async_graphql::Value::from("INTERNAL"))
+        );
+    }
+
+    #[test]
+    fn test_codes_are_exposed() {
+        let error: async_graphql::Error = AppError::NotFound("User not found".to_string()).into();
+        assert_eq!(error.message, "User not found");
+        let extensions = error.extensions.expect("Missing extensions");
+        assert_eq!(
+            extensions.get("code"),
+            Some(&async_graphql::Value::from("NOT_FOUND"))
+        );
+    }
+}
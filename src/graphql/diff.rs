@@ -0,0 +1,175 @@
+use async_graphql::{Enum, SimpleObject};
+
+// Beyond this many word pairs left to compare after trimming the common
+// start and end, the middle is reported as replaced wholesale rather than
+// spending quadratic time and memory on it.
+const MAX_COMPARISONS: usize = 1 << 20;
+
+#[derive(Enum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiffOperation {
+    Equal,
+    Insert,
+    Delete,
+}
+
+// A run of text that both versions share, or that only one of them has.
+// Putting the `EQUAL` and `DELETE` chunks together gives the old text, the
+// `EQUAL` and `INSERT` chunks the new one.
+#[derive(SimpleObject, Clone, Debug, PartialEq, Eq)]
+pub struct DiffChunk {
+    pub operation: DiffOperation,
+    pub text: String,
+}
+
+// Compares word by word. Each word carries the whitespace after it, so
+// unrelated texts do not line up on their spaces.
+pub fn diff_words(old: &str, new: &str) -> Vec<DiffChunk> {
+    let old = tokenize(old);
+    let new = tokenize(new);
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut chunks = Vec::new();
+    push_all(&mut chunks, DiffOperation::Equal, &old[..prefix]);
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_COMPARISONS {
+        push_all(&mut chunks, DiffOperation::Delete, old_middle);
+        push_all(&mut chunks, DiffOperation::Insert, new_middle);
+    } else {
+        diff_middle(&mut chunks, old_middle, new_middle);
+    }
+    push_all(
+        &mut chunks,
+        DiffOperation::Equal,
+        &old[old.len() - suffix..],
+    );
+    chunks
+}
+
+fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut after_whitespace = false;
+    for (i, c) in text.char_indices() {
+        let whitespace = c.is_whitespace();
+        if after_whitespace && !whitespace {
+            tokens.push(&text[start..i]);
+            start = i;
+        }
+        after_whitespace = whitespace;
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+// Longest common subsequence, walked from the front so deletions come before
+// insertions where both are possible.
+fn diff_middle(chunks: &mut Vec<DiffChunk>, old: &[&str], new: &[&str]) {
+    let width = new.len() + 1;
+    // `common[i * width + j]` is the length of the LCS of old[i..] and new[j..].
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            push(chunks, DiffOperation::Equal, old[i]);
+            i += 1;
+            j += 1;
+        } else if j == new.len()
+            || (i < old.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            push(chunks, DiffOperation::Delete, old[i]);
+            i += 1;
+        } else {
+            push(chunks, DiffOperation::Insert, new[j]);
+            j += 1;
+        }
+    }
+}
+
+fn push_all(chunks: &mut Vec<DiffChunk>, operation: DiffOperation, tokens: &[&str]) {
+    for token in tokens {
+        push(chunks, operation, token);
+    }
+}
+
+// Appends to the last chunk when it has the same operation.
+fn push(chunks: &mut Vec<DiffChunk>, operation: DiffOperation, token: &str) {
+    match chunks.last_mut() {
+        Some(last) if last.operation == operation => last.text.push_str(token),
+        _ => chunks.push(DiffChunk {
+            operation,
+            text: token.to_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn side(chunks: &[DiffChunk], skip: DiffOperation) -> String {
+        chunks
+            .iter()
+            .filter(|chunk| chunk.operation != skip)
+            .map(|chunk| chunk.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_words() {
+        let chunks = diff_words("Lunch at noon, see you", "Lunch at one, see you  there");
+        let summary: Vec<(DiffOperation, &str)> = chunks
+            .iter()
+            .map(|chunk| (chunk.operation, chunk.text.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (DiffOperation::Equal, "Lunch at "),
+                (DiffOperation::Delete, "noon, "),
+                (DiffOperation::Insert, "one, "),
+                (DiffOperation::Equal, "see "),
+                (DiffOperation::Delete, "you"),
+                (DiffOperation::Insert, "you  there"),
+            ]
+        );
+
+        assert!(diff_words("", "").is_empty());
+        assert_eq!(
+            diff_words("same", "same"),
+            vec![DiffChunk {
+                operation: DiffOperation::Equal,
+                text: "same".to_string(),
+            }]
+        );
+
+        // Either side can be put back together from the chunks.
+        let old = "the quick brown fox jumps over the lazy dog";
+        let new = "a quick red fox jumped over lazy  dogs";
+        let chunks = diff_words(old, new);
+        assert_eq!(side(&chunks, DiffOperation::Insert), old);
+        assert_eq!(side(&chunks, DiffOperation::Delete), new);
+    }
+}
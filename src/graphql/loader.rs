@@ -0,0 +1,165 @@
+use crate::db::repository::{
+    ChannelRepository, MessageRepository, ReactionCount, ReactionRepository, UserRepository,
+};
+use crate::entity::{channel, message_revision, user};
+use crate::error::AppError;
+use async_graphql::dataloader::{DataLoader, Loader};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Batches `Message.user` lookups, so a page of messages costs one user query
+// no matter how many authors it has.
+pub struct UserLoader {
+    users: Arc<dyn UserRepository>,
+}
+
+impl UserLoader {
+    pub fn new(users: Arc<dyn UserRepository>) -> Self {
+        Self { users }
+    }
+}
+
+impl Loader<i32> for UserLoader {
+    type Value = user::Model;
+    type Error = AppError;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+        let users = self.users.get_many(keys).await?;
+        Ok(users.into_iter().map(|u| (u.id, u)).collect())
+    }
+}
+
+// Loaders cache what they load, so a fresh one is created for every request.
+pub fn user_loader(users: Arc<dyn UserRepository>) -> DataLoader<UserLoader> {
+    DataLoader::new(UserLoader::new(users), tokio::spawn)
+}
+
+// Batches `Message.channel` the same way.
+pub struct ChannelLoader {
+    channels: Arc<dyn ChannelRepository>,
+}
+
+impl ChannelLoader {
+    pub fn new(channels: Arc<dyn ChannelRepository>) -> Self {
+        Self { channels }
+    }
+}
+
+impl Loader<i32> for ChannelLoader {
+    type Value = channel::Model;
+    type Error = AppError;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+        let channels = self.channels.get_many(keys).await?;
+        Ok(channels.into_iter().map(|c| (c.id, c)).collect())
+    }
+}
+
+pub fn channel_loader(channels: Arc<dyn ChannelRepository>) -> DataLoader<ChannelLoader> {
+    DataLoader::new(ChannelLoader::new(channels), tokio::spawn)
+}
+
+// Batches `Message.revisions` and `Message.editCount` the same way. Messages
+// that were never edited map to no entry.
+pub struct RevisionLoader {
+    messages: Arc<dyn MessageRepository>,
+}
+
+impl RevisionLoader {
+    pub fn new(messages: Arc<dyn MessageRepository>) -> Self {
+        Self { messages }
+    }
+}
+
+impl Loader<i32> for RevisionLoader {
+    type Value = Vec<message_revision::Model>;
+    type Error = AppError;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+        let mut revisions: HashMap<i32, Self::Value> = HashMap::new();
+        for revision in self.messages.revisions(keys).await? {
+            revisions
+                .entry(revision.message_id)
+                .or_default()
+                .push(revision);
+        }
+        Ok(revisions)
+    }
+}
+
+pub fn revision_loader(messages: Arc<dyn MessageRepository>) -> DataLoader<RevisionLoader> {
+    DataLoader::new(RevisionLoader::new(messages), tokio::spawn)
+}
+
+// Batches `Message.mentions`. Messages that mention no one map to no entry.
+pub struct MentionLoader {
+    messages: Arc<dyn MessageRepository>,
+}
+
+impl MentionLoader {
+    pub fn new(messages: Arc<dyn MessageRepository>) -> Self {
+        Self { messages }
+    }
+}
+
+impl Loader<i32> for MentionLoader {
+    type Value = Vec<user::Model>;
+    type Error = AppError;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+        let mut mentions: HashMap<i32, Self::Value> = HashMap::new();
+        for (message_id, user) in self.messages.mentions(keys).await? {
+            mentions.entry(message_id).or_default().push(user);
+        }
+        Ok(mentions)
+    }
+}
+
+pub fn mention_loader(messages: Arc<dyn MessageRepository>) -> DataLoader<MentionLoader> {
+    DataLoader::new(MentionLoader::new(messages), tokio::spawn)
+}
+
+// Batches `Message.reactions`. Keys pair the message id with the viewer, if
+// any, whose own reactions are flagged. Each message's emoji come most used
+// first.
+pub struct ReactionLoader {
+    reactions: Arc<dyn ReactionRepository>,
+}
+
+impl ReactionLoader {
+    pub fn new(reactions: Arc<dyn ReactionRepository>) -> Self {
+        Self { reactions }
+    }
+}
+
+impl Loader<(i32, Option<i32>)> for ReactionLoader {
+    type Value = Vec<ReactionCount>;
+    type Error = AppError;
+
+    async fn load(
+        &self,
+        keys: &[(i32, Option<i32>)],
+    ) -> Result<HashMap<(i32, Option<i32>), Self::Value>, Self::Error> {
+        let mut by_viewer: HashMap<Option<i32>, Vec<i32>> = HashMap::new();
+        for (message_id, viewer_id) in keys {
+            by_viewer.entry(*viewer_id).or_default().push(*message_id);
+        }
+        let mut loaded: HashMap<(i32, Option<i32>), Self::Value> = HashMap::new();
+        for (viewer_id, message_ids) in by_viewer {
+            for count in self.reactions.counts(&message_ids, viewer_id).await? {
+                loaded
+                    .entry((count.message_id, viewer_id))
+                    .or_default()
+                    .push(count);
+            }
+        }
+        for counts in loaded.values_mut() {
+            counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.emoji.cmp(&b.emoji)));
+        }
+        Ok(loaded)
+    }
+}
+
+pub fn reaction_loader(reactions: Arc<dyn ReactionRepository>) -> DataLoader<ReactionLoader> {
+    DataLoader::new(ReactionLoader::new(reactions), tokio::spawn)
+}
@@ -1,2 +1,4 @@
+pub mod diff;
+pub mod loader;
 pub mod schema;
 pub mod types;
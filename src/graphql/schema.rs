@@ -1,19 +1,84 @@
-use async_graphql::{Context, FieldError, FieldResult, Object, Schema, SimpleObject, ID};
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{Context, FieldResult, Object, Schema, SimpleObject, Subscription, ID};
 use chrono::prelude::*;
-use sea_orm::DatabaseConnection;
+use futures::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
 
-use crate::db::database::{
-    handle_message_action, handle_user_action, DatabaseAction, MessageAction, UserAction,
+use crate::auth::guard::{RoleGuard, ScopeGuard, SessionGuard};
+use crate::auth::middleware::CurrentUser;
+use crate::auth::personal_access_token::{DEFAULT_EXPIRY_DAYS, MAX_EXPIRY_DAYS};
+use crate::auth::scope::Scope;
+use crate::auth::token::TokenKeys;
+use crate::db::events::{self, MessageEvent};
+use crate::db::repository::{
+    channel_not_found, message_not_found, user_not_found, ChannelChanges, Repositories, ThreadEntry,
+};
+use crate::entity::channel::Visibility;
+use crate::entity::user::Role;
+use crate::error::AppError;
+use crate::graphql::diff::{diff_words, DiffChunk};
+use crate::graphql::loader::UserLoader;
+use crate::graphql::types::{
+    message_connection, Channel, DeletedMessage, Duration, Message, MessageConnection,
+    PersonalAccessToken, ThreadNode, User, MAX_THREAD_DEPTH,
 };
-use crate::graphql::types::{Message, User};
 
 pub struct MyContext {
-    db: DatabaseConnection,
+    pub repos: Repositories,
 }
 
 impl MyContext {
-    pub fn new(db: DatabaseConnection) -> Self {
-        Self { db }
+    pub fn new(repos: Repositories) -> Self {
+        Self { repos }
+    }
+}
+
+// The caller identified by the bearer token, or an error for anonymous requests.
+fn current_user<'a>(ctx: &'a Context<'_>) -> Result<&'a CurrentUser, AppError> {
+    ctx.data_opt::<CurrentUser>()
+        .ok_or_else(|| AppError::Unauthenticated("Authentication required".to_string()))
+}
+
+fn parse_id(id: &ID) -> Result<i32, AppError> {
+    id.parse()
+        .map_err(|_| AppError::Validation(format!("Invalid ID: {}", id.as_str())))
+}
+
+// The start of a time range query, given directly or as `within` before the
+// end. `within` counts back from `end`, or from now when there is no end.
+fn range_start(
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    within: Option<Duration>,
+) -> Result<Option<DateTime<Utc>>, AppError> {
+    let start = match (start, within) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::Validation(
+                "Pass either start or within, not both".to_string(),
+            ))
+        }
+        (start, None) => start,
+        (None, Some(Duration(within))) => Some(end.unwrap_or_else(Utc::now) - within),
+    };
+    if let (Some(start), Some(end)) = (start, end) {
+        if start > end {
+            return Err(AppError::Validation(
+                "start must not be after end".to_string(),
+            ));
+        }
+    }
+    Ok(start)
+}
+
+// Thread queries already join the authors, so hand them to the loader
+// instead of having `Message.user` fetch them again.
+async fn prime_authors(ctx: &Context<'_>, entries: &[ThreadEntry]) {
+    if let Some(loader) = ctx.data_opt::<DataLoader<UserLoader>>() {
+        let users = entries
+            .iter()
+            .filter_map(|entry| entry.user.clone())
+            .map(|user| (user.id, user));
+        loader.feed_many(users).await;
     }
 }
 
@@ -21,256 +86,798 @@ pub struct QueryRoot;
 
 #[Object]
 impl QueryRoot {
+    #[graphql(guard = "ScopeGuard::new(Scope::UsersRead)")]
     pub async fn get_user(&self, ctx: &Context<'_>, id: ID) -> FieldResult<User> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let user_id = id.parse::<i32>()?;
-        let action_result = handle_user_action(&db, UserAction::Get(user_id)).await?;
-
-        match action_result {
-            DatabaseAction::User(user) => Ok(User {
-                id: ID(user.id.to_string()),
-                name: user.name,
-            }),
-            DatabaseAction::Failure(message) => Err(async_graphql::Error::new(message)),
-            _ => Err(async_graphql::Error::new("Unexpected database action")),
-        }
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = parse_id(&id)?;
+        let user = repos.users.get(user_id).await?.ok_or_else(user_not_found)?;
+        Ok(user.into())
     }
 
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
     pub async fn get_message(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Option<Message>> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let message_id = id.parse::<i32>()?;
-        let message = handle_message_action(&db, MessageAction::Get(message_id)).await?;
-
-        match message {
-            DatabaseAction::Message(message) => Ok(Some(Message {
-                id: ID(message.id.to_string()),
-                user_id: ID(message.user_id.to_string()),
-                content: message.content,
-                created_at: message.created_at,
-                updated_at: message.updated_at,
-                parent_id: message.parent_id,
-                user: User {
-                    id,
-                    name: "".to_string(),
-                },
-            })),
-            DatabaseAction::Failure(message) => Err(async_graphql::Error::new(message)),
-            _ => Ok(None),
-        }
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let message_id = parse_id(&id)?;
+        let message = repos.messages.get(message_id).await?;
+        Ok(Some(message.ok_or_else(message_not_found)?.into()))
     }
 
     // Resolver for fetching all messages for a specific user
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
     pub async fn get_all_messages_for_user(
         &self,
         ctx: &Context<'_>,
         user_id: ID,
-    ) -> FieldResult<Vec<Message>> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let uid = user_id.parse::<i32>()?;
-        let messages = handle_message_action(&db, MessageAction::GetAllForUser(uid)).await?;
-        match messages {
-            DatabaseAction::Messages(messages) => Ok(messages
-                .into_iter()
-                .map(|msg| Message {
-                    id: ID(msg.id.to_string()),
-                    user_id: ID(msg.user_id.to_string()),
-                    content: msg.content,
-                    created_at: msg.created_at,
-                    updated_at: msg.updated_at,
-                    parent_id: msg.parent_id,
-                    user: User {
-                        id: Default::default(),
-                        name: "".to_string(),
-                    },
-                })
-                .collect()),
-            _ => Err(async_graphql::Error::new("Failed to fetch messages")),
-        }
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> FieldResult<MessageConnection> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let uid = parse_id(&user_id)?;
+        message_connection(after, before, first, last, |page| {
+            repos.messages.list_for_user(uid, page)
+        })
+        .await
+    }
+
+    // Messages that mention the user as `@name`, oldest first.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    pub async fn mentions_of(
+        &self,
+        ctx: &Context<'_>,
+        user_id: ID,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> FieldResult<MessageConnection> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let uid = parse_id(&user_id)?;
+        message_connection(after, before, first, last, |page| {
+            repos.messages.list_mentioning(uid, page)
+        })
+        .await
     }
 
+    // Either bound may be left out. `within` counts back from `end`, or from
+    // now when there is no end, e.g. `within: "24h"` for the last day.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
     pub async fn get_messages_in_time_range_for_user(
         &self,
         ctx: &Context<'_>,
         user_id: ID,
-        start: String,
-        end: String,
-    ) -> FieldResult<Vec<Message>> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let uid = user_id.parse::<i32>()?;
-        let start = DateTime::parse_from_rfc3339(&start)
-            .map_err(|e| async_graphql::Error::new(format!("Invalid start datetime: {}", e)))?
-            .with_timezone(&Utc);
-        let end = DateTime::parse_from_rfc3339(&end)
-            .map_err(|e| async_graphql::Error::new(format!("Invalid end datetime: {}", e)))?
-            .with_timezone(&Utc);
-        let result =
-            handle_message_action(&db, MessageAction::GetInTimeRangeForUser(uid, start, end))
-                .await?;
-
-        match result {
-            DatabaseAction::Messages(messages) => Ok(messages
-                .into_iter()
-                .map(|msg| Message {
-                    id: ID(msg.id.to_string()),
-                    user_id: ID(msg.user_id.to_string()),
-                    content: msg.content,
-                    created_at: msg.created_at,
-                    updated_at: msg.updated_at,
-                    parent_id: msg.parent_id,
-                    user: User {
-                        id: Default::default(),
-                        name: "".to_string(),
-                    },
-                })
-                .collect()),
-            _ => Err(async_graphql::Error::new("Failed to fetch messages")),
-        }
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        within: Option<Duration>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> FieldResult<MessageConnection> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let uid = parse_id(&user_id)?;
+        let start = range_start(start, end, within)?;
+        message_connection(after, before, first, last, |page| {
+            repos.messages.list_in_time_range(uid, start, end, page)
+        })
+        .await
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn channel(&self, ctx: &Context<'_>, id: ID) -> FieldResult<Channel> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let channel_id = parse_id(&id)?;
+        let channel = repos
+            .channels
+            .get(channel_id)
+            .await?
+            .ok_or_else(channel_not_found)?;
+        Ok(channel.into())
+    }
+
+    // Public channels by name. Unlisted ones are only found by id.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn channels(&self, ctx: &Context<'_>) -> FieldResult<Vec<Channel>> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let channels = repos.channels.list_public().await?;
+        Ok(channels.into_iter().map(Channel::from).collect())
+    }
+
+    // Messages and replies posted to a channel. The range works as for
+    // getMessagesInTimeRangeForUser.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn messages_in_channel(
+        &self,
+        ctx: &Context<'_>,
+        channel_id: ID,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        within: Option<Duration>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> FieldResult<MessageConnection> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let channel_id = parse_id(&channel_id)?;
+        repos
+            .channels
+            .get(channel_id)
+            .await?
+            .ok_or_else(channel_not_found)?;
+        let start = range_start(start, end, within)?;
+        message_connection(after, before, first, last, |page| {
+            repos.messages.list_in_channel(channel_id, start, end, page)
+        })
+        .await
     }
 
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
     async fn get_message_thread(
         &self,
         ctx: &Context<'_>,
         message_id: i32,
     ) -> FieldResult<Vec<Message>> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let messages =
-            handle_message_action(&db, MessageAction::GetMessagesInThread(message_id)).await?;
-
-        match messages {
-            DatabaseAction::MessageThread(messages) => {
-                let mut result_messages = Vec::new();
-
-                for (msg, user) in messages {
-                    let user_info = if let Some(user) = user {
-                        User {
-                            id: ID(user.id.to_string()),
-                            name: user.name,
-                        }
-                    } else {
-                        User {
-                            id: Default::default(),
-                            name: "".to_string(),
-                        }
-                    };
-
-                    let message = Message {
-                        id: ID(msg.id.to_string()),
-                        user_id: ID(msg.user_id.to_string()),
-                        content: msg.content,
-                        created_at: msg.created_at,
-                        updated_at: msg.updated_at,
-                        parent_id: msg.parent_id,
-                        user: user_info,
-                    };
-
-                    result_messages.push(message);
-                }
-
-                Ok(result_messages)
-            }
-            _ => Err(async_graphql::Error::new("Failed to fetch messages")),
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let entries = repos.messages.thread(message_id, None).await?;
+        prime_authors(ctx, &entries).await;
+        Ok(entries
+            .into_iter()
+            .map(|entry| Message::from(entry.message))
+            .collect())
+    }
+
+    // The thread below `root_id` as a tree, at most `max_depth` reply levels
+    // deep (capped at MAX_THREAD_DEPTH).
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn thread(
+        &self,
+        ctx: &Context<'_>,
+        root_id: ID,
+        max_depth: Option<i32>,
+    ) -> FieldResult<ThreadNode> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let root_id = parse_id(&root_id)?;
+        let max_depth = max_depth
+            .unwrap_or(MAX_THREAD_DEPTH)
+            .clamp(0, MAX_THREAD_DEPTH);
+        // Load one level more than is returned to tell whether the tree goes on.
+        let entries = repos
+            .messages
+            .thread(root_id, Some(max_depth as u32 + 1))
+            .await?;
+        prime_authors(ctx, &entries).await;
+        let messages = entries.into_iter().map(|entry| entry.message).collect();
+        ThreadNode::build(messages, max_depth)
+            .ok_or_else(|| AppError::NotFound("Root message not found".to_string()).into())
+    }
+
+    // What changed between two revisions of a message, numbered as in
+    // `Message.revisions`.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn message_revision_diff(
+        &self,
+        ctx: &Context<'_>,
+        message_id: ID,
+        from: i32,
+        to: i32,
+    ) -> FieldResult<Vec<DiffChunk>> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let message_id = parse_id(&message_id)?;
+        let message = repos
+            .messages
+            .get(message_id)
+            .await?
+            .filter(|message| message.deleted_at.is_none())
+            .ok_or_else(message_not_found)?;
+        let message = Message::from(message);
+        if !message.content_visible(ctx) {
+            return Err(AppError::Forbidden(
+                "Only moderators and the author can see revisions of a hidden message".to_string(),
+            )
+            .into());
         }
+        let versions = message.versions(ctx).await?;
+        let content = |revision: i32| {
+            usize::try_from(revision)
+                .ok()
+                .and_then(|revision| versions.get(revision))
+                .map(|version| version.content.as_str())
+                .ok_or_else(|| {
+                    AppError::Validation(format!("The message has no revision {}", revision))
+                })
+        };
+        Ok(diff_words(content(from)?, content(to)?))
+    }
+
+    #[graphql(guard = "SessionGuard")]
+    async fn list_tokens(&self, ctx: &Context<'_>) -> FieldResult<Vec<PersonalAccessToken>> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let tokens = repos.tokens.list(user_id).await?;
+        Ok(tokens.into_iter().map(PersonalAccessToken::from).collect())
     }
 }
 
-pub type MySchema = Schema<QueryRoot, MutationRoot, async_graphql::EmptySubscription>;
+pub type MySchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
 // MutationRoot for creating user
 pub struct MutationRoot;
 
-#[derive(SimpleObject)]
-pub struct MutationResponse {
-    pub success: bool,
+// A problem with the input that the client can show or fix, such as a
+// taken name or a message that no longer exists. Permission and server
+// failures are still reported as GraphQL errors.
+#[derive(SimpleObject, Clone, Debug, PartialEq, Eq)]
+pub struct UserError {
     pub message: String,
+    // Same codes as `extensions.code` on GraphQL errors.
+    pub code: String,
+    // The argument the error is about, if any.
+    pub field: Option<String>,
+}
+
+impl UserError {
+    fn for_field(field: &str, error: AppError) -> Self {
+        Self {
+            field: Some(field.to_owned()),
+            ..error.into()
+        }
+    }
 }
 
-async fn handle_database_action(result: DatabaseAction) -> FieldResult<MutationResponse> {
+impl From<AppError> for UserError {
+    fn from(error: AppError) -> Self {
+        Self {
+            message: error.message().to_owned(),
+            code: error.code().to_owned(),
+            field: None,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct AuthPayload {
+    pub token: Option<String>,
+    pub user: Option<User>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct CreateTokenPayload {
+    // The raw token. It is not stored and cannot be retrieved again.
+    pub token: Option<String>,
+    pub personal_access_token: Option<PersonalAccessToken>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct RevokeTokenPayload {
+    pub revoked_token_id: Option<ID>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct CreateUserPayload {
+    pub user: Option<User>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct RegisterPayload {
+    pub user: Option<User>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct UpdateUserPayload {
+    pub user: Option<User>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct SetUserRolePayload {
+    pub user: Option<User>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct DeleteUserPayload {
+    pub deleted_user_id: Option<ID>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct CreateMessagePayload {
+    pub message: Option<Message>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct UpdateMessagePayload {
+    pub message: Option<Message>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct HideMessagePayload {
+    pub message: Option<Message>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct AddReactionPayload {
+    pub message: Option<Message>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct RemoveReactionPayload {
+    pub message: Option<Message>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct CreateChannelPayload {
+    pub channel: Option<Channel>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct UpdateChannelPayload {
+    pub channel: Option<Channel>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct DeleteChannelPayload {
+    pub deleted_channel_id: Option<ID>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct DeleteMessagePayload {
+    pub deleted_message: Option<DeletedMessage>,
+    pub errors: Vec<UserError>,
+}
+
+#[derive(SimpleObject)]
+pub struct PurgeMessagePayload {
+    pub purged_message: Option<DeletedMessage>,
+    pub errors: Vec<UserError>,
+}
+
+// Splits the result of a write into the affected object and the errors for
+// its payload. Errors the client can act on become user errors; anything
+// else surfaces as a GraphQL error.
+fn payload_parts<T, U>(
+    result: Result<T, AppError>,
+    affected: impl FnOnce(T) -> U,
+) -> FieldResult<(Option<U>, Vec<UserError>)> {
     match result {
-        DatabaseAction::Success => Ok(MutationResponse {
-            success: true,
-            message: "Action succeeded".to_string(),
-        }),
-        DatabaseAction::Failure(message) => Err(FieldError::new(message)),
-        DatabaseAction::User(_) => Ok(MutationResponse {
-            success: true,
-            message: "User action succeeded".to_string(),
-        }),
-        DatabaseAction::Message(_) => Ok(MutationResponse {
-            success: true,
-            message: "Message action succeeded".to_string(),
-        }),
-        DatabaseAction::Messages(_) => Ok(MutationResponse {
-            success: true,
-            message: "Messages action succeeded".to_string(),
-        }),
-        DatabaseAction::MessageThread(_) => Ok(MutationResponse {
-            success: true,
-            message: "Message thread action succeeded".to_string(),
-        }),
+        Ok(value) => Ok((Some(affected(value)), Vec::new())),
+        Err(error) if error.is_user_error() => Ok((None, vec![error.into()])),
+        Err(error) => Err(error.into()),
     }
 }
 
 #[Object]
 impl MutationRoot {
+    #[graphql(guard = "ScopeGuard::new(Scope::UsersAdmin).and(RoleGuard::new(Role::Admin))")]
     pub async fn create_user(
         &self,
         ctx: &Context<'_>,
         name: String,
-    ) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let result = handle_user_action(&db, UserAction::Create(name)).await?;
-        handle_database_action(result).await
+    ) -> FieldResult<CreateUserPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let result = repos.users.create(&name).await;
+        let (user, errors) = payload_parts(result, User::from)?;
+        Ok(CreateUserPayload { user, errors })
     }
 
+    pub async fn register(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        password: String,
+    ) -> FieldResult<RegisterPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let result = repos.users.register(&name, &password).await;
+        let (user, errors) = payload_parts(result, User::from)?;
+        Ok(RegisterPayload { user, errors })
+    }
+
+    pub async fn login(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        password: String,
+    ) -> FieldResult<AuthPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let keys = ctx.data_unchecked::<TokenKeys>();
+        let result = repos.users.login(&name, &password).await;
+        let (user, errors) = payload_parts(result, |user| user)?;
+        Ok(AuthPayload {
+            token: user.as_ref().map(|user| keys.issue(user.id)).transpose()?,
+            user: user.map(User::from),
+            errors,
+        })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::UsersAdmin)")]
     pub async fn update_user(
         &self,
         ctx: &Context<'_>,
         id: ID,
         name: String,
-    ) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let user_id = id.parse::<i32>()?;
-        let result = handle_user_action(&db, UserAction::Update(user_id, name)).await?;
-        handle_database_action(result).await
+    ) -> FieldResult<UpdateUserPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let user_id = parse_id(&id)?;
+        let result = repos.users.update(actor, user_id, &name).await;
+        let (user, errors) = payload_parts(result, User::from)?;
+        Ok(UpdateUserPayload { user, errors })
     }
 
-    pub async fn delete_user(&self, ctx: &Context<'_>, id: ID) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let user_id = id.parse::<i32>()?;
-        let result = handle_user_action(&db, UserAction::Delete(user_id)).await?;
-        handle_database_action(result).await
+    #[graphql(guard = "ScopeGuard::new(Scope::UsersAdmin)")]
+    pub async fn delete_user(&self, ctx: &Context<'_>, id: ID) -> FieldResult<DeleteUserPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let user_id = parse_id(&id)?;
+        let result = repos.users.delete(actor, user_id).await;
+        let (deleted_user_id, errors) = payload_parts(result, |()| id)?;
+        Ok(DeleteUserPayload {
+            deleted_user_id,
+            errors,
+        })
     }
 
+    #[graphql(guard = "ScopeGuard::new(Scope::UsersAdmin).and(RoleGuard::new(Role::Admin))")]
+    pub async fn set_user_role(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        role: Role,
+    ) -> FieldResult<SetUserRolePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let user_id = parse_id(&id)?;
+        let result = repos.users.set_role(actor, user_id, role).await;
+        let (user, errors) = payload_parts(result, User::from)?;
+        Ok(SetUserRolePayload { user, errors })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
     pub async fn create_message(
         &self,
         ctx: &Context<'_>,
-        user_id: ID,
         content: String,
         parent_id: Option<i32>,
-    ) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let user_id = user_id.parse::<i32>()?;
-        let result =
-            handle_message_action(&db, MessageAction::Create(user_id, content, parent_id)).await?;
-        handle_database_action(result).await
+        // Replies are posted to the channel of their parent and can leave this out.
+        channel_id: Option<ID>,
+    ) -> FieldResult<CreateMessagePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let channel_id = channel_id.as_ref().map(parse_id).transpose()?;
+        let result = repos
+            .messages
+            .create(user_id, &content, parent_id, channel_id)
+            .await;
+        let (message, errors) = payload_parts(result, Message::from)?;
+        Ok(CreateMessagePayload { message, errors })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn create_channel(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        topic: Option<String>,
+        #[graphql(default)] visibility: Visibility,
+    ) -> FieldResult<CreateChannelPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let result = repos
+            .channels
+            .create(user_id, &name, topic.as_deref(), visibility)
+            .await;
+        let (channel, errors) = payload_parts(result, Channel::from)?;
+        Ok(CreateChannelPayload { channel, errors })
+    }
+
+    // Fields left out stay as they are; an empty topic removes it.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn update_channel(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        name: Option<String>,
+        topic: Option<String>,
+        visibility: Option<Visibility>,
+    ) -> FieldResult<UpdateChannelPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let channel_id = parse_id(&id)?;
+        let changes = ChannelChanges {
+            name,
+            topic,
+            visibility,
+        };
+        let result = repos.channels.update(actor, channel_id, changes).await;
+        let (channel, errors) = payload_parts(result, Channel::from)?;
+        Ok(UpdateChannelPayload { channel, errors })
     }
 
-    pub async fn delete_message(&self, ctx: &Context<'_>, id: ID) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let message_id = id.parse::<i32>()?;
-        let result = handle_message_action(&db, MessageAction::Delete(message_id)).await?;
-        handle_database_action(result).await
+    // Removes the channel and leaves its messages as tombstones. Members can
+    // only delete a channel whose live messages are all their own.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn delete_channel(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> FieldResult<DeleteChannelPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let channel_id = parse_id(&id)?;
+        let result = repos.channels.delete(actor, channel_id).await;
+        let (deleted_channel_id, errors) = payload_parts(result, |_| id)?;
+        Ok(DeleteChannelPayload {
+            deleted_channel_id,
+            errors,
+        })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn delete_message(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> FieldResult<DeleteMessagePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let message_id = parse_id(&id)?;
+        let result = repos.messages.delete(actor, message_id).await;
+        let (deleted_message, errors) = payload_parts(result, |message| {
+            events::DeletedMessage::from(&message).into()
+        })?;
+        Ok(DeleteMessagePayload {
+            deleted_message,
+            errors,
+        })
     }
 
+    // Unlike deleteMessage, removes the message and all replies below it for
+    // good instead of leaving a tombstone.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite).and(RoleGuard::new(Role::Admin))")]
+    pub async fn purge_message(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> FieldResult<PurgeMessagePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let message_id = parse_id(&id)?;
+        let result = repos.messages.purge(actor, message_id).await;
+        let (purged_message, errors) = payload_parts(result, |message| {
+            events::DeletedMessage::from(&message).into()
+        })?;
+        Ok(PurgeMessagePayload {
+            purged_message,
+            errors,
+        })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite).and(RoleGuard::new(Role::Moderator))")]
+    pub async fn hide_message(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        #[graphql(default = true)] hidden: bool,
+    ) -> FieldResult<HideMessagePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let message_id = parse_id(&id)?;
+        let result = repos.messages.hide(actor, message_id, hidden).await;
+        let (message, errors) = payload_parts(result, Message::from)?;
+        Ok(HideMessagePayload { message, errors })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
     pub async fn update_message(
         &self,
         ctx: &Context<'_>,
         id: ID,
         content: String,
-    ) -> FieldResult<MutationResponse> {
-        let db = ctx.data_unchecked::<MyContext>().db.clone();
-        let message_id = id.parse::<i32>()?;
-        let result = handle_message_action(&db, MessageAction::Update(message_id, content)).await?;
-        handle_database_action(result).await
+    ) -> FieldResult<UpdateMessagePayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let actor = current_user(ctx)?.actor();
+        let message_id = parse_id(&id)?;
+        let result = repos.messages.update(actor, message_id, &content).await;
+        let (message, errors) = payload_parts(result, Message::from)?;
+        Ok(UpdateMessagePayload { message, errors })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn add_reaction(
+        &self,
+        ctx: &Context<'_>,
+        message_id: ID,
+        emoji: String,
+    ) -> FieldResult<AddReactionPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let message_id = parse_id(&message_id)?;
+        let result = async {
+            repos.reactions.add(user_id, message_id, &emoji).await?;
+            repos
+                .messages
+                .get(message_id)
+                .await?
+                .ok_or_else(message_not_found)
+        }
+        .await;
+        let (message, errors) = payload_parts(result, Message::from)?;
+        Ok(AddReactionPayload { message, errors })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesWrite)")]
+    pub async fn remove_reaction(
+        &self,
+        ctx: &Context<'_>,
+        message_id: ID,
+        emoji: String,
+    ) -> FieldResult<RemoveReactionPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let message_id = parse_id(&message_id)?;
+        let result = async {
+            repos.reactions.remove(user_id, message_id, &emoji).await?;
+            repos
+                .messages
+                .get(message_id)
+                .await?
+                .ok_or_else(message_not_found)
+        }
+        .await;
+        let (message, errors) = payload_parts(result, Message::from)?;
+        Ok(RemoveReactionPayload { message, errors })
+    }
+
+    #[graphql(guard = "SessionGuard")]
+    pub async fn create_token(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        scopes: Vec<Scope>,
+        expires_in_days: Option<i64>,
+    ) -> FieldResult<CreateTokenPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let expires_in_days = expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+        if !(1..=MAX_EXPIRY_DAYS).contains(&expires_in_days) {
+            return Ok(CreateTokenPayload {
+                token: None,
+                personal_access_token: None,
+                errors: vec![UserError::for_field(
+                    "expiresInDays",
+                    AppError::Validation(format!(
+                        "expiresInDays must be between 1 and {}",
+                        MAX_EXPIRY_DAYS
+                    )),
+                )],
+            });
+        }
+        let expires_at = Utc::now() + chrono::Duration::days(expires_in_days);
+        let result = repos
+            .tokens
+            .create(user_id, &name, &scopes, expires_at)
+            .await;
+        let (created, errors) = payload_parts(result, |(token, model)| (token, model.into()))?;
+        let (token, personal_access_token) = created.unzip();
+        Ok(CreateTokenPayload {
+            token,
+            personal_access_token,
+            errors,
+        })
+    }
+
+    #[graphql(guard = "SessionGuard")]
+    pub async fn revoke_token(&self, ctx: &Context<'_>, id: ID) -> FieldResult<RevokeTokenPayload> {
+        let repos = &ctx.data_unchecked::<MyContext>().repos;
+        let user_id = current_user(ctx)?.id;
+        let token_id = parse_id(&id)?;
+        let result = repos.tokens.revoke(user_id, token_id).await;
+        let (revoked_token_id, errors) = payload_parts(result, |()| id)?;
+        Ok(RevokeTokenPayload {
+            revoked_token_id,
+            errors,
+        })
+    }
+}
+
+pub struct SubscriptionRoot;
+
+// Optional filters shared by the message subscriptions.
+fn matches_filter(
+    user_id: Option<i32>,
+    parent_id: Option<i32>,
+    user_filter: Option<i32>,
+    parent_filter: Option<i32>,
+) -> bool {
+    (user_filter.is_none() || user_id == user_filter)
+        && (parent_filter.is_none() || parent_id == parent_filter)
+}
+
+// Streams the local message events picked out by `select`. A subscriber that
+// lags behind the channel skips the events it missed.
+fn message_events<T, F>(ctx: &Context<'_>, select: F) -> FieldResult<impl Stream<Item = T>>
+where
+    F: Fn(MessageEvent) -> Option<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let receiver = ctx.data::<MyContext>()?.repos.events.subscribe();
+    Ok(BroadcastStream::new(receiver).filter_map(move |event| {
+        let item = event.ok().and_then(&select);
+        async move { item }
+    }))
+}
+
+#[Subscription]
+impl SubscriptionRoot {
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn message_created(
+        &self,
+        ctx: &Context<'_>,
+        parent_id: Option<i32>,
+        user_id: Option<ID>,
+    ) -> FieldResult<impl Stream<Item = Message>> {
+        let user_id = user_id.map(|id| parse_id(&id)).transpose()?;
+        message_events(ctx, move |event| match event {
+            MessageEvent::Created(msg)
+                if matches_filter(msg.user_id, msg.parent_id, user_id, parent_id) =>
+            {
+                Some(msg.into())
+            }
+            _ => None,
+        })
+    }
+
+    // Also fires when a message is hidden or unhidden.
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn message_updated(
+        &self,
+        ctx: &Context<'_>,
+        parent_id: Option<i32>,
+        user_id: Option<ID>,
+    ) -> FieldResult<impl Stream<Item = Message>> {
+        let user_id = user_id.map(|id| parse_id(&id)).transpose()?;
+        message_events(ctx, move |event| match event {
+            MessageEvent::Updated(msg)
+                if matches_filter(msg.user_id, msg.parent_id, user_id, parent_id) =>
+            {
+                Some(msg.into())
+            }
+            _ => None,
+        })
+    }
+
+    #[graphql(guard = "ScopeGuard::new(Scope::MessagesRead)")]
+    async fn message_deleted(
+        &self,
+        ctx: &Context<'_>,
+        parent_id: Option<i32>,
+        user_id: Option<ID>,
+    ) -> FieldResult<impl Stream<Item = DeletedMessage>> {
+        let user_id = user_id.map(|id| parse_id(&id)).transpose()?;
+        message_events(ctx, move |event| match event {
+            MessageEvent::Deleted(msg)
+                if matches_filter(msg.user_id, msg.parent_id, user_id, parent_id) =>
+            {
+                Some(msg.into())
+            }
+            _ => None,
+        })
     }
 }
@@ -1,9 +1,42 @@
-use async_graphql::{Object, ID};
+pub use crate::duration::Duration;
+
+use crate::auth::middleware::CurrentUser;
+use crate::auth::scope::{parse_scopes, Scope};
+use crate::db::events;
+use crate::db::repository::{MessageCursor, MessagePage, PageRequest, ReactionCount};
+use crate::entity::channel::Visibility;
+use crate::entity::user::Role;
+use crate::entity::{channel, message, personal_access_token, user};
+use crate::error::AppError;
+use crate::graphql::loader::{
+    ChannelLoader, MentionLoader, ReactionLoader, RevisionLoader, UserLoader,
+};
+use crate::graphql::schema::MyContext;
+use async_graphql::connection::{self, Connection, CursorType, Edge};
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{
+    Context, Enum, FieldError, FieldResult, InputValueError, InputValueResult, Object, Scalar,
+    ScalarType, SimpleObject, Value, ID,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
 
 pub struct User {
     pub id: ID,
     pub name: String,
+    pub role: Role,
+}
+
+impl From<user::Model> for User {
+    fn from(user: user::Model) -> Self {
+        Self {
+            id: ID(user.id.to_string()),
+            name: user.name,
+            role: user.role,
+        }
+    }
 }
 
 #[Object]
@@ -15,16 +48,265 @@ impl User {
     async fn name(&self) -> &str {
         &self.name
     }
+
+    async fn role(&self) -> Role {
+        self.role
+    }
+}
+
+pub struct Channel {
+    pub id: ID,
+    pub name: String,
+    pub topic: Option<String>,
+    pub created_by: Option<i32>,
+    pub visibility: Visibility,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<channel::Model> for Channel {
+    fn from(channel: channel::Model) -> Self {
+        Self {
+            id: ID(channel.id.to_string()),
+            name: channel.name,
+            topic: channel.topic,
+            created_by: channel.created_by,
+            visibility: channel.visibility,
+            created_at: channel.created_at,
+            updated_at: channel.updated_at,
+        }
+    }
+}
+
+#[Object]
+impl Channel {
+    async fn id(&self) -> &ID {
+        &self.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    async fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    async fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    // Null once the creator is deleted.
+    async fn created_by(&self, ctx: &Context<'_>) -> FieldResult<Option<User>> {
+        let Some(user_id) = self.created_by else {
+            return Ok(None);
+        };
+        let loader = ctx.data::<DataLoader<UserLoader>>()?;
+        Ok(loader.load_one(user_id).await?.map(User::from))
+    }
 }
 
 pub struct Message {
     pub id: ID,
-    pub user_id: ID,
+    pub user_id: Option<ID>,
     pub content: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub parent_id: Option<i32>,
-    pub user: User,
+    pub channel_id: Option<i32>,
+    pub hidden: bool,
+    pub deleted: bool,
+}
+
+pub const HIDDEN_CONTENT: &str = "[hidden]";
+pub const DELETED_CONTENT: &str = "[deleted]";
+
+#[derive(Enum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReplyOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+// Deepest reply level `thread` will return below its root.
+pub const MAX_THREAD_DEPTH: i32 = 10;
+
+// A message with its replies nested underneath, as returned by `thread`.
+pub struct ThreadNode {
+    pub message: Message,
+    pub depth: i32,
+    pub replies: Vec<ThreadNode>,
+    // Set on nodes at the depth limit that have replies which were left out.
+    pub has_more_replies: bool,
+}
+
+impl ThreadNode {
+    // Builds the tree from a flat thread whose first entry is the root.
+    // Anything deeper than `max_depth` is dropped and flagged on its parent.
+    pub fn build(messages: Vec<message::Model>, max_depth: i32) -> Option<Self> {
+        let mut messages = messages.into_iter();
+        let root = messages.next()?;
+        let mut children: HashMap<i32, Vec<message::Model>> = HashMap::new();
+        for msg in messages {
+            if let Some(parent_id) = msg.parent_id {
+                children.entry(parent_id).or_default().push(msg);
+            }
+        }
+        Some(Self::node(root, 0, max_depth, &mut children))
+    }
+
+    fn node(
+        msg: message::Model,
+        depth: i32,
+        max_depth: i32,
+        children: &mut HashMap<i32, Vec<message::Model>>,
+    ) -> Self {
+        let mut replies = children.remove(&msg.id).unwrap_or_default();
+        let has_more_replies = depth >= max_depth && !replies.is_empty();
+        if depth >= max_depth {
+            replies.clear();
+        }
+        replies.sort_by_key(|reply| (reply.created_at, reply.id));
+        Self {
+            message: msg.into(),
+            depth,
+            replies: replies
+                .into_iter()
+                .map(|reply| Self::node(reply, depth + 1, max_depth, children))
+                .collect(),
+            has_more_replies,
+        }
+    }
+}
+
+#[Object]
+impl ThreadNode {
+    async fn message(&self) -> &Message {
+        &self.message
+    }
+
+    // 0 for the root, 1 for its direct replies and so on.
+    async fn depth(&self) -> i32 {
+        self.depth
+    }
+
+    async fn replies(&self) -> &[ThreadNode] {
+        &self.replies
+    }
+
+    async fn has_more_replies(&self) -> bool {
+        self.has_more_replies
+    }
+}
+
+// One version of a message's content.
+#[derive(SimpleObject)]
+pub struct MessageRevision {
+    // 0 for the original, counting up with each edit.
+    pub revision: i32,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Everyone who reacted to a message with one emoji.
+#[derive(SimpleObject)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: i32,
+    // Whether the viewer is among them; always false for anonymous viewers.
+    pub reacted: bool,
+}
+
+impl From<ReactionCount> for Reaction {
+    fn from(count: ReactionCount) -> Self {
+        Self {
+            emoji: count.emoji,
+            count: count.count.try_into().unwrap_or(i32::MAX),
+            reacted: count.reacted,
+        }
+    }
+}
+
+impl Message {
+    // Hidden messages keep their place in threads, but only moderators and
+    // the author can still read what they said. Deleted ones are shown to
+    // no one.
+    pub fn content_visible(&self, ctx: &Context<'_>) -> bool {
+        if self.deleted {
+            return false;
+        }
+        if !self.hidden {
+            return true;
+        }
+        ctx.data_opt::<CurrentUser>().is_some_and(|viewer| {
+            viewer.role.can_moderate()
+                || self
+                    .user_id
+                    .as_ref()
+                    .is_some_and(|user_id| user_id.0 == viewer.id.to_string())
+        })
+    }
+
+    fn masked_content(&self) -> &'static str {
+        if self.deleted {
+            DELETED_CONTENT
+        } else {
+            HIDDEN_CONTENT
+        }
+    }
+
+    // Every version oldest first, ending with the current one. Contents are
+    // masked like `content`.
+    pub async fn versions(&self, ctx: &Context<'_>) -> FieldResult<Vec<MessageRevision>> {
+        let loader = ctx.data::<DataLoader<RevisionLoader>>()?;
+        let message_id = self.id.parse::<i32>()?;
+        let saved = loader.load_one(message_id).await?.unwrap_or_default();
+        let current = MessageRevision {
+            revision: saved.len() as i32,
+            content: self.content.clone(),
+            created_at: self.updated_at,
+        };
+        let visible = self.content_visible(ctx);
+        Ok(saved
+            .into_iter()
+            .map(|revision| MessageRevision {
+                revision: revision.revision,
+                content: revision.content,
+                created_at: revision.created_at,
+            })
+            .chain(std::iter::once(current))
+            .map(|mut revision| {
+                if !visible {
+                    revision.content = self.masked_content().to_owned();
+                }
+                revision
+            })
+            .collect())
+    }
+}
+
+impl From<message::Model> for Message {
+    fn from(msg: message::Model) -> Self {
+        Self {
+            id: ID(msg.id.to_string()),
+            user_id: msg.user_id.map(|user_id| ID(user_id.to_string())),
+            content: msg.content,
+            created_at: msg.created_at,
+            updated_at: msg.updated_at,
+            parent_id: msg.parent_id,
+            channel_id: msg.channel_id,
+            hidden: msg.hidden_at.is_some(),
+            deleted: msg.deleted_at.is_some(),
+        }
+    }
 }
 
 #[Object]
@@ -33,26 +315,272 @@ impl Message {
         &self.id
     }
 
-    async fn user_id(&self) -> &ID {
-        &self.user_id
+    // Null once the message is deleted.
+    async fn user_id(&self) -> Option<&ID> {
+        self.user_id.as_ref().filter(|_| !self.deleted)
+    }
+
+    async fn content(&self, ctx: &Context<'_>) -> &str {
+        if self.content_visible(ctx) {
+            self.content.as_str()
+        } else {
+            self.masked_content()
+        }
+    }
+
+    async fn hidden(&self) -> bool {
+        self.hidden
     }
-    async fn content(&self) -> &str {
-        &self.content
+
+    async fn deleted(&self) -> bool {
+        self.deleted
     }
 
-    async fn created_at(&self) -> String {
-        self.created_at.to_rfc3339()
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
     }
 
-    async fn updated_at(&self) -> String {
-        self.updated_at.to_rfc3339()
+    async fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
     }
 
     async fn parent_id(&self) -> Option<i32> {
         self.parent_id
     }
 
-    async fn user(&self) -> &User {
-        &self.user
+    // Null for messages outside of any channel.
+    async fn channel_id(&self) -> Option<ID> {
+        self.channel_id.map(|id| ID(id.to_string()))
+    }
+
+    async fn channel(&self, ctx: &Context<'_>) -> FieldResult<Option<Channel>> {
+        let Some(channel_id) = self.channel_id else {
+            return Ok(None);
+        };
+        let loader = ctx.data::<DataLoader<ChannelLoader>>()?;
+        Ok(loader.load_one(channel_id).await?.map(Channel::from))
+    }
+
+    // The content as it read after each edit, starting with the original.
+    async fn revisions(&self, ctx: &Context<'_>) -> FieldResult<Vec<MessageRevision>> {
+        self.versions(ctx).await
+    }
+
+    // The users the message mentions, by name. Empty where the content is
+    // masked, since the mentions would give it away.
+    async fn mentions(&self, ctx: &Context<'_>) -> FieldResult<Vec<User>> {
+        if !self.content_visible(ctx) {
+            return Ok(Vec::new());
+        }
+        let loader = ctx.data::<DataLoader<MentionLoader>>()?;
+        let message_id = self.id.parse::<i32>()?;
+        let users = loader.load_one(message_id).await?.unwrap_or_default();
+        Ok(users.into_iter().map(User::from).collect())
+    }
+
+    // The emoji used on the message, the most used first.
+    async fn reactions(&self, ctx: &Context<'_>) -> FieldResult<Vec<Reaction>> {
+        let loader = ctx.data::<DataLoader<ReactionLoader>>()?;
+        let message_id = self.id.parse::<i32>()?;
+        let viewer_id = ctx.data_opt::<CurrentUser>().map(|viewer| viewer.id);
+        let counts = loader
+            .load_one((message_id, viewer_id))
+            .await?
+            .unwrap_or_default();
+        Ok(counts.into_iter().map(Reaction::from).collect())
+    }
+
+    async fn edit_count(&self, ctx: &Context<'_>) -> FieldResult<i32> {
+        let loader = ctx.data::<DataLoader<RevisionLoader>>()?;
+        let message_id = self.id.parse::<i32>()?;
+        let saved = loader.load_one(message_id).await?;
+        Ok(saved.map_or(0, |saved| saved.len() as i32))
+    }
+
+    async fn replies(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+        #[graphql(default)] order_by: ReplyOrder,
+    ) -> FieldResult<MessageConnection> {
+        let messages = &ctx.data::<MyContext>()?.repos.messages;
+        let message_id = self.id.parse::<i32>()?;
+        message_connection(after, None, first, None, |mut page| {
+            page.newest_first = order_by == ReplyOrder::NewestFirst;
+            messages.list_replies(message_id, page)
+        })
+        .await
+    }
+
+    // Null once the message is deleted.
+    async fn user(&self, ctx: &Context<'_>) -> FieldResult<Option<User>> {
+        let Some(user_id) = self.user_id.as_ref().filter(|_| !self.deleted) else {
+            return Ok(None);
+        };
+        let loader = ctx.data::<DataLoader<UserLoader>>()?;
+        let user_id = user_id.parse::<i32>()?;
+        loader
+            .load_one(user_id)
+            .await?
+            .map(|user| Some(User::from(user)))
+            .ok_or_else(|| AppError::NotFound("User not found".to_string()).into())
+    }
+}
+
+#[Scalar]
+impl ScalarType for Duration {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match &value {
+            Value::String(s) => s.parse().map_err(InputValueError::custom),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+pub type MessageConnection = Connection<MessageCursor, Message>;
+
+// Fetches a page of a message list and wraps it in a Relay connection. Page
+// sizes are clamped in the repository layer.
+pub async fn message_connection<F>(
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+    fetch: impl FnOnce(PageRequest) -> F,
+) -> FieldResult<MessageConnection>
+where
+    F: Future<Output = Result<MessagePage, AppError>>,
+{
+    connection::query(
+        after,
+        before,
+        first,
+        last,
+        |after, before, first, last| async move {
+            if first.is_some() && last.is_some() {
+                return Err(FieldError::from(AppError::Validation(
+                    "Pass either first or last, not both".to_string(),
+                )));
+            }
+            let page = PageRequest {
+                after,
+                before,
+                limit: first.or(last).map(|limit| limit as u64),
+                from_end: last.is_some(),
+                ..Default::default()
+            };
+            let page = fetch(page).await?;
+            let mut connection = Connection::new(page.has_previous_page, page.has_next_page);
+            connection.edges = page
+                .messages
+                .into_iter()
+                .map(|msg| Edge::new(MessageCursor::from(&msg), Message::from(msg)))
+                .collect();
+            Ok::<_, FieldError>(connection)
+        },
+    )
+    .await
+}
+
+// Cursors are opaque to clients; underneath they encode
+// "<created_at in microseconds>:<id>".
+impl CursorType for MessageCursor {
+    type Error = &'static str;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let decoded = URL_SAFE_NO_PAD
+            .decode(s)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or("Invalid cursor")?;
+        let (created_at, id) = decoded.split_once(':').ok_or("Invalid cursor")?;
+        let created_at = created_at
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or("Invalid cursor")?;
+        let id = id.parse().map_err(|_| "Invalid cursor")?;
+        Ok(MessageCursor { created_at, id })
+    }
+
+    fn encode_cursor(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}:{}",
+            self.created_at.timestamp_micros(),
+            self.id
+        ))
+    }
+}
+
+// Sent to subscribers after a message is deleted.
+#[derive(SimpleObject)]
+pub struct DeletedMessage {
+    pub id: ID,
+    // Null for messages of deleted users.
+    pub user_id: Option<ID>,
+    pub parent_id: Option<i32>,
+}
+
+impl From<events::DeletedMessage> for DeletedMessage {
+    fn from(message: events::DeletedMessage) -> Self {
+        Self {
+            id: ID(message.id.to_string()),
+            user_id: message.user_id.map(|user_id| ID(user_id.to_string())),
+            parent_id: message.parent_id,
+        }
+    }
+}
+
+pub struct PersonalAccessToken {
+    pub id: ID,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<personal_access_token::Model> for PersonalAccessToken {
+    fn from(token: personal_access_token::Model) -> Self {
+        Self {
+            id: ID(token.id.to_string()),
+            name: token.name,
+            scopes: parse_scopes(&token.scopes),
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+        }
+    }
+}
+
+#[Object]
+impl PersonalAccessToken {
+    async fn id(&self) -> &ID {
+        &self.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    async fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    async fn last_used_at(&self) -> Option<DateTime<Utc>> {
+        self.last_used_at
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
     }
 }
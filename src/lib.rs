@@ -0,0 +1,9 @@
+pub mod auth;
+pub mod cli;
+pub mod config;
+pub mod db;
+pub mod duration;
+pub mod entity;
+pub mod error;
+pub mod graphql;
+pub mod server;
@@ -1,25 +1,24 @@
+use backend::cli::{self, Cli, Command};
+use backend::config::Config;
+use clap::Parser;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-pub mod db;
-pub mod entity;
-pub mod graphql;
-mod server;
-
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
     tracing_subscriber::registry()
+        .with(config.log_level)
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = server::app().await;
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    tracing::info!(
-        "GraphiQL: http://localhost:{}/graphiql",
-        listener.local_addr().unwrap().port()
-    );
-
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Err(e) = cli::run(command, &config).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
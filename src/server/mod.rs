@@ -1,165 +1,316 @@
-use crate::graphql::schema::{MutationRoot, MyContext, MySchema, QueryRoot};
-use async_graphql::{EmptySubscription, Schema};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use crate::auth::middleware::{authenticate, resolve_current_user, AuthState, CurrentUser};
+use crate::auth::token::TokenKeys;
+use crate::config::{Config, DatabaseConfig, MigrationMode, ServerConfig};
+use crate::db::events;
+use crate::db::repository::Repositories;
+use crate::error::AppError;
+use crate::graphql::loader::{
+    channel_loader, mention_loader, reaction_loader, revision_loader, user_loader,
+};
+use crate::graphql::schema::{MutationRoot, MyContext, MySchema, QueryRoot, SubscriptionRoot};
+use async_graphql::{http::ALL_WEBSOCKET_PROTOCOLS, Data, Schema};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
 use axum::{
-    extract::Extension,
-    response::{Html, IntoResponse},
+    body::{Body, HttpBody},
+    extract::{ws::WebSocketUpgrade, Extension, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
     routing::{get, post},
     Router,
 };
+use http_body_util::Limited;
 use migration::{Migrator, MigratorTrait};
-use sea_orm::{ConnectOptions, Database, DatabaseConnection};
-use std::time::Duration;
+use sea_orm::{ConnectOptions, Database, DatabaseConnection, DbErr};
+
+async fn graphql_handler(
+    schema: Extension<MySchema>,
+    Extension(repos): Extension<Repositories>,
+    current_user: Option<Extension<CurrentUser>>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut req = req
+        .into_inner()
+        .data(user_loader(repos.users))
+        .data(revision_loader(repos.messages.clone()))
+        .data(mention_loader(repos.messages))
+        .data(channel_loader(repos.channels))
+        .data(reaction_loader(repos.reactions));
+    if let Some(Extension(current_user)) = current_user {
+        req = req.data(current_user);
+    }
+    schema.execute(req).await.into()
+}
+
+// Serves subscriptions over graphql-transport-ws (and the legacy graphql-ws).
+// Clients that cannot set headers on the upgrade request may instead send
+// `{"Authorization": "Bearer <token>"}` as the `connection_init` payload.
+async fn graphql_ws_handler(
+    Extension(schema): Extension<MySchema>,
+    Extension(auth_state): Extension<AuthState>,
+    current_user: Option<Extension<CurrentUser>>,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let mut data = Data::default();
+    // A connection lives for a long time, so what messages show is batched
+    // per event but never cached.
+    let loader = user_loader(auth_state.repos.users.clone());
+    loader.enable_all_cache(false);
+    data.insert(loader);
+    let loader = revision_loader(auth_state.repos.messages.clone());
+    loader.enable_all_cache(false);
+    data.insert(loader);
+    let loader = mention_loader(auth_state.repos.messages.clone());
+    loader.enable_all_cache(false);
+    data.insert(loader);
+    let loader = channel_loader(auth_state.repos.channels.clone());
+    loader.enable_all_cache(false);
+    data.insert(loader);
+    let loader = reaction_loader(auth_state.repos.reactions.clone());
+    loader.enable_all_cache(false);
+    data.insert(loader);
+    if let Some(Extension(current_user)) = current_user {
+        data.insert(current_user);
+    }
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .with_data(data)
+                .on_connection_init(move |payload| connection_init(auth_state, payload))
+                .serve()
+        })
+}
+
+async fn connection_init(
+    auth_state: AuthState,
+    payload: serde_json::Value,
+) -> async_graphql::Result<Data> {
+    let mut data = Data::default();
+    let value = payload
+        .get("Authorization")
+        .or_else(|| payload.get("authorization"))
+        .and_then(|value| value.as_str());
+    let Some(value) = value else {
+        return Ok(data);
+    };
 
-async fn graphql_handler(schema: Extension<MySchema>, req: GraphQLRequest) -> GraphQLResponse {
-    schema.execute(req.into_inner()).await.into()
+    let token = value
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| AppError::Unauthenticated("Malformed Authorization header".to_string()))?;
+    match resolve_current_user(&auth_state, token.trim().to_owned()).await? {
+        Some(current_user) => {
+            data.insert(current_user);
+            Ok(data)
+        }
+        None => Err(AppError::Unauthenticated("Invalid or expired token".to_string()).into()),
+    }
 }
 
 async fn graphql_playground() -> impl IntoResponse {
     let html = async_graphql::http::playground_source(
-        async_graphql::http::GraphQLPlaygroundConfig::new("/graphql"),
+        async_graphql::http::GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/ws"),
     );
     Html(html)
 }
 
-pub async fn app() -> Router {
-    dotenvy::dotenv().ok();
-    let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
-    tracing::info!("Connecting to database: {}", db_url);
-    let mut opt = ConnectOptions::new(&db_url);
-    opt.max_connections(100)
-        .min_connections(5)
-        .connect_timeout(Duration::from_secs(8))
-        .acquire_timeout(Duration::from_secs(8))
-        .idle_timeout(Duration::from_secs(8))
-        .max_lifetime(Duration::from_secs(8))
-        .sqlx_logging(true)
-        .sqlx_logging_level(log::LevelFilter::Info);
-
-    let db: DatabaseConnection = Database::connect(opt)
+// async-graphql reads request bodies as a stream, which axum's
+// `DefaultBodyLimit` does not cover, so the limit is put on the body itself.
+// Bodies known to be too large up front are turned away without reading them.
+async fn limit_body_size(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    if req.body().size_hint().lower() > limit as u64 {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    next.run(req.map(|body| Body::new(Limited::new(body, limit))))
         .await
-        .expect("Database connection failed");
+}
 
-    let _ = Migrator::up(&db, None).await;
+fn schema(repos: Repositories, keys: TokenKeys, config: &ServerConfig) -> MySchema {
+    let mut builder = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(MyContext::new(repos))
+        .data(keys)
+        .limit_depth(config.max_query_depth);
+    if let Some(complexity) = config.max_query_complexity {
+        builder = builder.limit_complexity(complexity);
+    }
+    builder.finish()
+}
 
-    let schema = Schema::build(QueryRoot, MutationRoot, EmptySubscription)
-        .data(MyContext::new(db))
-        .finish();
+fn router(repos: Repositories, keys: TokenKeys, config: &ServerConfig) -> Router {
+    let auth_state = AuthState {
+        keys: keys.clone(),
+        repos: repos.clone(),
+    };
 
-    Router::new()
+    let mut router = Router::new()
         .route("/graphql", post(graphql_handler).get(graphql_handler))
-        .route("/graphiql", get(graphql_playground))
-        .layer(Extension(schema))
+        .route("/ws", get(graphql_ws_handler));
+    if config.playground {
+        router = router.route("/graphiql", get(graphql_playground));
+    }
+    router
+        .layer(middleware::from_fn_with_state(
+            auth_state.clone(),
+            authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            config.max_body_size,
+            limit_body_size,
+        ))
+        .layer(Extension(auth_state))
+        .layer(Extension(repos.clone()))
+        .layer(Extension(schema(repos, keys, config)))
+}
+
+pub async fn connect(database: &DatabaseConfig) -> Result<DatabaseConnection, DbErr> {
+    tracing::info!("Connecting to database: {}", database.url);
+    let mut opt = ConnectOptions::new(&database.url);
+    opt.max_connections(database.max_connections)
+        .min_connections(database.min_connections)
+        .connect_timeout(database.connect_timeout)
+        .acquire_timeout(database.acquire_timeout)
+        .idle_timeout(database.idle_timeout)
+        .max_lifetime(database.max_lifetime)
+        .sqlx_logging(database.log_level != log::LevelFilter::Off)
+        .sqlx_logging_level(database.log_level);
+    Database::connect(opt).await
+}
+
+// Applies or checks for pending migrations as the mode says. An error means
+// the schema may not be what the code expects, so the server must not start.
+pub async fn migrate(db: &DatabaseConnection, mode: MigrationMode) -> Result<(), DbErr> {
+    match mode {
+        MigrationMode::Auto | MigrationMode::MigrateAndExit => Migrator::up(db, None).await,
+        MigrationMode::Verify => {
+            let pending = Migrator::get_pending_migrations(db).await?;
+            if pending.is_empty() {
+                return Ok(());
+            }
+            let names: Vec<&str> = pending.iter().map(|migration| migration.name()).collect();
+            Err(DbErr::Custom(format!(
+                "Pending migrations: {}",
+                names.join(", ")
+            )))
+        }
+    }
+}
+
+// Expects the schema to be up to date, see `migrate`.
+pub fn app(config: &Config, keys: TokenKeys, db: DatabaseConnection) -> Router {
+    let repos = Repositories::sea_orm(db.clone());
+    events::spawn_listener(db, config.database.url.clone(), repos.events.clone());
+
+    router(repos, keys, &config.server)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::database::{
-        handle_message_action, handle_user_action, MessageAction, UserAction,
-    };
+    use crate::db::testing::{self, TestDb};
+    use crate::entity::user::Role;
     use crate::entity::{message, user};
     use axum::{
         body::{to_bytes, Body},
         http::{self, Request, StatusCode},
     };
+    use chrono::DateTime;
 
-    use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
-    use sea_orm::{ConnectionTrait, Database};
+    use futures::StreamExt;
+    use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
     use serde_json::{json, Value};
     use tower::ServiceExt;
 
-    async fn setup_app() -> Router {
-        dotenvy::dotenv().ok();
-        let db_url = std::env::var("TEST_INTEGRATION_URL").expect("DATABASE_URL is not set");
-
-        let db: DatabaseConnection = Database::connect(db_url)
-            .await
-            .expect("Database connection failed");
+    // Rows created by `load_test_data`. Tests refer to them by these ids
+    // instead of assuming which ones the database hands out.
+    struct TestData {
+        alice: i32,
+        bob: i32,
+        charlie: i32,
+        david: i32,
+        eve: i32,
+        // Alice's two messages, then one each by Bob, Charlie, David and Eve.
+        messages: Vec<i32>,
+    }
 
-        load_test_data(&db).await;
+    // Every test gets a database of its own, which is dropped with the
+    // returned `TestDb`, so it has to be kept until the test is done.
+    async fn setup() -> (TestDb, TestData) {
+        let db = testing::connect("TEST_INTEGRATION_URL").await;
+        let data = load_test_data(&db).await;
+        (db, data)
+    }
 
-        let schema = Schema::build(QueryRoot, MutationRoot, EmptySubscription)
-            .data(MyContext::new(db))
-            .finish();
+    async fn setup_app() -> (Router, TestDb, TestData) {
+        let (db, data) = setup().await;
+        let app = router(
+            Repositories::sea_orm(db.clone()),
+            test_keys(),
+            &ServerConfig::default(),
+        );
+        (app, db, data)
+    }
 
-        Router::new()
-            .route("/graphql", post(graphql_handler).get(graphql_handler))
-            .route("/graphiql", get(graphql_playground))
-            .layer(Extension(schema))
+    fn test_keys() -> TokenKeys {
+        TokenKeys::new(b"test-secret")
     }
 
-    async fn load_test_data(db: &DatabaseConnection) {
-        // Reset the database
-        user::Entity::delete_many()
-            .filter(user::Column::Id.gt(0))
-            .exec(db)
-            .await
-            .unwrap();
-        message::Entity::delete_many()
-            .filter(message::Column::Id.gt(0))
-            .exec(db)
-            .await
-            .unwrap();
-        // Reset auto increment
-        let sql = "ALTER SEQUENCE user_id_seq RESTART WITH 1;";
-        db.execute(sea_orm::Statement::from_string(
-            db.get_database_backend(),
-            sql.to_owned(),
-        ))
-        .await
-        .expect("Could not reset auto increment");
-        let sql = "ALTER SEQUENCE message_id_seq RESTART WITH 1;";
-        db.execute(sea_orm::Statement::from_string(
-            db.get_database_backend(),
-            sql.to_owned(),
-        ))
-        .await
-        .expect("Could not reset auto increment");
-
-        let users = vec![
-            ("Alice", 1),
-            ("Bob", 2),
-            ("Charlie", 3),
-            ("David", 4),
-            ("Eve", 5),
-        ];
+    // Authorization header value for acting as the given user.
+    fn bearer(user_id: i32) -> String {
+        format!("Bearer {}", test_keys().issue(user_id).unwrap())
+    }
 
-        // Create users sequentially
-        for (name, _) in users {
-            handle_user_action(db, UserAction::Create(name.to_string()))
-                .await
-                .unwrap();
+    async fn load_test_data(db: &DatabaseConnection) -> TestData {
+        let repos = Repositories::sea_orm(db.clone());
+        let mut users = Vec::new();
+        for name in ["Alice", "Bob", "Charlie", "David", "Eve"] {
+            users.push(repos.users.create(name).await.unwrap().id);
         }
+        let [alice, bob, charlie, david, eve] = users[..] else {
+            unreachable!()
+        };
 
         let messages = vec![
-            ("Hello, world!", 1),
-            ("I am Alice", 1),
-            ("Hi, there!", 2),
-            ("How are you?", 3),
-            ("I'm fine, thank you!", 4),
-            ("Goodbye!", 5),
+            ("Hello, world!", alice),
+            ("I am Alice", alice),
+            ("Hi, there!", bob),
+            ("How are you?", charlie),
+            ("I'm fine, thank you!", david),
+            ("Goodbye!", eve),
         ];
 
         // Create messages sequentially
+        let mut ids = Vec::new();
         for (content, user_id) in messages {
-            handle_message_action(
-                db,
-                MessageAction::Create(user_id, content.to_string(), None),
-            )
-            .await
-            .unwrap();
+            let message = repos
+                .messages
+                .create(user_id, content, None, None)
+                .await
+                .unwrap();
+            ids.push(message.id);
+        }
+
+        TestData {
+            alice,
+            bob,
+            charlie,
+            david,
+            eve,
+            messages: ids,
         }
     }
 
     #[tokio::test]
     async fn test_get_user_found() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!("{{ getUser(id: {}) {{ id name }} }}", data.bob)
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(r#"{"query":"{ getUser(id: 2) { id name } }"}"#))
+            .body(Body::from(body.to_string()))
             .unwrap();
 
         let response = app.oneshot(req).await.expect("Failed to execute request");
@@ -173,7 +324,7 @@ mod tests {
             json!({
                 "data": {
                     "getUser": {
-                        "id": "2",
+                        "id": data.bob.to_string(),
                         "name": "Bob"
                     }
                 }
@@ -183,7 +334,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_user_not_found() {
-        let app = setup_app().await;
+        let (app, _db, _) = setup_app().await;
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
@@ -209,14 +360,89 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let (app, _db, _) = setup_app().await;
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"query":"mutation { register(name: \"Frank\", password: \"hunter2hunter2\") { user { id name } errors { message } } }"}"#))
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"]["register"]["user"]["name"], "Frank");
+        assert_eq!(value["data"]["register"]["errors"], json!([]));
+        let frank = value["data"]["register"]["user"]["id"].clone();
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"query":"mutation { login(name: \"Frank\", password: \"hunter2hunter2\") { token user { id name } } }"}"#))
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["login"]["user"],
+            json!({
+                "id": frank,
+                "name": "Frank"
+            })
+        );
+        let token = value["data"]["login"]["token"].as_str().unwrap();
+        assert_eq!(
+            test_keys().verify(token).unwrap().sub.to_string(),
+            frank.as_str().unwrap()
+        );
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"query":"mutation { login(name: \"Frank\", password: \"wrong password\") { token errors { message } } }"}"#))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["login"],
+            json!({
+                "token": null,
+                "errors": [{ "message": "Invalid name or password" }]
+            })
+        );
+    }
+
     #[tokio::test]
     async fn test_update_user() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!(
+                "mutation {{ updateUser(id: {}, name: \"Bobby\") {{ user {{ id name }} errors {{ message }} }} }}",
+                data.bob
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(r#"{"query":"mutation { updateUser(id: 2, name: \"Bobby\") { success message } }"}"#))
+            .header(http::header::AUTHORIZATION, bearer(data.bob))
+            .body(Body::from(body.to_string()))
             .unwrap();
 
         let response = app.oneshot(req).await.expect("Failed to execute request");
@@ -228,10 +454,10 @@ mod tests {
             value,
             json!({
                 "data": {
-                      "updateUser": {
-                    "success": true,
-                    "message": "Action succeeded"
-                }
+                    "updateUser": {
+                        "user": { "id": data.bob.to_string(), "name": "Bobby" },
+                        "errors": []
+                    }
                 }
             })
         );
@@ -239,14 +465,19 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_user() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!(
+                "mutation {{ deleteUser(id: {}) {{ deletedUserId errors {{ message }} }} }}",
+                data.bob
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                r#"{"query":"mutation { deleteUser(id: 2) { success message } }"}"#,
-            ))
+            .header(http::header::AUTHORIZATION, bearer(data.bob))
+            .body(Body::from(body.to_string()))
             .unwrap();
 
         let response = app.oneshot(req).await.expect("Failed to execute request");
@@ -258,25 +489,75 @@ mod tests {
             value,
             json!({
                 "data": {
-                      "deleteUser": {
-                    "success": true,
-                    "message": "Action succeeded"
-                }
+                    "deleteUser": {
+                        "deletedUserId": data.bob.to_string(),
+                        "errors": []
+                    }
                 }
             })
         );
     }
 
+    #[tokio::test]
+    async fn test_session_cannot_modify_other_users() {
+        let (app, _db, data) = setup_app().await;
+        for mutation in [
+            format!(
+                "updateUser(id: {}, name: \"Mallory\") {{ user {{ id }} }}",
+                data.charlie
+            ),
+            format!("deleteUser(id: {}) {{ deletedUserId }}", data.charlie),
+        ] {
+            let body = json!({ "query": format!("mutation {{ {} }}", mutation) });
+            let req = Request::builder()
+                .uri("/graphql")
+                .method(http::Method::POST)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(http::header::AUTHORIZATION, bearer(data.bob))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+
+            let response = app
+                .clone()
+                .oneshot(req)
+                .await
+                .expect("Failed to execute request");
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let value: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(value["data"], Value::Null);
+            assert_eq!(value["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        }
+
+        let body = json!({
+            "query": format!("{{ getUser(id: {}) {{ name }} }}", data.charlie)
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"]["getUser"], json!({ "name": "Charlie" }));
+    }
+
     #[tokio::test]
     async fn test_get_all_message() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!(
+                "{{ getAllMessagesForUser (userId: {}) {{ nodes {{ userId content }} }} }}",
+                data.alice
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                r#"{"query":"{ getAllMessagesForUser (userId: 1) { userId content } }"}"#,
-            ))
+            .body(Body::from(body.to_string()))
             .unwrap();
         let response = app.oneshot(req).await.expect("Failed to execute request");
 
@@ -287,34 +568,248 @@ mod tests {
             value,
             json!({
                 "data": {
-                    "getAllMessagesForUser": [
-                        {
-                            "userId": "1",
-                            "content": "Hello, world!"
-                        },
-                        {
-                            "userId": "1",
-                            "content": "I am Alice"
-                        }
+                    "getAllMessagesForUser": {
+                        "nodes": [
+                            {
+                                "userId": data.alice.to_string(),
+                                "content": "Hello, world!"
+                            },
+                            {
+                                "userId": data.alice.to_string(),
+                                "content": "I am Alice"
+                            }
+                        ]
+                    }
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paginate_messages_for_user() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        // Alice's messages, oldest first
+        let mut expected: Vec<String> =
+            data.messages[..2].iter().map(|id| id.to_string()).collect();
+        for i in 0..5 {
+            let message = repos
+                .messages
+                .create(data.alice, &format!("More {}", i), None, None)
+                .await
+                .unwrap();
+            expected.push(message.id.to_string());
+        }
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let query = |args: &str| {
+            let body = json!({
+                "query": format!(
+                    "{{ getAllMessagesForUser(userId: {}, {}) {{ edges {{ cursor node {{ id }} }} pageInfo {{ hasPreviousPage hasNextPage startCursor endCursor }} }} }}",
+                    data.alice, args
+                )
+            });
+            Request::builder()
+                .uri("/graphql")
+                .method(http::Method::POST)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let ids = |value: &Value| -> Vec<String> {
+            value["data"]["getAllMessagesForUser"]["edges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|edge| edge["node"]["id"].as_str().unwrap().to_owned())
+                .collect()
+        };
+
+        // Alice's seven messages, read in pages of three
+        let response = app.clone().oneshot(query("first: 3")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ids(&value), expected[..3]);
+        let page_info = &value["data"]["getAllMessagesForUser"]["pageInfo"];
+        assert_eq!(page_info["hasPreviousPage"], false);
+        assert_eq!(page_info["hasNextPage"], true);
+
+        let after = page_info["endCursor"].as_str().unwrap().to_owned();
+        let response = app
+            .clone()
+            .oneshot(query(&format!("first: 3, after: \"{}\"", after)))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ids(&value), expected[3..6]);
+
+        let response = app.clone().oneshot(query("last: 2")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ids(&value), expected[5..]);
+        let page_info = &value["data"]["getAllMessagesForUser"]["pageInfo"];
+        assert_eq!(page_info["hasPreviousPage"], true);
+        assert_eq!(page_info["hasNextPage"], false);
+
+        let response = app.oneshot(query("after: \"not-a-cursor\"")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["errors"][0]["message"], "Invalid cursor");
+    }
+
+    #[tokio::test]
+    async fn test_message_user_is_the_author() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let bobs = data.messages[2];
+        let reply = repos
+            .messages
+            .create(data.david, "Reply", Some(bobs), None)
+            .await
+            .unwrap();
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": format!(
+                "{{ getMessage(id: {0}) {{ user {{ id name }} }} getMessageThread(messageId: {0}) {{ id user {{ name }} }} }}",
+                bobs
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "data": {
+                    "getMessage": { "user": { "id": data.bob.to_string(), "name": "Bob" } },
+                    "getMessageThread": [
+                        { "id": bobs.to_string(), "user": { "name": "Bob" } },
+                        { "id": reply.id.to_string(), "user": { "name": "David" } }
                     ]
                 }
             })
         );
     }
 
+    #[tokio::test]
+    async fn test_nested_thread_and_replies() {
+        let (db, data) = setup().await;
+        // root <- a <- b <- c, and root <- d
+        let repos = Repositories::sea_orm(db.clone());
+        let root = data.messages[0];
+        let mut parent = root;
+        let mut replies = Vec::new();
+        for user_id in [data.bob, data.charlie, data.david] {
+            parent = repos
+                .messages
+                .create(user_id, "Reply", Some(parent), None)
+                .await
+                .unwrap()
+                .id;
+            replies.push(parent);
+        }
+        let a = replies[0];
+        let d = repos
+            .messages
+            .create(data.eve, "Reply", Some(root), None)
+            .await
+            .unwrap()
+            .id;
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": format!(
+                "{{ thread(rootId: {0}, maxDepth: 1) {{ depth message {{ id }} hasMoreReplies replies {{ depth message {{ id }} hasMoreReplies replies {{ depth }} }} }} getMessage(id: {0}) {{ replies(first: 1, orderBy: NEWEST_FIRST) {{ nodes {{ id }} pageInfo {{ hasNextPage }} }} }} }}",
+                root
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "data": {
+                    "thread": {
+                        "depth": 0,
+                        "message": { "id": root.to_string() },
+                        "hasMoreReplies": false,
+                        "replies": [
+                            {
+                                "depth": 1,
+                                "message": { "id": a.to_string() },
+                                "hasMoreReplies": true,
+                                "replies": []
+                            },
+                            {
+                                "depth": 1,
+                                "message": { "id": d.to_string() },
+                                "hasMoreReplies": false,
+                                "replies": []
+                            }
+                        ]
+                    },
+                    "getMessage": {
+                        "replies": {
+                            "nodes": [{ "id": d.to_string() }],
+                            "pageInfo": { "hasNextPage": true }
+                        }
+                    }
+                }
+            })
+        );
+    }
+
     #[tokio::test]
     async fn test_delete_message() {
-        let app = setup_app().await;
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let root = data.messages[0];
+        let reply = repos
+            .messages
+            .create(data.bob, "Hi Alice", Some(root), None)
+            .await
+            .unwrap()
+            .id;
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": format!(
+                "mutation {{ deleteMessage(id: {}) {{ deletedMessage {{ id userId parentId }} errors {{ message }} }} }}",
+                root
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                r#"{"query":"mutation { deleteMessage(id: 1) { success message } }"}"#,
-            ))
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(body.to_string()))
             .unwrap();
 
-        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
 
         assert_eq!(response.status(), StatusCode::OK);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
@@ -324,9 +819,49 @@ mod tests {
             json!({
                 "data": {
                     "deleteMessage": {
-                    "success": true,
-                    "message": "Action succeeded"
+                        "deletedMessage": {
+                            "id": root.to_string(),
+                            "userId": data.alice.to_string(),
+                            "parentId": null
+                        },
+                        "errors": []
+                    }
                 }
+            })
+        );
+
+        // The thread keeps its shape around the tombstone, even for the author
+        let body = json!({
+            "query": format!(
+                "{{ thread(rootId: {}) {{ message {{ content deleted userId user {{ name }} }} replies {{ message {{ id content }} }} }} }}",
+                root
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "data": {
+                    "thread": {
+                        "message": {
+                            "content": "[deleted]",
+                            "deleted": true,
+                            "userId": null,
+                            "user": null
+                        },
+                        "replies": [
+                            { "message": { "id": reply.to_string(), "content": "Hi Alice" } }
+                        ]
+                    }
                 }
             })
         );
@@ -334,14 +869,20 @@ mod tests {
 
     #[tokio::test]
     async fn test_update_message() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
+        let id = data.messages[0];
+        let body = json!({
+            "query": format!(
+                "mutation {{ updateMessage(id: {}, content: \"THIS IS AN UPDATED MESSAGE\") {{ message {{ id content }} errors {{ message }} }} }}",
+                id
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                r#"{"query":"mutation { updateMessage(id: 1, content: \"THIS IS AN UPDATED MESSAGE\") { success message } }"}"#,
-            ))
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(body.to_string()))
             .unwrap();
 
         let response = app
@@ -358,21 +899,25 @@ mod tests {
             json!({
                 "data": {
                     "updateMessage": {
-                    "success": true,
-                    "message": "Action succeeded"
-                }
+                        "message": { "id": id.to_string(), "content": "THIS IS AN UPDATED MESSAGE" },
+                        "errors": []
+                    }
                 }
             })
         );
 
-        // Check if the message was updated
+        // Check if the message was updated, with the original kept as a revision
+        let body = json!({
+            "query": format!(
+                "{{ getMessage(id: {0}) {{ id content editCount revisions {{ revision content }} }} messageRevisionDiff(messageId: {0}, from: 0, to: 1) {{ operation text }} }}",
+                id
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(
-                r#"{"query":"{ getMessage(id: 1) { id content } }"}"#,
-            ))
+            .body(Body::from(body.to_string()))
             .unwrap();
         let response = app.oneshot(req).await.expect("Failed to execute request");
         assert_eq!(response.status(), StatusCode::OK);
@@ -383,9 +928,18 @@ mod tests {
             json!({
                 "data": {
                     "getMessage": {
-                        "id": "1",
-                        "content": "THIS IS AN UPDATED MESSAGE"
-                    }
+                        "id": id.to_string(),
+                        "content": "THIS IS AN UPDATED MESSAGE",
+                        "editCount": 1,
+                        "revisions": [
+                            { "revision": 0, "content": "Hello, world!" },
+                            { "revision": 1, "content": "THIS IS AN UPDATED MESSAGE" }
+                        ]
+                    },
+                    "messageRevisionDiff": [
+                        { "operation": "DELETE", "text": "Hello, world!" },
+                        { "operation": "INSERT", "text": "THIS IS AN UPDATED MESSAGE" }
+                    ]
                 }
             })
         );
@@ -393,13 +947,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_updated_non_existent_message() {
-        let app = setup_app().await;
+        let (app, _db, data) = setup_app().await;
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
             .body(Body::from(
-                r#"{"query":"mutation { updateMessage(id: 99999, content: \"THIS IS AN UPDATED MESSAGE\") { success message } }"}"#,
+                r#"{"query":"mutation { updateMessage(id: 99999, content: \"THIS IS AN UPDATED MESSAGE\") { message { id } errors { message code field } } }"}"#,
             ))
             .unwrap();
 
@@ -417,9 +972,13 @@ mod tests {
             json!({
                 "data": {
                     "updateMessage": {
-                    "success": true,
-                    "message": "Action succeeded"
-                }
+                        "message": null,
+                        "errors": [{
+                            "message": "Message not found",
+                            "code": "NOT_FOUND",
+                            "field": null
+                        }]
+                    }
                 }
             })
         );
@@ -446,37 +1005,744 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn get_messages_in_time_range() {
-        let app = setup_app().await;
+    async fn test_update_message_by_non_author() {
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!(
+                "mutation {{ updateMessage(id: {}, content: \"Not mine\") {{ message {{ id }} errors {{ message }} }} }}",
+                data.messages[0]
+            )
+        });
         let req = Request::builder()
             .uri("/graphql")
             .method(http::Method::POST)
             .header(http::header::CONTENT_TYPE, "application/json")
-            .body(Body::from(r#"{"query":"{ getMessagesInTimeRangeForUser(userId: 1, start: \"2021-01-01T00:00:00Z\", end: \"2099-01-02T00:00:00Z\") { id userId content } }"}"#))
+            .header(http::header::AUTHORIZATION, bearer(data.bob))
+            .body(Body::from(body.to_string()))
             .unwrap();
+
         let response = app.oneshot(req).await.expect("Failed to execute request");
 
         assert_eq!(response.status(), StatusCode::OK);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
         let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"], Value::Null);
+        assert_eq!(
+            value["errors"][0]["message"],
+            "Only the author can modify this message"
+        );
+        assert_eq!(value["errors"][0]["extensions"]["code"], "FORBIDDEN");
+    }
+
+    #[tokio::test]
+    async fn test_moderator_can_hide_message() {
+        let (db, data) = setup().await;
+        let mut bob: user::ActiveModel = user::Entity::find_by_id(data.bob)
+            .one(&*db)
+            .await
+            .unwrap()
+            .unwrap()
+            .into();
+        bob.role = Set(Role::Moderator);
+        bob.update(&*db).await.unwrap();
+        let app = router(
+            Repositories::sea_orm(db.clone()),
+            test_keys(),
+            &ServerConfig::default(),
+        );
+
+        let hide = json!({
+            "query": format!(
+                "mutation {{ hideMessage(id: {}) {{ message {{ id hidden }} }} }}",
+                data.messages[0]
+            )
+        })
+        .to_string();
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.charlie))
+            .body(Body::from(hide.clone()))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["errors"][0]["message"], "Insufficient role");
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.bob))
+            .body(Body::from(hide))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["hideMessage"]["message"],
+            json!({ "id": data.messages[0].to_string(), "hidden": true })
+        );
+
+        // Anonymous readers see a placeholder, the author still sees the original
+        let query = json!({
+            "query": format!("{{ getMessage(id: {}) {{ content hidden }} }}", data.messages[0])
+        })
+        .to_string();
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(query.clone()))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({ "data": { "getMessage": { "content": "[hidden]", "hidden": true } } })
+        );
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(query))
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"]["getMessage"]["content"], "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn test_message_reactions() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let id = data.messages[0];
+        repos.reactions.add(data.alice, id, "👍").await.unwrap();
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": format!(
+                "mutation {{ addReaction(messageId: {}, emoji: \"👍\") {{ message {{ reactions {{ emoji count reacted }} }} errors {{ message }} }} }}",
+                id
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.bob))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
         assert_eq!(
             value,
             json!({
                 "data": {
-                    "getMessagesInTimeRangeForUser": [
-                        {
-                            "id": "1",
-                            "userId": "1",
-                            "content": "Hello, world!"
+                    "addReaction": {
+                        "message": {
+                            "reactions": [{ "emoji": "👍", "count": 2, "reacted": true }]
                         },
-                        {
-                            "id": "2",
-                            "userId": "1",
-                            "content": "I am Alice"
-                        }
-                    ]
+                        "errors": []
+                    }
+                }
+            })
+        );
+
+        // Anonymous viewers see the counts only
+        let body = json!({
+            "query": format!("{{ getMessage(id: {}) {{ reactions {{ emoji count reacted }} }} }}", id)
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["getMessage"]["reactions"],
+            json!([{ "emoji": "👍", "count": 2, "reacted": false }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_mentions() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": "mutation { createMessage(content: \"Lunch, @Bob and @Charlie?\") { message { id mentions { name } } errors { message } } }"
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        let message = &value["data"]["createMessage"]["message"];
+        assert_eq!(
+            message["mentions"],
+            json!([{ "name": "Bob" }, { "name": "Charlie" }])
+        );
+
+        let body = json!({
+            "query": format!("{{ mentionsOf(userId: {}) {{ nodes {{ id content }} }} }}", data.bob)
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["mentionsOf"]["nodes"],
+            json!([{ "id": message["id"], "content": "Lunch, @Bob and @Charlie?" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_messages_in_channel() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let app = router(repos, test_keys(), &ServerConfig::default());
+        let post = |query: String, user_id: i32| {
+            let body = json!({ "query": query });
+            let req = Request::builder()
+                .uri("/graphql")
+                .method(http::Method::POST)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(http::header::AUTHORIZATION, bearer(user_id))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(req).await.unwrap();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        let value = post(
+            "mutation { createChannel(name: \"lunch\", topic: \"Where to eat\") { channel { id visibility createdBy { name } } errors { message } } }".to_string(),
+            data.alice,
+        )
+        .await;
+        let channel = &value["data"]["createChannel"]["channel"];
+        assert_eq!(channel["visibility"], "PUBLIC");
+        assert_eq!(channel["createdBy"]["name"], "Alice");
+        let channel_id = channel["id"].as_str().unwrap().to_owned();
+
+        let value = post(
+            format!(
+                "mutation {{ createMessage(content: \"Pizza?\", channelId: {}) {{ message {{ id }} }} }}",
+                channel_id
+            ),
+            data.alice,
+        )
+        .await;
+        let root = value["data"]["createMessage"]["message"]["id"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        post(
+            format!(
+                "mutation {{ createMessage(content: \"Sushi\", parentId: {}) {{ message {{ id }} }} }}",
+                root
+            ),
+            data.bob,
+        )
+        .await;
+
+        let value = post(
+            format!(
+                "{{ messagesInChannel(channelId: {}, within: \"1h\") {{ nodes {{ content channel {{ name }} }} }} }}",
+                channel_id
+            ),
+            data.bob,
+        )
+        .await;
+        assert_eq!(
+            value["data"]["messagesInChannel"]["nodes"],
+            json!([
+                { "content": "Pizza?", "channel": { "name": "lunch" } },
+                { "content": "Sushi", "channel": { "name": "lunch" } },
+            ])
+        );
+
+        // Only the creator and moderators manage the channel
+        let value = post(
+            format!(
+                "mutation {{ deleteChannel(id: {}) {{ deletedChannelId errors {{ code }} }} }}",
+                channel_id
+            ),
+            data.bob,
+        )
+        .await;
+        assert_eq!(value["errors"][0]["extensions"]["code"], "FORBIDDEN");
+    }
+
+    #[tokio::test]
+    async fn test_create_message_uses_token_author() {
+        let (db, data) = setup().await;
+        let app = router(
+            Repositories::sea_orm(db.clone()),
+            test_keys(),
+            &ServerConfig::default(),
+        );
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.charlie))
+            .body(Body::from(
+                r#"{"query":"mutation { createMessage(content: \"Posted by Charlie\") { message { id user { name } } } }"}"#,
+            ))
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let messages = message::Entity::find()
+            .filter(message::Column::Content.eq("Posted by Charlie"))
+            .all(&*db)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].user_id, Some(data.charlie));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["createMessage"]["message"],
+            json!({ "id": messages[0].id.to_string(), "user": { "name": "Charlie" } })
+        );
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"query":"mutation { createMessage(content: \"Anonymous\") { message { id } } }"}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["errors"][0]["message"], "Authentication required");
+        assert_eq!(value["errors"][0]["extensions"]["code"], "UNAUTHENTICATED");
+    }
+
+    #[tokio::test]
+    async fn test_personal_access_token_scopes() {
+        let (app, _db, data) = setup_app().await;
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(data.alice))
+            .body(Body::from(
+                r#"{"query":"mutation { createToken(name: \"reader\", scopes: [MESSAGES_READ]) { token personalAccessToken { name scopes } } }"}"#,
+            ))
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["createToken"]["personalAccessToken"],
+            json!({
+                "name": "reader",
+                "scopes": ["MESSAGES_READ"]
+            })
+        );
+        let token = value["data"]["createToken"]["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // Reading is within the token's scopes
+        let body = json!({
+            "query": format!("{{ getMessage(id: {}) {{ id content }} }}", data.messages[0])
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"]["getMessage"]["content"], "Hello, world!");
+
+        // Writing is not
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(
+                r#"{"query":"mutation { createMessage(content: \"From a bot\") { message { id } } }"}"#,
+            ))
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["errors"][0]["message"],
+            "Missing required scope: messages:write"
+        );
+
+        // Nor is minting more tokens
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(
+                r#"{"query":"{ listTokens { id name lastUsedAt } }"}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["errors"][0]["message"],
+            "Personal access tokens cannot manage tokens"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_created_subscription() {
+        let (db, data) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let schema = schema(repos.clone(), test_keys(), &ServerConfig::default());
+
+        let mut stream = schema.execute_stream(format!(
+            "subscription {{ messageCreated(userId: {}) {{ userId content parentId }} }}",
+            data.bob
+        ));
+        // The first poll runs the resolver, which subscribes to the event channel.
+        assert!(futures::poll!(stream.next()).is_pending());
+
+        let parent = data.messages[2];
+        for (user_id, content) in [(data.alice, "Not for Bob's feed"), (data.bob, "Bob again")] {
+            repos
+                .messages
+                .create(user_id, content, Some(parent), None)
+                .await
+                .unwrap();
+        }
+
+        let response = tokio::time::timeout(std::time::Duration::from_secs(5), stream.next())
+            .await
+            .expect("Timed out waiting for the subscription")
+            .expect("Subscription stream ended");
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "messageCreated": {
+                    "userId": data.bob.to_string(),
+                    "content": "Bob again",
+                    "parentId": parent
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscription_rejects_invalid_id() {
+        let (db, _) = setup().await;
+        let repos = Repositories::sea_orm(db.clone());
+        let schema = schema(repos, test_keys(), &ServerConfig::default());
+
+        let mut stream =
+            schema.execute_stream(r#"subscription { messageDeleted(userId: "abc") { id } }"#);
+        let response = stream.next().await.expect("Subscription stream ended");
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value["errors"][0]["message"], "Invalid ID: abc");
+        assert_eq!(value["errors"][0]["extensions"]["code"], "VALIDATION");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_is_rejected() {
+        let (app, _db, _) = setup_app().await;
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, "Bearer not-a-token")
+            .body(Body::from(r#"{"query":"{ getUser(id: 1) { id name } }"}"#))
+            .unwrap();
+
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_server_config_limits() {
+        let config = ServerConfig {
+            playground: false,
+            max_query_complexity: Some(2),
+            max_body_size: 128,
+            ..ServerConfig::default()
+        };
+        let app = router(Repositories::in_memory(), test_keys(), &config);
+
+        let req = Request::builder()
+            .uri("/graphiql")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"query":"{ getUser(id: 1) { id name } }"}"#))
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["errors"][0]["message"], "Query is too complex.");
+
+        let query =
+            json!({ "query": format!("{{ getUser(id: 1) {{ id }} }} # {}", "x".repeat(128)) });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(query.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn get_messages_in_time_range() {
+        let (app, _db, data) = setup_app().await;
+        let body = json!({
+            "query": format!(
+                "{{ getMessagesInTimeRangeForUser(userId: {}, start: \"2021-01-01T00:00:00Z\", end: \"2099-01-02T00:00:00Z\") {{ nodes {{ id userId content }} }} }}",
+                data.alice
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "data": {
+                    "getMessagesInTimeRangeForUser": {
+                        "nodes": [
+                            {
+                                "id": data.messages[0].to_string(),
+                                "userId": data.alice.to_string(),
+                                "content": "Hello, world!"
+                            },
+                            {
+                                "id": data.messages[1].to_string(),
+                                "userId": data.alice.to_string(),
+                                "content": "I am Alice"
+                            }
+                        ]
+                    }
                 }
             })
         );
     }
+
+    #[tokio::test]
+    async fn test_open_and_relative_time_ranges() {
+        let (app, _db, data) = setup_app().await;
+        let query = |args: &str| {
+            let body = json!({
+                "query": format!(
+                    "{{ getMessagesInTimeRangeForUser(userId: {}, {}) {{ nodes {{ id createdAt }} }} }}",
+                    data.alice, args
+                )
+            });
+            Request::builder()
+                .uri("/graphql")
+                .method(http::Method::POST)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let ids = |value: &Value| -> Vec<String> {
+            value["data"]["getMessagesInTimeRangeForUser"]["nodes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| node["id"].as_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let response = app.clone().oneshot(query("within: \"1h\"")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            ids(&value),
+            [data.messages[0].to_string(), data.messages[1].to_string()]
+        );
+        let created_at = value["data"]["getMessagesInTimeRangeForUser"]["nodes"][0]["createdAt"]
+            .as_str()
+            .unwrap();
+        assert!(DateTime::parse_from_rfc3339(created_at).is_ok());
+
+        let response = app
+            .clone()
+            .oneshot(query("start: \"2099-01-01T00:00:00+02:00\""))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert!(ids(&value).is_empty());
+
+        let response = app
+            .clone()
+            .oneshot(query("end: \"2021-01-01T00:00:00Z\", within: \"1w\""))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert!(ids(&value).is_empty());
+
+        // Malformed values are rejected before the resolver runs
+        for args in ["start: \"yesterday\"", "within: \"5x\"", "within: \"0h\""] {
+            let response = app.clone().oneshot(query(args)).await.unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let value: Value = serde_json::from_slice(&body).unwrap();
+            let message = value["errors"][0]["message"].as_str().unwrap();
+            assert!(message.starts_with("Failed to parse"), "{}", message);
+        }
+
+        let response = app
+            .oneshot(query("start: \"2021-01-01T00:00:00Z\", within: \"24h\""))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["errors"][0]["message"],
+            "Pass either start or within, not both"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migration_modes() {
+        let db = testing::connect("TEST_INTEGRATION_URL").await;
+        migrate(&db, MigrationMode::Verify).await.unwrap();
+
+        Migrator::down(&*db, Some(1)).await.unwrap();
+        let err = migrate(&db, MigrationMode::Verify).await.unwrap_err();
+        let last = Migrator::migrations().pop().unwrap();
+        assert_eq!(
+            err,
+            DbErr::Custom(format!("Pending migrations: {}", last.name()))
+        );
+
+        migrate(&db, MigrationMode::Auto).await.unwrap();
+        migrate(&db, MigrationMode::Verify).await.unwrap();
+    }
+
+    // The API runs the same on the in-memory repositories, without Postgres.
+    #[tokio::test]
+    async fn test_in_memory_repositories() {
+        let repos = Repositories::in_memory();
+        let author = repos.users.create("Memo").await.unwrap();
+        let root = repos
+            .messages
+            .create(author.id, "Root", None, None)
+            .await
+            .unwrap();
+        let app = router(repos, test_keys(), &ServerConfig::default());
+
+        let body = json!({
+            "query": format!(
+                "mutation {{ createMessage(content: \"Reply\", parentId: {}) {{ message {{ content user {{ name }} }} errors {{ code }} }} }}",
+                root.id
+            )
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::AUTHORIZATION, bearer(author.id))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(req)
+            .await
+            .expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["createMessage"],
+            json!({ "message": { "content": "Reply", "user": { "name": "Memo" } }, "errors": [] })
+        );
+
+        let body = json!({
+            "query": format!("{{ getMessageThread(messageId: {}) {{ content }} }}", root.id)
+        });
+        let req = Request::builder()
+            .uri("/graphql")
+            .method(http::Method::POST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(req).await.expect("Failed to execute request");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value["data"]["getMessageThread"],
+            json!([{ "content": "Root" }, { "content": "Reply" }])
+        );
+    }
 }